@@ -0,0 +1,621 @@
+use std::{collections::{HashMap, HashSet}, io::SeekFrom};
+
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::{Client, Response, StatusCode, Url, header::{CONTENT_LENGTH, CONTENT_TYPE}};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, RequestBuilder};
+use reqwest_tracing::TracingMiddleware;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::backend::remote::{ByteStream, PutBody, RemoteBackend, RemoteStat, SyncError, Synced};
+use crate::config::settings::{BasicAuth, Config, ServerConfig, UploadConfig};
+use crate::file_uploader::batch::SyncBatch;
+use crate::file_uploader::bodies::{Bodies, Body};
+use crate::file_uploader::compression::{Compressor, ZSTD};
+use crate::file_uploader::file_upload::{ChangeFeed, FileEntryDTO, Operations, RemoteConflict};
+use crate::file_uploader::handshake::{CLIENT_FEATURES, Feature, HandshakeError, Hello, LEGACY_FEATURES, Limits, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, Welcome};
+use crate::file_uploader::request::SyncRequest;
+use crate::file_uploader::response::{ItemResult, ItemStatus, SyncResponse};
+use crate::file_uploader::retry::{RetryError, RetryPolicy};
+use crate::file_uploader::sessions::{SessionStore, UploadSession};
+use crate::file_uploader::{proxy::ProxyRoute, tls};
+use crate::progress::events::{Progress, ProgressEvent};
+use crate::throttle::bandwidth::{Bandwidth, Direction};
+
+//Commands wait while a probe runs, so it can't hang on a silent network
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+//A pocket-drive server. Batches go through POST /sync (see request.rs and
+//response.rs), the rest of the file operations are:
+//  GET    /files?path=...   raw content, 404 when there is no such file
+//  HEAD   /files?path=...   X-File-Hash, X-Modified-Time, X-Version-Id, Content-Length
+//  PUT    /files?path=...   raw content with X-File-Hash and X-Modified-Time -> RemoteStat
+//  DELETE /files?path=...
+//  GET    /list?prefix=...  -> [RemoteStat]
+//  POST   /move             {"from", "to"}
+//  GET    /changes?cursor=... -> {cursor, changes: [RemoteChange]}
+//What else the server can do is settled by the handshake, see handshake.rs.
+pub struct HttpBackend {
+    server: ServerConfig,
+    client: ClientWithMiddleware,
+    retry: RetryPolicy,
+    upload: UploadConfig,
+    sessions: SessionStore,
+    progress: Progress,
+    bandwidth: Bandwidth,
+    compressor: Compressor,
+    //Features both sides listed in the handshake
+    features: HashSet<Feature>,
+    //The server's largest request body, from the handshake
+    max_body: Option<u64>
+}
+
+//One entry of the 409 body: {"conflicts": [RemoteConflict]}
+#[derive(Deserialize)]
+struct ConflictBody {
+    conflicts: Vec<RemoteConflict>
+}
+
+#[derive(Serialize)]
+struct CreateUpload<'a> {
+    file_path: &'a str,
+    file_hash: Option<&'a str>,
+    file_size: u64
+}
+
+#[derive(Deserialize)]
+struct UploadOffset {
+    upload_id: String,
+    offset: u64
+}
+
+#[derive(Serialize)]
+struct Move<'a> {
+    from: &'a str,
+    to: &'a str
+}
+
+//TLS and proxy settings apply to every backend that talks HTTP. The second
+//value is the proxy base_url is reached through, for error reports.
+pub fn client(config: &Config, base_url: &str) -> (ClientWithMiddleware, Option<String>) {
+    //One pool for every worker, connections are kept for reuse
+    let client = Client::builder()
+        .pool_max_idle_per_host(config.upload.workers.max(1))
+        .tls_backend_preconfigured(tls::client_config(&config.tls));
+    let proxy = ProxyRoute::resolve(&config.proxy, base_url);
+    let client = ProxyRoute::apply(proxy.as_ref(), client).build().unwrap();
+    let proxy = proxy.filter(|p| p.covers(base_url)).map(|p| p.display());
+    if let Some(proxy) = &proxy {
+        tracing::debug!("reaching {} through {}", base_url, proxy);
+    }
+    let client = ClientBuilder::new(client)
+        .with(TracingMiddleware::default())
+        .build();
+    (client, proxy)
+}
+
+//Any answer, even an error status, means the server is back. A 407 is
+//the proxy answering, not the server.
+pub async fn probe_url(client: &ClientWithMiddleware, url: &str) -> bool {
+    client.get(url)
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await
+        .is_ok_and(|r| r.status() != StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+}
+
+impl HttpBackend {
+    pub fn new(config: &Config, progress: Progress, bandwidth: Bandwidth) -> Self {
+        let (client, proxy) = client(config, &config.server.base_url);
+        Self {
+            server: config.server.clone(),
+            client,
+            retry: RetryPolicy::new(&config.retry, proxy),
+            upload: config.upload.clone(),
+            sessions: SessionStore::open(),
+            progress,
+            bandwidth,
+            compressor: Compressor::new(&config.compression),
+            features: LEGACY_FEATURES.iter().copied().collect(),
+            max_body: None
+        }
+    }
+
+    //Every request to the server goes through here
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header("X-Device-Id", &self.server.device_id);
+        if let Some(token) = &self.server.token {
+            request.bearer_auth(token)
+        } else if let Some(auth) = &self.server.basic_auth {
+            request.basic_auth(&auth.username, auth.password.as_ref())
+        } else {
+            request
+        }
+    }
+
+    async fn send<F, Fut>(&self, build: F) -> Result<Response, RetryError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = RequestBuilder>
+    {
+        self.retry.send(build).await
+    }
+
+    fn file_url(&self, path: &str) -> Result<Url, RetryError> {
+        Url::parse_with_params(&self.server.endpoint("files"), &[("path", path)])
+            .map_err(|e| RetryError::Exhausted(e.to_string()))
+    }
+
+    //Anything that might not fit a request body goes through a session, in
+    //chunks that do
+    fn resumable_threshold(&self) -> u64 {
+        self.max_body.map_or(self.upload.resumable_threshold, |max| self.upload.resumable_threshold.min(max / 2))
+    }
+
+    fn chunk_size(&self) -> u64 {
+        self.max_body.map_or(self.upload.chunk_size, |max| self.upload.chunk_size.min(max / 2)).max(1)
+    }
+
+    //Without upload sessions a body the server won't take can't be sent at
+    //all. Those entries are taken out of the request and rejected.
+    fn take_oversized(&self, operations: &mut SyncBatch) -> Vec<ItemResult> {
+        let Some(max) = self.max_body.filter(|_| !self.features.contains(&Feature::Resumable)) else {
+            return Vec::new();
+        };
+        let mut rejected = Vec::new();
+        for (op, entries) in operations.iter_mut() {
+            entries.retain(|dto| {
+                let size = dto.compressed_size.unwrap_or(dto.file_size) as u64;
+                if *op == Operations::Delete || size <= max {
+                    return true;
+                }
+                rejected.push(ItemResult {
+                    file_path: dto.file_path.clone(),
+                    operation: *op,
+                    status: ItemStatus::Rejected,
+                    server_hash: None,
+                    server_size: None,
+                    version_id: None,
+                    content_hash: None,
+                    modified_time: Some(dto.modified_time),
+                    device_id: None,
+                    message: Some(format!("{} bytes is more than the server accepts ({}) and it takes no resumable uploads", size, max))
+                });
+                false
+            });
+        }
+        rejected
+    }
+
+    //Compresses what is worth it and marks those entries. Marks and temp
+    //files from an earlier pass are undone first, so after compression was
+    //turned off this puts every body back to the raw file.
+    async fn compress_bodies(&self, operations: &mut SyncBatch, bodies: &mut Bodies) {
+        for (op, entries) in operations.iter_mut() {
+            for dto in entries.iter_mut() {
+                dto.compressed_size = None;
+                if dto.content_encoding.take().is_some() {
+                    bodies.restore(&dto.file_path);
+                }
+                //Encrypted bodies don't compress and the server couldn't decode them
+                if *op == Operations::Delete || dto.upload_id.is_some() || dto.encryption.is_some() {
+                    continue;
+                }
+                let Some(local) = bodies.get(&dto.file_path).map(|b| b.local.clone()) else {
+                    continue;
+                };
+
+                match self.compressor.compress(&local, dto.file_size as u64).await {
+                    Ok(Some(body)) => {
+                        let size = tokio::fs::metadata(&body).await.map(|m| m.len() as i64).ok();
+                        dto.content_encoding = Some(ZSTD.to_string());
+                        dto.compressed_size = size;
+                        bodies.temp(dto.file_path.clone(), local, body, None);
+                    }
+                    Ok(None) => {}
+                    //Sent raw, opening it again will report a real problem
+                    Err(e) => tracing::warn!("could not compress {}: {}", dto.file_path, e)
+                }
+            }
+        }
+    }
+
+    //Sends every file over the resumable threshold through its own upload
+    //session and records the session id in its payload entry
+    async fn upload_large_files(&self, operations: &mut SyncBatch, bodies: &Bodies) -> Result<(), RetryError> {
+        if !self.features.contains(&Feature::Resumable) {
+            return Ok(());
+        }
+        for (op, entries) in operations.iter_mut() {
+            if *op == Operations::Delete {
+                continue;
+            }
+            for dto in entries.iter_mut() {
+                if dto.file_size as u64 >= self.resumable_threshold()
+                    && let Some(body) = bodies.get(&dto.file_path)
+                {
+                    dto.upload_id = Some(self.upload_resumable(dto, body).await?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Resumable protocol:
+    //  POST  /uploads       {file_path, file_hash, file_size} -> {upload_id, offset}
+    //  GET   /uploads/{id}  -> {upload_id, offset}, 404 once the server dropped it
+    //  PATCH /uploads/{id}  Upload-Offset header plus the bytes from there -> {upload_id, offset}
+    async fn upload_resumable(&self, dto: &FileEntryDTO, body: &Body) -> Result<String, RetryError> {
+        let size = dto.file_size as u64;
+        //An encrypted copy is made with a new key every time, only a session
+        //for these exact bytes can be carried on
+        let hash = body.hash.clone().or_else(|| dto.file_hash.clone()).unwrap_or_default();
+
+        let known = self.sessions.get(&dto.file_path, &hash, size).unwrap_or_else(|e| {
+            tracing::warn!("Could not read the upload session of {}, starting over: {}", dto.file_path, e);
+            None
+        });
+        let mut session = match known {
+            Some(session) => match self.committed_offset(&session.upload_id).await? {
+                Some(offset) => UploadSession { offset, ..session },
+                None => self.create_session(dto, &hash).await?
+            },
+            None => self.create_session(dto, &hash).await?
+        };
+
+        let mut file = File::open(&body.file).await
+            .map_err(|e| RetryError::Exhausted(e.to_string()))?;
+        let mut failures = 0;
+
+        while session.offset < size {
+            let len = (size - session.offset).min(self.chunk_size()) as usize;
+            let mut buf = vec![0u8; len];
+            let read = async {
+                file.seek(SeekFrom::Start(session.offset)).await?;
+                file.read_exact(&mut buf).await
+            };
+            read.await.map_err(|e| RetryError::Exhausted(format!("{} changed while uploading: {}", dto.file_path, e)))?;
+            let chunk = Bytes::from(buf);
+
+            let url = self.server.endpoint(&format!("uploads/{}", session.upload_id));
+            let offset = session.offset;
+            let result = self.send(|| {
+                let request = self.authorize(self.client.patch(url.clone()))
+                    .header("Upload-Offset", offset)
+                    .header(CONTENT_TYPE, "application/offset+octet-stream")
+                    .body(reqwest::Body::wrap_stream(self.bandwidth.throttle_bytes(Direction::Upload, chunk.clone())));
+                async move { request }
+            }).await;
+
+            match result {
+                Ok(response) => {
+                    let committed: UploadOffset = response.json().await
+                        .map_err(|e| RetryError::Exhausted(e.to_string()))?;
+                    self.progress.emit(ProgressEvent::BytesUploaded {
+                        path: body.local.clone(),
+                        bytes: committed.offset.saturating_sub(session.offset)
+                    });
+                    session.offset = committed.offset;
+                    failures = 0;
+                }
+                Err(e) => {
+                    //Part of the chunk may have landed, ask where to carry on from
+                    failures += 1;
+                    if failures >= 3 {
+                        return Err(e);
+                    }
+                    match self.committed_offset(&session.upload_id).await? {
+                        Some(offset) => session.offset = offset,
+                        None => session = self.create_session(dto, &hash).await?
+                    }
+                }
+            }
+            self.save_session(&session);
+        }
+
+        Ok(session.upload_id)
+    }
+
+    async fn create_session(&self, dto: &FileEntryDTO, body_hash: &str) -> Result<UploadSession, RetryError> {
+        let body = CreateUpload {
+            file_path: &dto.file_path,
+            file_hash: dto.file_hash.as_deref(),
+            file_size: dto.file_size as u64
+        };
+        let response = self.send(|| {
+            let request = self.authorize(self.client.post(self.server.endpoint("uploads"))).json(&body);
+            async move { request }
+        }).await?;
+        let created: UploadOffset = response.json().await
+            .map_err(|e| RetryError::Exhausted(e.to_string()))?;
+
+        let session = UploadSession {
+            file_path: dto.file_path.clone(),
+            file_hash: body_hash.to_string(),
+            file_size: dto.file_size as u64,
+            upload_id: created.upload_id,
+            offset: created.offset
+        };
+        self.save_session(&session);
+        Ok(session)
+    }
+
+    //Losing the saved offset only costs a restart from the server's offset,
+    //not worth failing the upload over
+    fn save_session(&self, session: &UploadSession) {
+        if let Err(e) = self.sessions.save(session) {
+            tracing::warn!("Could not save the upload session of {}: {}", session.file_path, e);
+        }
+    }
+
+    //For servers without POST /move: a copy under the new name, then the
+    //old one goes
+    async fn copy_rename(&self, from: &str, to: &str) -> Result<(), RetryError> {
+        let missing = || RetryError::Status(StatusCode::NOT_FOUND, format!("{} is not there", from));
+        let stat = self.stat(from).await?.ok_or_else(missing)?;
+        let mut stream = self.get(from).await?.ok_or_else(missing)?;
+        let mut content = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+
+        let dto = FileEntryDTO {
+            file_name: to.rsplit('/').next().unwrap_or_default().to_string(),
+            file_path: to.to_string(),
+            file_hash: stat.hash,
+            file_size: content.len() as i64,
+            modified_time: stat.modified_time,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        };
+        self.put(&dto, PutBody::Bytes(Bytes::from(content))).await?;
+        self.delete(from).await
+    }
+
+    async fn committed_offset(&self, upload_id: &str) -> Result<Option<u64>, RetryError> {
+        let url = self.server.endpoint(&format!("uploads/{}", upload_id));
+        let result = self.send(|| {
+            let request = self.authorize(self.client.get(url.clone()));
+            async move { request }
+        }).await;
+
+        match result {
+            Ok(response) => {
+                let committed: UploadOffset = response.json().await
+                    .map_err(|e| RetryError::Exhausted(e.to_string()))?;
+                Ok(Some(committed.offset))
+            }
+            Err(RetryError::Status(StatusCode::NOT_FOUND, _)) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl RemoteBackend for HttpBackend {
+    fn describe(&self) -> String {
+        self.server.base_url.clone()
+    }
+
+    //Held in memory so every attempt can resend it. The pipeline's files go
+    //through sync, which streams them and resumes large ones.
+    async fn put(&self, dto: &FileEntryDTO, body: PutBody<'_>) -> Result<RemoteStat, RetryError> {
+        let url = self.file_url(&dto.file_path)?;
+        let content = match body {
+            PutBody::File { file, .. } => tokio::fs::read(file).await.map(Bytes::from)?,
+            PutBody::Bytes(bytes) => bytes
+        };
+        let response = self.send(|| {
+            let mut request = self.authorize(self.client.put(url.clone()))
+                .header("X-Modified-Time", dto.modified_time)
+                .header(CONTENT_LENGTH, content.len())
+                .body(reqwest::Body::wrap_stream(self.bandwidth.throttle_bytes(Direction::Upload, content.clone())));
+            if let Some(hash) = &dto.file_hash {
+                request = request.header("X-File-Hash", hash);
+            }
+            async move { request }
+        }).await?;
+        response.json().await.map_err(|e| RetryError::Exhausted(e.to_string()))
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<ByteStream>, RetryError> {
+        let url = self.file_url(path)?;
+        match self.send(|| {
+            let request = self.authorize(self.client.get(url.clone()));
+            async move { request }
+        }).await {
+            Ok(response) => Ok(Some(Box::pin(response.bytes_stream().map_err(std::io::Error::other)))),
+            Err(RetryError::Status(StatusCode::NOT_FOUND, _)) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), RetryError> {
+        let url = self.file_url(path)?;
+        match self.send(|| {
+            let request = self.authorize(self.client.delete(url.clone()));
+            async move { request }
+        }).await {
+            Ok(_) | Err(RetryError::Status(StatusCode::NOT_FOUND, _)) => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), RetryError> {
+        if !self.features.contains(&Feature::Rename) {
+            return self.copy_rename(from, to).await;
+        }
+        let body = Move { from, to };
+        self.send(|| {
+            let request = self.authorize(self.client.post(self.server.endpoint("move"))).json(&body);
+            async move { request }
+        }).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteStat>, RetryError> {
+        let url = Url::parse_with_params(&self.server.endpoint("list"), &[("prefix", prefix)])
+            .map_err(|e| RetryError::Exhausted(e.to_string()))?;
+        let response = self.send(|| {
+            let request = self.authorize(self.client.get(url.clone()));
+            async move { request }
+        }).await?;
+        response.json().await.map_err(|e| RetryError::Exhausted(e.to_string()))
+    }
+
+    async fn stat(&self, path: &str) -> Result<Option<RemoteStat>, RetryError> {
+        let url = self.file_url(path)?;
+        let response = match self.send(|| {
+            let request = self.authorize(self.client.head(url.clone()));
+            async move { request }
+        }).await {
+            Ok(response) => response,
+            Err(RetryError::Status(StatusCode::NOT_FOUND, _)) => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        let header = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        Ok(Some(RemoteStat {
+            path: path.to_string(),
+            hash: header("X-File-Hash"),
+            size: header(CONTENT_LENGTH.as_str()).and_then(|v| v.parse().ok()).unwrap_or(0),
+            modified_time: header("X-Modified-Time").and_then(|v| v.parse().ok()).unwrap_or(0),
+            version_id: header("X-Version-Id"),
+            content_hash: None
+        }))
+    }
+
+    async fn probe(&self) -> bool {
+        probe_url(&self.client, &self.server.base_url).await
+    }
+
+    fn authenticate(&mut self, token: Option<String>, basic_auth: Option<BasicAuth>) {
+        self.server.token = token;
+        self.server.basic_auth = basic_auth;
+    }
+
+    async fn handshake(&mut self, device_id: &str) -> Result<Limits, HandshakeError> {
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features: CLIENT_FEATURES.to_vec(),
+            device_id: device_id.to_string()
+        };
+        let result = self.send(|| {
+            let request = self.authorize(self.client.post(self.server.endpoint("handshake"))).json(&hello);
+            async move { request }
+        }).await;
+
+        let welcome: Welcome = match result {
+            Ok(response) => response.json().await
+                .map_err(|e| RetryError::Exhausted(format!("unreadable handshake answer: {}", e)))?,
+            Err(RetryError::Status(StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED, _)) => {
+                tracing::info!("{} predates the handshake, assuming protocol {}", self.server.base_url, PROTOCOL_VERSION);
+                Welcome {
+                    protocol_version: PROTOCOL_VERSION,
+                    min_protocol_version: PROTOCOL_VERSION,
+                    features: LEGACY_FEATURES.to_vec(),
+                    limits: Limits::default()
+                }
+            }
+            Err(RetryError::Status(StatusCode::UPGRADE_REQUIRED, reason)) => {
+                return Err(HandshakeError::Incompatible(format!("it refuses protocol {}: {}", PROTOCOL_VERSION, reason)));
+            }
+            Err(e) => return Err(e.into())
+        };
+
+        if welcome.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::Incompatible(format!(
+                "it speaks protocol {} and this client needs at least {}, upgrade the server", welcome.protocol_version, MIN_PROTOCOL_VERSION
+            )));
+        }
+        if welcome.min_protocol_version > PROTOCOL_VERSION {
+            return Err(HandshakeError::Incompatible(format!(
+                "it needs protocol {} and this client speaks {}, upgrade the client", welcome.min_protocol_version, PROTOCOL_VERSION
+            )));
+        }
+
+        self.features = welcome.features.into_iter().filter(|f| CLIENT_FEATURES.contains(f)).collect();
+        if !self.features.contains(&Feature::Compression) && self.compressor.enabled() {
+            tracing::warn!("{} takes no compressed bodies, sending them uncompressed", self.server.base_url);
+            self.compressor.disable();
+        }
+        self.max_body = welcome.limits.max_body_bytes;
+        Ok(welcome.limits)
+    }
+
+    async fn sync(&self, _device_id: &str, batch: &SyncBatch, bodies: &mut Bodies) -> Result<Synced, SyncError> {
+        let mut wire = batch.clone();
+        self.upload_large_files(&mut wire, bodies).await?;
+
+        self.compress_bodies(&mut wire, bodies).await;
+        let oversized = self.take_oversized(&mut wire);
+        let (result, skipped) = loop {
+            let (request, skipped) = SyncRequest::build(&wire, bodies);
+            if request.is_empty() {
+                let mut response = SyncResponse::all_ok(&HashMap::new());
+                response.results.extend(oversized);
+                return Ok(Synced { response, skipped });
+            }
+            let request = &request;
+            let idempotency_key = request.idempotency_key();
+            let result = self.send(|| {
+                let form = request.form(&self.progress, &self.bandwidth);
+                let mut builder = self.authorize(self.client.post(self.server.endpoint("sync")))
+                    .multipart(form);
+                if let Some(key) = &idempotency_key {
+                    builder = builder.header("Idempotency-Key", key.as_str());
+                }
+                async move { builder }
+            }).await;
+
+            let compressed = wire.values().flatten().any(|dto| dto.content_encoding.is_some());
+            match result {
+                Err(RetryError::Status(StatusCode::UNSUPPORTED_MEDIA_TYPE, _)) if compressed => {
+                    tracing::warn!("{} doesn't accept zstd bodies, sending them uncompressed", self.server.base_url);
+                    self.compressor.disable();
+                    self.compress_bodies(&mut wire, bodies).await;
+                }
+                result => break (result, skipped)
+            }
+        };
+
+        match result {
+            Ok(response) => {
+                let body = response.text().await.unwrap_or_default();
+                for dto in wire.values().flatten().filter(|dto| dto.upload_id.is_some()) {
+                    if let Err(e) = self.sessions.remove(&dto.file_path) {
+                        tracing::warn!("Could not drop the upload session of {}: {}", dto.file_path, e);
+                    }
+                }
+                let mut response = SyncResponse::parse(&body, &wire)
+                    .map_err(|e| SyncError::Failed(RetryError::Exhausted(e)))?;
+                response.results.extend(oversized);
+                Ok(Synced { response, skipped })
+            }
+            Err(RetryError::Status(StatusCode::CONFLICT, body)) => match serde_json::from_str::<ConflictBody>(&body) {
+                Ok(conflict) => Err(SyncError::Conflict(conflict.conflicts)),
+                Err(_) => Err(SyncError::Failed(RetryError::Status(StatusCode::CONFLICT, body)))
+            },
+            Err(e) => Err(SyncError::Failed(e))
+        }
+    }
+
+    async fn changes(&self, cursor: Option<String>) -> Result<ChangeFeed, RetryError> {
+        let endpoint = self.server.endpoint("changes");
+        let url = match &cursor {
+            Some(cursor) => Url::parse_with_params(&endpoint, &[("cursor", cursor)]),
+            None => Url::parse(&endpoint)
+        }.map_err(|e| RetryError::Exhausted(e.to_string()))?;
+
+        let response = self.send(|| {
+            let request = self.authorize(self.client.get(url.clone()));
+            async move { request }
+        }).await?;
+        response.json().await.map_err(|e| RetryError::Exhausted(e.to_string()))
+    }
+}
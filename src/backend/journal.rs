@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::remote::{PutBody, RemoteBackend};
+use crate::file_uploader::file_upload::{ChangeFeed, FileEntryDTO, RemoteChange};
+use crate::file_uploader::retry::RetryError;
+
+//Stores without a change feed of their own get one from this journal: every
+//synced batch adds one record under .pocket-drive/journal/<device>/, numbered
+//by the device that wrote it. A device only numbers a record once its
+//previous one is stored, so a reader that has seen a number has seen every
+//number before it, whatever the clocks say. The cursor is the last number
+//read from each device, e.g. "laptop=12,phone=4".
+//
+//Every COMPACT_EVERY records a device folds all but its last KEEP_RECORDS
+//into base-<number>.json, the latest change of each path up to that number.
+//A reader whose cursor is older starts from the base, seeing some changes it
+//already had again.
+const JOURNAL_PREFIX: &str = ".pocket-drive/journal/";
+const BASE_PREFIX: &str = "base-";
+//Records read per pull, the rest comes with the next one
+const MAX_RECORDS: usize = 256;
+const KEEP_RECORDS: u64 = 256;
+const COMPACT_EVERY: u64 = 64;
+
+//The next number is the last listed one plus one, so two batches of the same
+//device appending at once would both take it. Appends to one store by one
+//device wait for each other, keyed by the store's describe().
+static APPENDING: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> = LazyLock::new(Mutex::default);
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    changes: Vec<RemoteChange>
+}
+
+//What one device has in the journal
+#[derive(Default)]
+struct DeviceLog {
+    records: BTreeMap<u64, String>,
+    //Number it covers and name of the newest base
+    base: Option<(u64, String)>
+}
+
+impl DeviceLog {
+    fn last(&self) -> u64 {
+        let last_record = self.records.keys().next_back().copied().unwrap_or(0);
+        last_record.max(self.base.as_ref().map_or(0, |(through, _)| *through))
+    }
+}
+
+pub async fn append<B: RemoteBackend + ?Sized>(backend: &B, device_id: &str, changes: Vec<RemoteChange>) -> Result<(), RetryError> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let changes = changes.into_iter()
+        .map(|change| RemoteChange {
+            entry: FileEntryDTO {
+                upload_id: None,
+                base_hash: None,
+                content_encoding: None,
+                compressed_size: None,
+                part: None,
+                idempotency_key: None,
+                ..change.entry
+            },
+            ..change
+        })
+        .collect();
+
+    let device = sanitize(device_id);
+    let lock = APPENDING.lock().unwrap()
+        .entry(format!("{} {}", backend.describe(), device))
+        .or_default()
+        .clone();
+    let _appending = lock.lock().await;
+    let log = logs(backend, &format!("{}{}/", JOURNAL_PREFIX, device)).await?.remove(&device).unwrap_or_default();
+    let number = log.last() + 1;
+    write(backend, &format!("{}{}/{:012}.json", JOURNAL_PREFIX, device, number), &Record { changes }).await?;
+
+    //The record is in, a failed compaction only leaves more to read
+    if number % COMPACT_EVERY == 0
+        && number > KEEP_RECORDS
+        && let Err(e) = compact(backend, &device, log, number - KEEP_RECORDS).await
+    {
+        tracing::warn!("couldn't compact the journal of {}: {}", device, e);
+    }
+    Ok(())
+}
+
+pub async fn read<B: RemoteBackend + ?Sized>(backend: &B, cursor: Option<String>) -> Result<ChangeFeed, RetryError> {
+    let mut seen = parse_cursor(cursor.as_deref().unwrap_or_default());
+    let mut budget = MAX_RECORDS;
+    let mut changes = Vec::new();
+
+    for (device, log) in logs(backend, JOURNAL_PREFIX).await? {
+        let mut at = seen.get(&device).copied().unwrap_or(0);
+        if let Some((through, name)) = &log.base
+            && at < *through
+        {
+            //Gone when it was just replaced, the next pull reads the new one
+            let Some(record) = load(backend, name).await? else {
+                continue;
+            };
+            changes.extend(record.changes);
+            at = *through;
+            budget = budget.saturating_sub(1);
+        }
+        for (number, name) in log.records.range(at + 1..) {
+            if budget == 0 {
+                break;
+            }
+            //Folded into a base meanwhile, picked up from there next time
+            let Some(record) = load(backend, name).await? else {
+                break;
+            };
+            changes.extend(record.changes);
+            at = *number;
+            budget -= 1;
+        }
+        if at > 0 {
+            seen.insert(device, at);
+        }
+    }
+
+    Ok(ChangeFeed { cursor: format_cursor(&seen), changes })
+}
+
+//Folds the records up to through into a new base and removes what it covers
+async fn compact<B: RemoteBackend + ?Sized>(backend: &B, device: &str, log: DeviceLog, through: u64) -> Result<(), RetryError> {
+    let mut latest: BTreeMap<String, RemoteChange> = BTreeMap::new();
+    if let Some((_, name)) = &log.base {
+        for change in load(backend, name).await?.map(|r| r.changes).unwrap_or_default() {
+            latest.insert(change.entry.file_path.clone(), change);
+        }
+    }
+    let folded: Vec<&String> = log.records.range(..=through).map(|(_, name)| name).collect();
+    for name in &folded {
+        for change in load(backend, name).await?.map(|r| r.changes).unwrap_or_default() {
+            latest.insert(change.entry.file_path.clone(), change);
+        }
+    }
+
+    let name = format!("{}{}/{}{:012}.json", JOURNAL_PREFIX, device, BASE_PREFIX, through);
+    write(backend, &name, &Record { changes: latest.into_values().collect() }).await?;
+    if let Some((_, old)) = &log.base {
+        backend.delete(old).await?;
+    }
+    for name in folded {
+        backend.delete(name).await?;
+    }
+    Ok(())
+}
+
+//Records and bases under the prefix, by device
+async fn logs<B: RemoteBackend + ?Sized>(backend: &B, prefix: &str) -> Result<BTreeMap<String, DeviceLog>, RetryError> {
+    let mut logs: BTreeMap<String, DeviceLog> = BTreeMap::new();
+    for stat in backend.list(prefix).await? {
+        let Some((device, file)) = stat.path.strip_prefix(JOURNAL_PREFIX).and_then(|rest| rest.split_once('/')) else {
+            continue;
+        };
+        let Some(stem) = file.strip_suffix(".json") else {
+            continue;
+        };
+        let log = logs.entry(device.to_string()).or_default();
+        match stem.strip_prefix(BASE_PREFIX).map(str::parse::<u64>) {
+            Some(Ok(through)) => {
+                if log.base.as_ref().is_none_or(|(newest, _)| through > *newest) {
+                    log.base = Some((through, stat.path));
+                }
+            }
+            Some(Err(_)) => {}
+            None => {
+                if let Ok(number) = stem.parse::<u64>() {
+                    log.records.insert(number, stat.path);
+                }
+            }
+        }
+    }
+    Ok(logs)
+}
+
+async fn write<B: RemoteBackend + ?Sized>(backend: &B, name: &str, record: &Record) -> Result<(), RetryError> {
+    let millis = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+    let record = serde_json::to_vec(record).unwrap();
+    let meta = FileEntryDTO {
+        file_name: name.rsplit('/').next().unwrap().to_string(),
+        file_path: name.to_string(),
+        file_hash: None,
+        file_size: record.len() as i64,
+        modified_time: millis as i64,
+        upload_id: None,
+        base_hash: None,
+        content_encoding: None,
+        compressed_size: None,
+        encryption: None,
+        part: None,
+        idempotency_key: None
+    };
+    backend.put(&meta, PutBody::Bytes(Bytes::from(record))).await?;
+    Ok(())
+}
+
+//None when the record is gone. One that can't be parsed reads as empty so
+//it doesn't hold the feed up.
+async fn load<B: RemoteBackend + ?Sized>(backend: &B, name: &str) -> Result<Option<Record>, RetryError> {
+    let Some(mut stream) = backend.get(name).await? else {
+        return Ok(None);
+    };
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk.map_err(|e| RetryError::Exhausted(e.to_string()))?);
+    }
+    match serde_json::from_slice::<Record>(&body) {
+        Ok(record) => Ok(Some(record)),
+        Err(e) => {
+            tracing::warn!("skipping unreadable journal record {}: {}", name, e);
+            Ok(Some(Record { changes: Vec::new() }))
+        }
+    }
+}
+
+//Anything that doesn't parse starts that device from the beginning
+fn parse_cursor(cursor: &str) -> HashMap<String, u64> {
+    cursor.split(',')
+        .filter_map(|part| part.split_once('='))
+        .filter_map(|(device, number)| Some((device.to_string(), number.parse().ok()?)))
+        .collect()
+}
+
+fn format_cursor(seen: &HashMap<String, u64>) -> String {
+    let mut parts: Vec<String> = seen.iter().map(|(device, number)| format!("{}={}", device, number)).collect();
+    parts.sort();
+    parts.join(",")
+}
+
+fn sanitize(device_id: &str) -> String {
+    device_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::local_dir::LocalDirBackend;
+    use crate::file_uploader::file_upload::Operations;
+    use crate::progress::events::Progress;
+
+    fn change(path: &str, hash: &str, device: &str) -> RemoteChange {
+        RemoteChange {
+            operation: Operations::Update,
+            entry: FileEntryDTO {
+                file_name: path.rsplit('/').next().unwrap().to_string(),
+                file_path: path.to_string(),
+                file_hash: Some(hash.to_string()),
+                file_size: 1,
+                modified_time: 1,
+                upload_id: None,
+                base_hash: Some("base".to_string()),
+                content_encoding: None,
+                compressed_size: None,
+                encryption: None,
+                part: None,
+                idempotency_key: Some("key".to_string())
+            },
+            device_id: Some(device.to_string())
+        }
+    }
+
+    fn hashes(feed: &ChangeFeed) -> Vec<&str> {
+        feed.changes.iter().map(|c| c.entry.file_hash.as_deref().unwrap()).collect()
+    }
+
+    fn store() -> (tempfile::TempDir, LocalDirBackend) {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = LocalDirBackend::new(dir.path().to_path_buf(), Progress::new());
+        (dir, backend)
+    }
+
+    #[tokio::test]
+    async fn reads_each_device_from_its_own_position() {
+        let (_dir, backend) = store();
+        append(&backend, "laptop", vec![change("/a", "1", "laptop")]).await.unwrap();
+        append(&backend, "phone", vec![change("/b", "2", "phone")]).await.unwrap();
+
+        let feed = read(&backend, None).await.unwrap();
+        assert_eq!(feed.cursor, "laptop=1,phone=1");
+        assert_eq!(hashes(&feed), ["1", "2"]);
+        assert_eq!(feed.changes[0].entry.base_hash, None);
+        assert_eq!(feed.changes[0].entry.idempotency_key, None);
+
+        append(&backend, "phone", vec![change("/b", "3", "phone")]).await.unwrap();
+        let feed = read(&backend, Some(feed.cursor)).await.unwrap();
+        assert_eq!((feed.cursor.as_str(), hashes(&feed)), ("laptop=1,phone=2", vec!["3"]));
+        let feed = read(&backend, Some(feed.cursor)).await.unwrap();
+        assert_eq!((feed.cursor.as_str(), feed.changes.len()), ("laptop=1,phone=2", 0));
+    }
+
+    //A device whose clock is far behind, or whose record took long to land,
+    //is still read after another device's newer record was
+    #[tokio::test]
+    async fn a_late_record_is_not_skipped() {
+        let (_dir, backend) = store();
+        append(&backend, "fast", vec![change("/a", "1", "fast")]).await.unwrap();
+        let feed = read(&backend, None).await.unwrap();
+
+        append(&backend, "slow", vec![change("/b", "2", "slow")]).await.unwrap();
+        let feed = read(&backend, Some(feed.cursor)).await.unwrap();
+        assert_eq!(hashes(&feed), ["2"]);
+    }
+
+    #[tokio::test]
+    async fn compacts_old_records_into_a_base() {
+        let (dir, backend) = store();
+        append(&backend, "laptop", vec![change("/early", "0", "laptop")]).await.unwrap();
+        let early = read(&backend, None).await.unwrap().cursor;
+        for i in 2..=KEEP_RECORDS + COMPACT_EVERY {
+            append(&backend, "laptop", vec![change(&format!("/{}", i % 3), &i.to_string(), "laptop")]).await.unwrap();
+        }
+
+        let journal = dir.path().join(JOURNAL_PREFIX).join("laptop");
+        let files = std::fs::read_dir(&journal).unwrap().count();
+        assert_eq!(files, KEEP_RECORDS as usize + 1);
+        assert!(journal.join(format!("{}{:012}.json", BASE_PREFIX, COMPACT_EVERY)).exists());
+
+        //Behind the base: the latest change of every path it covers, then the records
+        let feed = read(&backend, Some(early)).await.unwrap();
+        assert_eq!(feed.changes.len(), 4 + MAX_RECORDS - 1);
+        let base: Vec<_> = feed.changes[..4].iter().map(|c| (c.entry.file_path.as_str(), c.entry.file_hash.as_deref().unwrap())).collect();
+        assert_eq!(base, [("/0", "63"), ("/1", "64"), ("/2", "62"), ("/early", "0")]);
+        assert_eq!(feed.cursor, format!("laptop={}", COMPACT_EVERY + MAX_RECORDS as u64 - 1));
+
+        let feed = read(&backend, Some(feed.cursor)).await.unwrap();
+        assert_eq!(hashes(&feed), [(KEEP_RECORDS + COMPACT_EVERY).to_string()]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_appends_keep_every_record() {
+        let (_dir, backend) = store();
+        let appends = (0..16).map(|i| {
+            let backend = &backend;
+            async move { append(backend, "laptop", vec![change(&format!("/{}", i), &i.to_string(), "laptop")]).await.unwrap() }
+        });
+        futures_util::future::join_all(appends).await;
+
+        let feed = read(&backend, None).await.unwrap();
+        assert_eq!(feed.cursor, "laptop=16");
+        let mut hashes: Vec<u64> = hashes(&feed).iter().map(|h| h.parse().unwrap()).collect();
+        hashes.sort();
+        assert_eq!(hashes, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_cursor_from_elsewhere_starts_over() {
+        assert!(parse_cursor("000001700000000000-laptop-1234abcd.json").is_empty());
+        assert_eq!(parse_cursor("a=3,b=x"), HashMap::from([("a".to_string(), 3)]));
+    }
+}
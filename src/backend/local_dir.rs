@@ -0,0 +1,341 @@
+use std::{io, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use blake2::{Blake2s256, Digest};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use walkdir::WalkDir;
+
+use crate::backend::remote::{ByteStream, INTERNAL_PREFIX, PutBody, RemoteBackend, RemoteStat, is_internal, relative_key};
+use crate::file_hasher::hasher::to_hex;
+use crate::file_uploader::file_upload::FileEntryDTO;
+use crate::file_uploader::retry::RetryError;
+use crate::progress::events::{Progress, ProgressEvent};
+
+const META_DIR: &str = "meta";
+const PART_SUFFIX: &str = ".pocket-drive-part";
+
+//Mirrors the tree into a directory, usually a mounted drive or NAS share, so
+//the files stay browsable there. What a plain directory can't hold, the hash
+//each file was stored with, goes into .pocket-drive/meta/ next to them.
+pub struct LocalDirBackend {
+    root: PathBuf,
+    progress: Progress
+}
+
+impl LocalDirBackend {
+    pub fn new(root: PathBuf, progress: Progress) -> Self {
+        Self { root, progress }
+    }
+
+    //An unmounted share looks like a missing root, that's being offline
+    //rather than a reason to create it
+    fn target(&self, path: &str) -> Result<PathBuf, RetryError> {
+        if !self.root.is_dir() {
+            return Err(RetryError::Unreachable(format!("{} is not there, is it mounted?", self.root.display())));
+        }
+        Ok(self.root.join(relative_key(path)?))
+    }
+
+    fn meta_path(&self, path: &str) -> Result<PathBuf, RetryError> {
+        let key = relative_key(path)?;
+        Ok(self.root.join(INTERNAL_PREFIX).join(META_DIR).join(format!("{}.json", key)))
+    }
+
+    async fn read_meta(&self, path: &str) -> Option<RemoteStat> {
+        let meta = fs::read(self.meta_path(path).ok()?).await.ok()?;
+        serde_json::from_slice(&meta).ok()
+    }
+
+    //Written next to the target and renamed over it, so the mirror never
+    //holds a half written file
+    async fn write(&self, target: &Path, body: PutBody<'_>, modified: SystemTime) -> io::Result<(u64, String)> {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let part = target.with_file_name(format!(".{}{}", name, PART_SUFFIX));
+
+        let written = async {
+            let mut out = File::create(&part).await?;
+            let mut hash = Blake2s256::new();
+            let mut size = 0u64;
+            match body {
+                PutBody::Bytes(bytes) => {
+                    hash.update(&bytes);
+                    out.write_all(&bytes).await?;
+                    size = bytes.len() as u64;
+                }
+                PutBody::File { file, local } => {
+                    let mut input = File::open(file).await?;
+                    let mut buf = vec![0u8; 256 * 1024];
+                    loop {
+                        let n = input.read(&mut buf).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        hash.update(&buf[..n]);
+                        out.write_all(&buf[..n]).await?;
+                        size += n as u64;
+                        self.progress.emit(ProgressEvent::BytesUploaded { path: local.to_path_buf(), bytes: n as u64 });
+                    }
+                }
+            }
+            out.sync_all().await?;
+            out.into_std().await.set_modified(modified)?;
+            fs::rename(&part, target).await?;
+            Ok((size, to_hex(&hash.finalize())))
+        }.await;
+
+        if written.is_err() {
+            let _ = fs::remove_file(&part).await;
+        }
+        written
+    }
+}
+
+impl RemoteBackend for LocalDirBackend {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    async fn put(&self, dto: &FileEntryDTO, body: PutBody<'_>) -> Result<RemoteStat, RetryError> {
+        let target = self.target(&dto.file_path)?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(dto.modified_time.max(0) as u64);
+        let (size, content_hash) = self.write(&target, body, modified).await?;
+
+        let stat = RemoteStat {
+            path: dto.file_path.clone(),
+            hash: dto.file_hash.clone(),
+            size: size as i64,
+            modified_time: dto.modified_time,
+            version_id: None,
+            content_hash: Some(content_hash)
+        };
+        if !is_internal(&dto.file_path) {
+            let meta = self.meta_path(&dto.file_path)?;
+            fs::create_dir_all(meta.parent().unwrap()).await?;
+            fs::write(&meta, serde_json::to_vec(&stat).unwrap()).await?;
+        }
+        Ok(stat)
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<ByteStream>, RetryError> {
+        match File::open(self.target(path)?).await {
+            Ok(file) => Ok(Some(Box::pin(ReaderStream::new(file)))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), RetryError> {
+        for file in [self.target(path)?, self.meta_path(path)?] {
+            match fs::remove_file(&file).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into())
+            }
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), RetryError> {
+        let (source, target) = (self.target(from)?, self.target(to)?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&source, &target).await?;
+
+        let (source_meta, target_meta) = (self.meta_path(from)?, self.meta_path(to)?);
+        if let Some(mut stat) = self.read_meta(from).await {
+            stat.path = to.to_string();
+            fs::create_dir_all(target_meta.parent().unwrap()).await?;
+            fs::write(&target_meta, serde_json::to_vec(&stat).unwrap()).await?;
+            let _ = fs::remove_file(&source_meta).await;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteStat>, RetryError> {
+        if !self.root.is_dir() {
+            return Err(RetryError::Unreachable(format!("{} is not there, is it mounted?", self.root.display())));
+        }
+        let root = self.root.clone();
+        let prefix = prefix.trim_start_matches('/').to_string();
+        let internal = is_internal(&prefix);
+
+        //Only the directory the prefix names is walked, the journal is read
+        //on every pull and shouldn't cost a walk of the whole mirror
+        let start = root.join(&prefix[..prefix.rfind('/').map_or(0, |i| i + 1)]);
+        let found = tokio::task::spawn_blocking(move || {
+            WalkDir::new(&start)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_file())
+                .filter_map(|entry| {
+                    let key = entry.path().strip_prefix(&root).ok()?.to_string_lossy().replace('\\', "/");
+                    let wanted = key.starts_with(&prefix)
+                        && is_internal(&key) == internal
+                        && !key.ends_with(PART_SUFFIX);
+                    if !wanted {
+                        return None;
+                    }
+                    let metadata = entry.metadata().ok()?;
+                    Some((key, metadata.len(), modified_millis(metadata.modified().ok())))
+                })
+                .collect::<Vec<_>>()
+        }).await.unwrap();
+
+        let mut stats = Vec::with_capacity(found.len());
+        for (key, size, modified_time) in found {
+            let hash = self.read_meta(&key).await
+                .filter(|meta| meta.size == size as i64 && meta.modified_time == modified_time)
+                .and_then(|meta| meta.hash);
+            stats.push(RemoteStat { path: key, hash, size: size as i64, modified_time, version_id: None, content_hash: None });
+        }
+        Ok(stats)
+    }
+
+    //The stored hash only counts while the file still looks like it did when
+    //it was written, someone may have edited the mirror directly
+    async fn stat(&self, path: &str) -> Result<Option<RemoteStat>, RetryError> {
+        let metadata = match fs::metadata(self.target(path)?).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+        let size = metadata.len() as i64;
+        let modified_time = modified_millis(metadata.modified().ok());
+        let hash = self.read_meta(path).await
+            .filter(|meta| meta.size == size && meta.modified_time == modified_time)
+            .and_then(|meta| meta.hash);
+        Ok(Some(RemoteStat { path: path.to_string(), hash, size, modified_time, version_id: None, content_hash: None }))
+    }
+
+    async fn probe(&self) -> bool {
+        self.root.is_dir()
+    }
+}
+
+fn modified_millis(modified: Option<SystemTime>) -> i64 {
+    modified
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|m| m.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    fn dto(path: &str, hash: &str) -> FileEntryDTO {
+        FileEntryDTO {
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            file_path: path.to_string(),
+            file_hash: Some(hash.to_string()),
+            file_size: 0,
+            modified_time: 1700000000000,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        }
+    }
+
+    fn bytes(content: &'static [u8]) -> PutBody<'static> {
+        PutBody::Bytes(Bytes::from_static(content))
+    }
+
+    async fn read(backend: &LocalDirBackend, path: &str) -> Option<Vec<u8>> {
+        let stream = backend.get(path).await.unwrap()?;
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        Some(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn mirrors_files_with_their_hash_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = LocalDirBackend::new(dir.path().to_path_buf(), Progress::new());
+        let source = dir.path().join("source");
+        std::fs::write(&source, b"from a file").unwrap();
+
+        let stat = backend.put(&dto("/docs/a.txt", "h1"), PutBody::File { file: &source, local: &source }).await.unwrap();
+        assert_eq!(stat.size, 11);
+        assert_eq!(stat.content_hash.unwrap(), to_hex(&Blake2s256::digest(b"from a file")));
+        assert_eq!(std::fs::read(dir.path().join("docs/a.txt")).unwrap(), b"from a file");
+        assert_eq!(read(&backend, "docs/a.txt").await.unwrap(), b"from a file");
+
+        let stat = backend.stat("/docs/a.txt").await.unwrap().unwrap();
+        assert_eq!((stat.hash.as_deref(), stat.size, stat.modified_time), (Some("h1"), 11, 1700000000000));
+        assert!(backend.stat("/docs/missing").await.unwrap().is_none());
+        assert!(read(&backend, "/docs/missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn forgets_the_hash_of_a_file_edited_in_the_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = LocalDirBackend::new(dir.path().to_path_buf(), Progress::new());
+        backend.put(&dto("/a.txt", "h1"), bytes(b"stored")).await.unwrap();
+
+        std::fs::write(dir.path().join("a.txt"), b"edited by hand").unwrap();
+        let stat = backend.stat("/a.txt").await.unwrap().unwrap();
+        assert_eq!((stat.hash, stat.size), (None, 14));
+        assert_eq!(backend.list("").await.unwrap()[0].hash, None);
+    }
+
+    #[tokio::test]
+    async fn lists_under_the_prefix_without_bookkeeping_or_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = LocalDirBackend::new(dir.path().to_path_buf(), Progress::new());
+        for path in ["docs/1", "docs/sub/2", "other/3", ".pocket-drive/journal/4"] {
+            backend.put(&dto(path, "h"), bytes(b"x")).await.unwrap();
+        }
+        std::fs::write(dir.path().join("docs").join(format!(".5{}", PART_SUFFIX)), b"half").unwrap();
+
+        let paths = |stats: Vec<RemoteStat>| {
+            let mut paths: Vec<String> = stats.into_iter().map(|s| s.path).collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(paths(backend.list("/docs/").await.unwrap()), ["docs/1", "docs/sub/2"]);
+        assert_eq!(paths(backend.list("").await.unwrap()), ["docs/1", "docs/sub/2", "other/3"]);
+        assert_eq!(paths(backend.list(".pocket-drive/journal/").await.unwrap()), [".pocket-drive/journal/4"]);
+        assert_eq!(backend.list("docs/1").await.unwrap()[0].hash.as_deref(), Some("h"));
+    }
+
+    #[tokio::test]
+    async fn renames_and_deletes_with_the_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = LocalDirBackend::new(dir.path().to_path_buf(), Progress::new());
+        backend.put(&dto("/docs/old", "h1"), bytes(b"moved")).await.unwrap();
+
+        backend.rename("/docs/old", "/elsewhere/new").await.unwrap();
+        assert!(backend.stat("/docs/old").await.unwrap().is_none());
+        assert_eq!(backend.stat("/elsewhere/new").await.unwrap().unwrap().hash.as_deref(), Some("h1"));
+
+        backend.delete("/elsewhere/new").await.unwrap();
+        backend.delete("/elsewhere/new").await.unwrap();
+        assert!(backend.list("").await.unwrap().is_empty());
+        assert!(!backend.meta_path("/elsewhere/new").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn a_missing_root_is_offline_and_paths_stay_inside_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let unmounted = LocalDirBackend::new(dir.path().join("share"), Progress::new());
+        assert!(!unmounted.probe().await);
+        assert!(matches!(unmounted.put(&dto("/a", "h"), bytes(b"x")).await, Err(RetryError::Unreachable(_))));
+        assert!(matches!(unmounted.list("").await, Err(RetryError::Unreachable(_))));
+        assert!(!dir.path().join("share").exists());
+
+        let backend = LocalDirBackend::new(dir.path().to_path_buf(), Progress::new());
+        assert!(matches!(backend.put(&dto("/../escape", "h"), bytes(b"x")).await, Err(RetryError::Status(..))));
+        assert!(!dir.path().parent().unwrap().join("escape").exists());
+    }
+}
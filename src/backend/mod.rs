@@ -0,0 +1,5 @@
+pub mod remote;
+pub mod journal;
+pub mod http;
+pub mod local_dir;
+pub mod s3;
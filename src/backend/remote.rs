@@ -0,0 +1,363 @@
+use std::{io, path::{Component, Path}};
+
+use bytes::Bytes;
+use futures_util::stream::BoxStream;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::journal;
+use crate::config::settings::BasicAuth;
+use crate::file_uploader::batch::SyncBatch;
+use crate::file_uploader::bodies::Bodies;
+use crate::file_uploader::file_upload::{ChangeFeed, FileEntryDTO, Operations, RemoteChange, RemoteConflict};
+use crate::file_uploader::handshake::{HandshakeError, Limits};
+use crate::file_uploader::response::{ItemResult, ItemStatus, SYNC_RESPONSE_VERSION, SyncResponse};
+use crate::file_uploader::retry::RetryError;
+
+//Backends keep their own bookkeeping under this prefix, next to the files
+pub const INTERNAL_PREFIX: &str = ".pocket-drive/";
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+//What a backend knows about one stored file. Paths are the ones the server
+//sees, so sealed when encryption is on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteStat {
+    pub path: String,
+    //file_hash of the entry that stored it, None when the store can't tell
+    pub hash: Option<String>,
+    pub size: i64,
+    pub modified_time: i64,
+    #[serde(default)]
+    pub version_id: Option<String>,
+    //blake2s of the stored bytes, when the backend computed it while storing
+    #[serde(default, skip_serializing)]
+    pub content_hash: Option<String>
+}
+
+pub enum PutBody<'a> {
+    //The prepared body and the user's file it was made from
+    File { file: &'a Path, local: &'a Path },
+    Bytes(Bytes)
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    //Nothing in the batch was applied
+    Conflict(Vec<RemoteConflict>),
+    Failed(RetryError)
+}
+
+impl From<RetryError> for SyncError {
+    fn from(e: RetryError) -> Self {
+        SyncError::Failed(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct Synced {
+    pub response: SyncResponse,
+    //Entries whose body couldn't be read, they were not sent at all
+    pub skipped: Vec<(Operations, FileEntryDTO)>
+}
+
+//Everything the sync pipeline needs from the place files are kept. The file
+//operations are the building blocks, sync and changes have versions built
+//from them which a backend with a smarter protocol replaces.
+//
+//Errors use RetryError whatever the transport: Unreachable takes the pipeline
+//offline, Status(401 | 403) stops it until new credentials arrive.
+pub trait RemoteBackend: Send + Sync + 'static {
+    //For log lines, e.g. the server url or the directory
+    fn describe(&self) -> String;
+
+    fn put(&self, dto: &FileEntryDTO, body: PutBody<'_>) -> impl Future<Output = Result<RemoteStat, RetryError>> + Send;
+
+    //None when there is no such file
+    fn get(&self, path: &str) -> impl Future<Output = Result<Option<ByteStream>, RetryError>> + Send;
+
+    //Deleting what isn't there is not an error
+    fn delete(&self, path: &str) -> impl Future<Output = Result<(), RetryError>> + Send;
+
+    fn rename(&self, from: &str, to: &str) -> impl Future<Output = Result<(), RetryError>> + Send;
+
+    //Every file under the prefix, bookkeeping files only when the prefix asks for them
+    fn list(&self, prefix: &str) -> impl Future<Output = Result<Vec<RemoteStat>, RetryError>> + Send;
+
+    fn stat(&self, path: &str) -> impl Future<Output = Result<Option<RemoteStat>, RetryError>> + Send;
+
+    //Whether the store answers at all, used to leave the offline state
+    fn probe(&self) -> impl Future<Output = bool> + Send;
+
+    fn authenticate(&mut self, _token: Option<String>, _basic_auth: Option<BasicAuth>) {}
+
+    //Agrees on a protocol before anything is sent and returns the limits
+    //batches have to stay within. Plain stores have nothing to agree on.
+    fn handshake(&mut self, _device_id: &str) -> impl Future<Output = Result<Limits, HandshakeError>> + Send {
+        async { Ok(Limits::default()) }
+    }
+
+    //Applies a batch and reports every entry. A base_hash that doesn't match
+    //the stored file turns the whole batch into a conflict before anything
+    //is written, like the server does. A batch that failed half way is sent
+    //again as is, entries it already applied are no conflict then.
+    fn sync(&self, device_id: &str, batch: &SyncBatch, bodies: &mut Bodies) -> impl Future<Output = Result<Synced, SyncError>> + Send {
+        async move {
+            let conflicts = find_conflicts(self, batch).await?;
+            if !conflicts.is_empty() {
+                return Err(SyncError::Conflict(conflicts));
+            }
+
+            let mut results = Vec::new();
+            let mut skipped = Vec::new();
+            let mut applied = Vec::new();
+            for op in [Operations::Insert, Operations::Update, Operations::Delete] {
+                for dto in batch.get(&op).into_iter().flatten() {
+                    let stored = match op {
+                        Operations::Delete => self.delete(&dto.file_path).await.map(|_| None),
+                        _ => {
+                            let Some(body) = bodies.get(&dto.file_path) else {
+                                skipped.push((op, dto.clone()));
+                                continue;
+                            };
+                            match self.put(dto, PutBody::File { file: &body.file, local: &body.local }).await {
+                                //Gone between hashing and sending, the watcher reports it again
+                                Err(RetryError::Exhausted(e)) if !body.file.exists() => {
+                                    tracing::warn!("leaving {} out: {}", dto.file_path, e);
+                                    skipped.push((op, dto.clone()));
+                                    continue;
+                                }
+                                stored => stored.map(Some)
+                            }
+                        }
+                    };
+                    let (status, stat, message) = match stored {
+                        Ok(stat) => (ItemStatus::Ok, stat, None),
+                        Err(RetryError::Status(StatusCode::BAD_REQUEST, reason)) => (ItemStatus::Rejected, None, Some(reason)),
+                        //What was applied still goes into the journal. The
+                        //batch comes again and finds those entries done.
+                        Err(e) => {
+                            if let Err(journal) = journal::append(self, device_id, applied).await {
+                                tracing::warn!("couldn't journal a partly applied batch: {}", journal);
+                            }
+                            return Err(e.into());
+                        }
+                    };
+                    results.push(ItemResult {
+                        file_path: dto.file_path.clone(),
+                        operation: op,
+                        status,
+                        server_hash: stat.as_ref().and_then(|s| s.hash.clone()),
+                        server_size: stat.as_ref().map(|s| s.size),
+                        version_id: stat.as_ref().and_then(|s| s.version_id.clone()),
+                        content_hash: stat.and_then(|s| s.content_hash),
+                        modified_time: Some(dto.modified_time),
+                        device_id: Some(device_id.to_string()),
+                        message
+                    });
+                    if status == ItemStatus::Ok {
+                        applied.push(RemoteChange { operation: op, entry: dto.clone(), device_id: Some(device_id.to_string()) });
+                    }
+                }
+            }
+
+            journal::append(self, device_id, applied).await?;
+            Ok(Synced { response: SyncResponse { version: SYNC_RESPONSE_VERSION, results }, skipped })
+        }
+    }
+
+    //What other devices changed since the cursor, None for everything
+    fn changes(&self, cursor: Option<String>) -> impl Future<Output = Result<ChangeFeed, RetryError>> + Send {
+        journal::read(self, cursor)
+    }
+}
+
+async fn find_conflicts<B: RemoteBackend + ?Sized>(backend: &B, batch: &SyncBatch) -> Result<Vec<RemoteConflict>, RetryError> {
+    let mut conflicts = Vec::new();
+    let entries: Vec<(Operations, &FileEntryDTO)> = batch.iter()
+        .flat_map(|(op, dtos)| dtos.iter().map(|dto| (*op, dto)))
+        .collect();
+    for (op, dto) in entries {
+        let Some(base) = &dto.base_hash else {
+            continue;
+        };
+        let stat = match backend.stat(&dto.file_path).await {
+            Ok(stat) => stat,
+            //Rejected when it is applied
+            Err(RetryError::Status(StatusCode::BAD_REQUEST, _)) => continue,
+            Err(e) => return Err(e)
+        };
+        //A store that can't tell the hash has nothing to compare
+        let current = match &stat {
+            Some(stat) if stat.hash.is_none() => continue,
+            Some(stat) => stat.hash.as_deref(),
+            None => None
+        };
+        //Already applied by an earlier attempt at this batch
+        let applied = match op {
+            Operations::Delete => stat.is_none(),
+            _ => current.is_some() && current == dto.file_hash.as_deref()
+        };
+        if current != Some(base.as_str()) && !applied {
+            conflicts.push(RemoteConflict {
+                file_path: dto.file_path.clone(),
+                remote_hash: current.map(str::to_string),
+                remote_size: stat.as_ref().map(|s| s.size),
+                remote_modified_time: stat.as_ref().map(|s| s.modified_time),
+                device_id: None
+            });
+        }
+    }
+    Ok(conflicts)
+}
+
+pub fn is_internal(path: &str) -> bool {
+    path.trim_start_matches('/').starts_with(INTERNAL_PREFIX)
+}
+
+//Wire paths may be absolute, they are stored relative to the backend's root.
+//Anything that could climb out of it is refused.
+pub fn relative_key(path: &str) -> Result<String, RetryError> {
+    let trimmed = path.trim_start_matches('/');
+    let safe = !trimmed.is_empty() && Path::new(trimmed).components().all(|c| matches!(c, Component::Normal(_)));
+    if !safe {
+        return Err(RetryError::Status(StatusCode::BAD_REQUEST, format!("path {:?} can't be stored", path)));
+    }
+    Ok(trimmed.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::backend::local_dir::LocalDirBackend;
+    use crate::progress::events::Progress;
+
+    //A directory whose store goes away when a given path is written
+    struct Flaky {
+        inner: LocalDirBackend,
+        unreachable_at: Option<String>
+    }
+
+    impl RemoteBackend for Flaky {
+        fn describe(&self) -> String {
+            self.inner.describe()
+        }
+
+        async fn put(&self, dto: &FileEntryDTO, body: PutBody<'_>) -> Result<RemoteStat, RetryError> {
+            if self.unreachable_at.as_deref() == Some(dto.file_path.as_str()) {
+                return Err(RetryError::Unreachable("gone".to_string()));
+            }
+            self.inner.put(dto, body).await
+        }
+
+        async fn get(&self, path: &str) -> Result<Option<ByteStream>, RetryError> {
+            self.inner.get(path).await
+        }
+
+        async fn delete(&self, path: &str) -> Result<(), RetryError> {
+            self.inner.delete(path).await
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> Result<(), RetryError> {
+            self.inner.rename(from, to).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<RemoteStat>, RetryError> {
+            self.inner.list(prefix).await
+        }
+
+        async fn stat(&self, path: &str) -> Result<Option<RemoteStat>, RetryError> {
+            self.inner.stat(path).await
+        }
+
+        async fn probe(&self) -> bool {
+            true
+        }
+    }
+
+    fn dto(path: &str, hash: &str, base: Option<&str>) -> FileEntryDTO {
+        FileEntryDTO {
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            file_path: path.to_string(),
+            file_hash: Some(hash.to_string()),
+            file_size: 4,
+            modified_time: 1700000000000,
+            upload_id: None,
+            base_hash: base.map(str::to_string),
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        }
+    }
+
+    fn setup(unreachable_at: Option<&str>) -> (tempfile::TempDir, Flaky, Bodies) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("store")).unwrap();
+        let backend = Flaky {
+            inner: LocalDirBackend::new(dir.path().join("store"), Progress::new()),
+            unreachable_at: unreachable_at.map(str::to_string)
+        };
+        let mut bodies = Bodies::default();
+        for name in ["a", "b", "c"] {
+            let file = dir.path().join(name);
+            std::fs::write(&file, name.repeat(4)).unwrap();
+            bodies.local(format!("/{}", name), file);
+        }
+        (dir, backend, bodies)
+    }
+
+    async fn seed(backend: &Flaky, path: &str, hash: &str) {
+        backend.inner.put(&dto(path, hash, None), PutBody::Bytes(Bytes::from_static(b"seed"))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_batch_sent_again_is_no_conflict_with_itself() {
+        let (_dir, backend, mut bodies) = setup(None);
+        seed(&backend, "/a", "old-a").await;
+        seed(&backend, "/c", "old-c").await;
+        let batch: SyncBatch = HashMap::from([
+            (Operations::Update, vec![dto("/a", "new-a", Some("old-a"))]),
+            (Operations::Delete, vec![dto("/c", "old-c", Some("old-c"))])
+        ]);
+
+        backend.sync("laptop", &batch, &mut bodies).await.unwrap();
+        let synced = backend.sync("laptop", &batch, &mut bodies).await.unwrap();
+        assert!(synced.response.results.iter().all(|r| r.status == ItemStatus::Ok));
+        assert_eq!(backend.stat("/a").await.unwrap().unwrap().hash.as_deref(), Some("new-a"));
+    }
+
+    #[tokio::test]
+    async fn a_changed_remote_is_still_a_conflict() {
+        let (_dir, backend, mut bodies) = setup(None);
+        seed(&backend, "/a", "someone-else").await;
+        let batch: SyncBatch = HashMap::from([(Operations::Update, vec![dto("/a", "new-a", Some("old-a"))])]);
+
+        match backend.sync("laptop", &batch, &mut bodies).await {
+            Err(SyncError::Conflict(conflicts)) => assert_eq!(conflicts[0].remote_hash.as_deref(), Some("someone-else")),
+            other => panic!("expected a conflict, got {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn a_batch_that_failed_half_way_journals_what_it_applied() {
+        let (_dir, backend, mut bodies) = setup(Some("/b"));
+        seed(&backend, "/a", "old-a").await;
+        seed(&backend, "/b", "old-b").await;
+        let batch: SyncBatch = HashMap::from([
+            (Operations::Update, vec![dto("/a", "new-a", Some("old-a")), dto("/b", "new-b", Some("old-b"))])
+        ]);
+
+        assert!(matches!(backend.sync("laptop", &batch, &mut bodies).await, Err(SyncError::Failed(RetryError::Unreachable(_)))));
+        let feed = backend.changes(None).await.unwrap();
+        assert_eq!(feed.changes.iter().map(|c| c.entry.file_path.as_str()).collect::<Vec<_>>(), ["/a"]);
+
+        let backend = Flaky { unreachable_at: None, ..backend };
+        let synced = backend.sync("laptop", &batch, &mut bodies).await.unwrap();
+        assert_eq!(synced.response.results.len(), 2);
+        assert_eq!(backend.stat("/b").await.unwrap().unwrap().hash.as_deref(), Some("new-b"));
+    }
+}
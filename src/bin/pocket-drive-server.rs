@@ -0,0 +1,10 @@
+use pocket_drive::{config::server::ServerSettings, logging, server};
+
+#[tokio::main]
+async fn main() {
+    logging::init();
+    let settings = ServerSettings::load();
+    let listener = tokio::net::TcpListener::bind(&settings.listen).await
+        .unwrap_or_else(|e| panic!("server: can't listen on {}: {}", settings.listen, e));
+    server::serve(settings, listener).await.unwrap();
+}
@@ -0,0 +1,64 @@
+use std::{env, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::config::settings::BasicAuth;
+
+pub const SERVER_CONFIG_ENV: &str = "POCKET_DRIVE_SERVER_CONFIG";
+pub const DEFAULT_SERVER_CONFIG_PATH: &str = "pocket-drive-server.json";
+pub const SERVER_TOKEN_ENV: &str = "POCKET_DRIVE_SERVER_TOKEN";
+
+//Settings of pocket-drive-server, kept apart from the client's config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub listen: String,
+    //Holds the index database, the blobs and unfinished uploads
+    pub data_dir: PathBuf,
+    //Bearer token clients must send, POCKET_DRIVE_SERVER_TOKEN overrides it.
+    //With neither a token nor basic_auth the server is open
+    pub token: Option<String>,
+    pub basic_auth: Option<BasicAuth>,
+    //Bytes the current files may take up, versions don't count
+    pub quota_bytes: Option<u64>,
+    //Largest request body accepted, a sync request carries whole files
+    pub max_body_bytes: u64,
+    //Entries in one sync request
+    pub max_batch_files: usize,
+    //Changes returned per GET /changes, the client pulls the rest next time
+    pub max_changes: usize
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8000".to_string(),
+            data_dir: PathBuf::from("pocket-drive-data"),
+            token: None,
+            basic_auth: None,
+            quota_bytes: None,
+            max_body_bytes: 1024 * 1024 * 1024,
+            max_batch_files: 1000,
+            max_changes: 1000
+        }
+    }
+}
+
+impl ServerSettings {
+    //Reads the file named by POCKET_DRIVE_SERVER_CONFIG, falling back to ./pocket-drive-server.json
+    pub fn load() -> Self {
+        let path = env::var(SERVER_CONFIG_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_SERVER_CONFIG_PATH));
+
+        let mut settings: Self = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .unwrap_or_else(|e| panic!("invalid server config {}: {}", path.display(), e)),
+            Err(_) => Self::default()
+        };
+        if let Ok(token) = env::var(SERVER_TOKEN_ENV) {
+            settings.token = Some(token);
+        }
+        settings
+    }
+}
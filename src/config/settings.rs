@@ -0,0 +1,391 @@
+use std::{env, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{db_listener::conflicts::ConflictStrategy, progress::renderer::RendererKind};
+
+pub const CONFIG_ENV: &str = "POCKET_DRIVE_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "pocket-drive.json";
+pub const TOKEN_ENV: &str = "POCKET_DRIVE_TOKEN";
+pub const PROXY_PASSWORD_ENV: &str = "POCKET_DRIVE_PROXY_PASSWORD";
+pub const S3_ACCESS_KEY_ENV: &str = "AWS_ACCESS_KEY_ID";
+pub const S3_SECRET_KEY_ENV: &str = "AWS_SECRET_ACCESS_KEY";
+
+//Every section has defaults, so a missing file or a partial file both work
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub progress: RendererKind,
+    pub hasher: HasherConfig,
+    pub server: ServerConfig,
+    pub retry: RetryConfig,
+    pub batch: BatchConfig,
+    pub upload: UploadConfig,
+    pub sync: SyncConfig,
+    pub conflict: ConflictConfig,
+    pub bandwidth: BandwidthConfig,
+    pub compression: CompressionConfig,
+    pub encryption: EncryptionConfig,
+    pub tls: TlsConfig,
+    pub proxy: ProxyConfig,
+    pub backend: BackendConfig
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConflictConfig {
+    pub strategy: ConflictStrategy
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HasherConfig {
+    //Size of the dedicated hashing pool, 0 lets rayon pick one thread per core
+    pub threads: usize,
+    //Cap on read bandwidth shared by all hashing threads
+    pub max_read_mbps: Option<u64>,
+    //Run hashing threads at nice 19 and in the idle IO class (Linux only)
+    pub low_priority: bool,
+    //Files at least this large also get a partial fingerprint stored, so an
+    //mtime-only change can be ruled out without reading the whole file
+    pub fingerprint_min_size: u64
+}
+
+impl Default for HasherConfig {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            max_read_mbps: None,
+            low_priority: false,
+            fingerprint_min_size: 64 * 1024 * 1024
+        }
+    }
+}
+
+impl Config {
+    //Reads the file named by POCKET_DRIVE_CONFIG, falling back to ./pocket-drive.json
+    pub fn load() -> Self {
+        let path = env::var(CONFIG_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .unwrap_or_else(|e| panic!("invalid config {}: {}", path.display(), e)),
+            Err(_) => Self::default()
+        }
+        .with_env_overrides()
+    }
+
+    //Secrets are easier to keep out of files
+    fn with_env_overrides(mut self) -> Self {
+        if let Ok(token) = env::var(TOKEN_ENV) {
+            self.server.token = Some(token);
+        }
+        if let Ok(password) = env::var(PROXY_PASSWORD_ENV) {
+            self.proxy.password = Some(password);
+        }
+        if let Ok(key) = env::var(S3_ACCESS_KEY_ENV) {
+            self.backend.s3.access_key_id.get_or_insert(key);
+        }
+        if let Ok(secret) = env::var(S3_SECRET_KEY_ENV) {
+            self.backend.s3.secret_access_key = Some(secret);
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub base_url: String,
+    //Sent as a bearer token, POCKET_DRIVE_TOKEN overrides it
+    pub token: Option<String>,
+    pub basic_auth: Option<BasicAuth>,
+    //Identifies this machine to the server, defaults to the hostname
+    pub device_id: String
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "http://localhost:8000".to_string(),
+            token: None,
+            basic_auth: None,
+            device_id: default_device_id()
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path.trim_start_matches('/'))
+    }
+}
+
+fn default_device_id() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok().map(|h| h.trim().to_string()))
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "pocket-drive".to_string())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    //Includes the first try
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    //Ceiling for both the backoff and a server supplied Retry-After
+    pub max_delay_ms: u64
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 60_000
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub max_files: usize,
+    //Total size of the file bodies in one sync request
+    pub max_bytes: u64,
+    //Files at least this large get a request of their own, so small files
+    //don't wait behind them
+    pub dedicated_min_size: u64
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_files: 500,
+            max_bytes: 256 * 1024 * 1024,
+            dedicated_min_size: 16 * 1024 * 1024
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    //Files at least this large go through a resumable upload session
+    //instead of a multipart part
+    pub resumable_threshold: u64,
+    pub chunk_size: u64,
+    //Sync requests in flight at once, they share one connection pool
+    pub workers: usize
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            resumable_threshold: 64 * 1024 * 1024,
+            chunk_size: 8 * 1024 * 1024,
+            workers: 4
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    //zstd bodies for the multipart sync request, resumable uploads stay raw
+    pub enabled: bool,
+    pub level: i32,
+    //Smaller files aren't worth the frame overhead
+    pub min_size: u64
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: 3,
+            min_size: 4096
+        }
+    }
+}
+
+//The passphrase comes from POCKET_DRIVE_PASSPHRASE. Every device syncing the
+//same files needs the same passphrase and salt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    //Also seal file names and every path component, not just the content
+    pub encrypt_paths: bool,
+    //Any string of at least 8 bytes, it doesn't need to be secret
+    pub salt: Option<String>,
+    //Plaintext bytes per authenticated chunk
+    pub chunk_size: u32
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            encrypt_paths: false,
+            salt: None,
+            chunk_size: 64 * 1024
+        }
+    }
+}
+
+//Certificate files are PEM. The defaults verify against the bundled web roots
+//like any browser would.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    //Extra roots trusted on top of the bundled ones, e.g. a home server's own CA
+    pub ca_file: Option<PathBuf>,
+    //Trust only ca_file, not the bundled roots
+    pub ca_only: bool,
+    //Certificate chain and private key presented when the server asks for one
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    //"sha256/<base64>" of the server's SubjectPublicKeyInfo, the leaf has to
+    //match one of them on top of the usual verification
+    pub pins: Vec<String>,
+    //Accepts any certificate, for development only. Pins are still checked.
+    pub insecure: bool
+}
+
+//Without a url the usual HTTPS_PROXY, HTTP_PROXY, ALL_PROXY and NO_PROXY
+//variables apply
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    //http://, https://, socks5:// or socks5h:// (the proxy resolves names)
+    pub url: Option<String>,
+    //Override credentials in the url, POCKET_DRIVE_PROXY_PASSWORD overrides password
+    pub username: Option<String>,
+    pub password: Option<String>,
+    //Comma separated hosts and domains to reach directly, replaces NO_PROXY
+    pub no_proxy: Option<String>,
+    //Look at the environment when no url is set
+    pub from_env: bool
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            username: None,
+            password: None,
+            no_proxy: None,
+            from_env: true
+        }
+    }
+}
+
+//Where synced files are kept. "http" is a pocket-drive server at
+//server.base_url, the others need no server at all.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    pub local: LocalBackendConfig,
+    pub s3: S3Config
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Http,
+    //A directory, usually a mounted drive or NAS share, mirroring the tree
+    Local,
+    //Any S3 compatible object store
+    S3
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LocalBackendConfig {
+    pub root: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct S3Config {
+    //e.g. https://s3.eu-west-1.amazonaws.com or http://nas:9000
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    //Prepended to every key, lets several trees share a bucket
+    pub prefix: String,
+    //AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY fill these in
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    //endpoint/bucket/key rather than bucket.endpoint/key, what MinIO and
+    //most self hosted stores expect
+    pub path_style: bool
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:9000".to_string(),
+            region: "us-east-1".to_string(),
+            bucket: "pocket-drive".to_string(),
+            prefix: String::new(),
+            access_key_id: None,
+            secret_access_key: None,
+            path_style: true
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    //How often the server change feed is polled, 0 turns downloads off
+    pub pull_interval_secs: u64
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            pull_interval_secs: 30
+        }
+    }
+}
+
+//Limits are in KiB/s, a missing limit means unlimited. The first schedule
+//window containing the current local time replaces the defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    pub upload_kbps: Option<u64>,
+    pub download_kbps: Option<u64>,
+    //Shared by uploads and downloads together
+    pub total_kbps: Option<u64>,
+    pub schedule: Vec<BandwidthWindow>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandwidthWindow {
+    //"HH:MM" local time, a window that ends before it starts runs past midnight
+    pub start: String,
+    pub end: String,
+    #[serde(default)]
+    pub upload_kbps: Option<u64>,
+    #[serde(default)]
+    pub download_kbps: Option<u64>,
+    #[serde(default)]
+    pub total_kbps: Option<u64>
+}
@@ -0,0 +1,244 @@
+use std::{fs::File, io::{self, BufWriter, Read, Write}, path::Path};
+
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce, aead::{Aead, Payload}};
+
+use crate::{crypto::vault::{MAX_CHUNK_SIZE, Vault, random}, file_hasher::hasher::to_hex};
+
+//Layout of an encrypted body:
+//  "PDE1" | chunk size u32 BE | wrap nonce (24) | wrapped file key (48) | base nonce (16)
+//followed by the chunks, each chunk_size bytes of plaintext plus a 16 byte
+//tag. The last chunk is flagged in its nonce and may be empty, so dropping
+//or reordering chunks fails authentication. The header is the associated
+//data of every chunk.
+pub const MAGIC: &[u8; 4] = b"PDE1";
+pub const HEADER_LEN: usize = 4 + 4 + 24 + 48 + 16;
+const TAG_LEN: u64 = 16;
+
+//Value of the DTO encryption field
+pub const ALGORITHM: &str = "xchacha20poly1305";
+
+pub fn sealed_size(plain: u64, chunk_size: u32) -> u64 {
+    let chunks = plain / chunk_size as u64 + 1;
+    HEADER_LEN as u64 + plain + chunks * TAG_LEN
+}
+
+//Inverse of sealed_size, for sizes the server reports
+pub fn opened_size(sealed: u64, chunk_size: u32) -> u64 {
+    let body = sealed.saturating_sub(HEADER_LEN as u64);
+    let unit = chunk_size as u64 + TAG_LEN;
+    (body / unit) * chunk_size as u64 + (body % unit).saturating_sub(TAG_LEN)
+}
+
+fn chunk_nonce(base: &[u8], index: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..16].copy_from_slice(base);
+    nonce[16..20].copy_from_slice(&index.to_be_bytes());
+    nonce[20] = last as u8;
+    nonce.into()
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+//Writes the encrypted form of source to target, returns its size and hash.
+//Every call picks a new file key, so the hash differs between calls.
+pub fn encrypt_file(vault: &Vault, source: &Path, target: &Path) -> io::Result<(u64, String)> {
+    let file_key = vault.file_key();
+    let (wrap_nonce, wrapped) = vault.wrap(&file_key);
+    let base_nonce: [u8; 16] = random();
+    let chunk_size = vault.chunk_size();
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&chunk_size.to_be_bytes());
+    header.extend_from_slice(&wrap_nonce);
+    header.extend_from_slice(&wrapped);
+    header.extend_from_slice(&base_nonce);
+
+    let cipher = XChaCha20Poly1305::new(&file_key.into());
+    let mut input = File::open(source)?;
+    let mut output = BufWriter::new(File::create(target)?);
+    output.write_all(&header)?;
+    let mut hash = Blake2s256::new();
+    hash.update(&header);
+
+    let mut buf = vec![0u8; chunk_size as usize];
+    let mut written = header.len() as u64;
+    let mut index = 0u32;
+    loop {
+        let n = fill(&mut input, &mut buf)?;
+        let last = n < buf.len();
+        let sealed = cipher
+            .encrypt(&chunk_nonce(&base_nonce, index, last), Payload { msg: &buf[..n], aad: &header })
+            .map_err(|_| invalid("encryption failed"))?;
+        output.write_all(&sealed)?;
+        hash.update(&sealed);
+        written += sealed.len() as u64;
+        index = index.checked_add(1).ok_or_else(|| invalid("file too large to encrypt"))?;
+        if last {
+            break;
+        }
+    }
+    output.flush()?;
+    Ok((written, to_hex(&hash.finalize())))
+}
+
+//Reads until buf is full or the file ends
+fn fill(input: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n
+        }
+    }
+    Ok(filled)
+}
+
+//Decrypts a body as it streams in. A chunk is only opened once more bytes
+//follow it, whatever is left at finish has to be the flagged last chunk.
+pub struct Decryptor<'a> {
+    vault: &'a Vault,
+    header: Vec<u8>,
+    cipher: Option<XChaCha20Poly1305>,
+    chunk_len: usize,
+    buf: Vec<u8>,
+    index: u32
+}
+
+impl<'a> Decryptor<'a> {
+    pub fn new(vault: &'a Vault) -> Self {
+        Self {
+            vault,
+            header: Vec::with_capacity(HEADER_LEN),
+            cipher: None,
+            chunk_len: 0,
+            buf: Vec::new(),
+            index: 0
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) -> io::Result<Vec<u8>> {
+        if self.cipher.is_none() {
+            let take = (HEADER_LEN - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.header.len() < HEADER_LEN {
+                return Ok(Vec::new());
+            }
+            self.open_header()?;
+        }
+
+        self.buf.extend_from_slice(data);
+        let mut plain = Vec::new();
+        while self.buf.len() > self.chunk_len {
+            let chunk: Vec<u8> = self.buf.drain(..self.chunk_len).collect();
+            plain.extend(self.open_chunk(&chunk, false)?);
+        }
+        Ok(plain)
+    }
+
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        if self.cipher.is_none() {
+            return Err(invalid("encrypted body is truncated"));
+        }
+        let chunk = std::mem::take(&mut self.buf);
+        self.open_chunk(&chunk, true)
+    }
+
+    fn open_header(&mut self) -> io::Result<()> {
+        if &self.header[..4] != MAGIC {
+            return Err(invalid("not an encrypted body"));
+        }
+        let chunk_size = u32::from_be_bytes(self.header[4..8].try_into().unwrap());
+        //The header isn't authenticated until the first chunk opens
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(invalid("chunk size in the header is out of range"));
+        }
+        let file_key = self.vault
+            .unwrap(&self.header[8..32], &self.header[32..80])
+            .ok_or_else(|| invalid("file key doesn't unwrap, wrong passphrase or salt"))?;
+        self.cipher = Some(XChaCha20Poly1305::new(&file_key.into()));
+        self.chunk_len = chunk_size as usize + TAG_LEN as usize;
+        Ok(())
+    }
+
+    fn open_chunk(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.header[80..96], self.index, last);
+        self.index += 1;
+        self.cipher.as_ref().unwrap()
+            .decrypt(&nonce, Payload { msg: chunk, aad: &self.header })
+            .map_err(|_| invalid("encrypted body failed authentication"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vault() -> Vault {
+        Vault::derive(b"correct horse", b"pocket-drive-salt", true, 4096).unwrap()
+    }
+
+    fn decrypt(vault: &Vault, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decryptor = Decryptor::new(vault);
+        //Odd pieces, the way a body streams in
+        let mut plain = Vec::new();
+        for piece in body.chunks(1000) {
+            plain.extend(decryptor.push(piece)?);
+        }
+        plain.extend(decryptor.finish()?);
+        Ok(plain)
+    }
+
+    fn encrypt(vault: &Vault, plain: &[u8]) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("plain"), dir.path().join("sealed"));
+        std::fs::write(&source, plain).unwrap();
+        let (size, _) = encrypt_file(vault, &source, &target).unwrap();
+        let body = std::fs::read(&target).unwrap();
+        assert_eq!(size, body.len() as u64);
+        assert_eq!(size, sealed_size(plain.len() as u64, vault.chunk_size()));
+        body
+    }
+
+    #[test]
+    fn round_trips_across_chunks() {
+        let vault = vault();
+        for len in [0, 1, 4096, 4097, 3 * 4096 + 17] {
+            let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(decrypt(&vault, &encrypt(&vault, &plain)).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn equal_content_encrypts_differently() {
+        let vault = vault();
+        let (a, b) = (encrypt(&vault, b"same bytes"), encrypt(&vault, b"same bytes"));
+        assert_ne!(a, b);
+        assert_ne!(a[8..80], b[8..80], "file keys must not repeat");
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let body = encrypt(&vault(), b"secret");
+        let other = Vault::derive(b"wrong", b"pocket-drive-salt", true, 4096).unwrap();
+        assert!(decrypt(&other, &body).is_err());
+    }
+
+    #[test]
+    fn oversized_chunk_size_in_header_is_refused() {
+        let vault = vault();
+        let mut body = encrypt(&vault, b"secret");
+        body[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        let error = Decryptor::new(&vault).push(&body).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn short_salt_is_an_error() {
+        assert!(matches!(Vault::derive(b"pass", b"short", true, 4096), Err(crate::crypto::vault::VaultError::BadSalt(_))));
+    }
+}
@@ -0,0 +1,2 @@
+pub mod vault;
+pub mod container;
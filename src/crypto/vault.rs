@@ -0,0 +1,193 @@
+use std::{env, fmt};
+
+use argon2::Argon2;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use blake2::{Blake2sMac256, digest::Mac};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce, aead::{Aead, OsRng, rand_core::RngCore}};
+
+use crate::config::settings::EncryptionConfig;
+
+//Never read from the config file, so the passphrase can't end up in a backup of it
+pub const PASSPHRASE_ENV: &str = "POCKET_DRIVE_PASSPHRASE";
+
+pub(crate) type Key = [u8; 32];
+
+//Upper bound on encryption.chunk_size and on the chunk size a container
+//header may claim, a whole chunk is held in memory while it is opened
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum VaultError {
+    MissingPassphrase,
+    MissingSalt,
+    //Argon2 refused the salt, it is shorter than 8 bytes
+    BadSalt(String)
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::MissingPassphrase => write!(f, "encryption.enabled is set but {} is not", PASSPHRASE_ENV),
+            VaultError::MissingSalt => write!(f, "encryption.enabled is set but encryption.salt is missing"),
+            VaultError::BadSalt(e) => write!(f, "encryption.salt can't be used: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for VaultError {}
+
+//Keys derived from the passphrase. They only ever live in memory on this
+//machine, the server sees wrapped file keys and sealed strings.
+pub struct Vault {
+    wrap_key: Key,
+    name_key: Key,
+    hash_key: Key,
+    encrypt_paths: bool,
+    chunk_size: u32
+}
+
+impl Vault {
+    //None when encryption is off. Turning it on without a passphrase or salt
+    //is a configuration error, syncing plaintext instead would be worse.
+    pub fn from_config(config: &EncryptionConfig) -> Result<Option<Self>, VaultError> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let passphrase = env::var(PASSPHRASE_ENV).map_err(|_| VaultError::MissingPassphrase)?;
+        let salt = config.salt.as_deref().ok_or(VaultError::MissingSalt)?;
+
+        Self::derive(passphrase.as_bytes(), salt.as_bytes(), config.encrypt_paths, config.chunk_size).map(Some)
+    }
+
+    //Argon2id with its default cost gives the master key, every other key is
+    //a keyed hash of it with a label
+    pub fn derive(passphrase: &[u8], salt: &[u8], encrypt_paths: bool, chunk_size: u32) -> Result<Self, VaultError> {
+        let mut master = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase, salt, &mut master)
+            .map_err(|e| VaultError::BadSalt(e.to_string()))?;
+
+        Ok(Self {
+            wrap_key: mac(&master, &[b"wrap"]),
+            name_key: mac(&master, &[b"names"]),
+            hash_key: mac(&master, &[b"hashes"]),
+            encrypt_paths,
+            chunk_size: chunk_size.clamp(4096, MAX_CHUNK_SIZE)
+        })
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    //Fresh for every encryption, so equal files don't encrypt to equal bytes
+    //and the server can't tell which of them match. It travels wrapped in
+    //the container header.
+    pub(crate) fn file_key(&self) -> Key {
+        random()
+    }
+
+    pub(crate) fn wrap(&self, file_key: &Key) -> ([u8; 24], Vec<u8>) {
+        let nonce: [u8; 24] = random();
+        let wrapped = XChaCha20Poly1305::new(&self.wrap_key.into())
+            .encrypt(XNonce::from_slice(&nonce), file_key.as_slice())
+            .unwrap();
+        (nonce, wrapped)
+    }
+
+    pub(crate) fn unwrap(&self, nonce: &[u8], wrapped: &[u8]) -> Option<Key> {
+        let key = XChaCha20Poly1305::new(&self.wrap_key.into())
+            .decrypt(XNonce::from_slice(nonce), wrapped)
+            .ok()?;
+        key.try_into().ok()
+    }
+
+    //Each component is sealed on its own so the server can still see the
+    //directory structure, just not the names in it
+    pub fn seal_path(&self, path: &str) -> String {
+        if !self.encrypt_paths {
+            return path.to_string();
+        }
+        map_components(path, |c| Some(seal(&self.name_key, c))).unwrap()
+    }
+
+    pub fn open_path(&self, path: &str) -> Option<String> {
+        if !self.encrypt_paths {
+            return Some(path.to_string());
+        }
+        map_components(path, |c| open(&self.name_key, c))
+    }
+
+    pub fn seal_name(&self, name: &str) -> String {
+        match self.encrypt_paths {
+            true => seal(&self.name_key, name),
+            false => name.to_string()
+        }
+    }
+
+    pub fn open_name(&self, name: &str) -> Option<String> {
+        match self.encrypt_paths {
+            true => open(&self.name_key, name),
+            false => Some(name.to_string())
+        }
+    }
+
+    //Content hashes are always sealed, a plain hash would let the server
+    //confirm a guess of what a file contains
+    pub fn seal_hash(&self, hash: &str) -> String {
+        seal(&self.hash_key, hash)
+    }
+
+    pub fn open_hash(&self, hash: &str) -> Option<String> {
+        open(&self.hash_key, hash)
+    }
+}
+
+pub(crate) fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+pub(crate) fn mac(key: &Key, parts: &[&[u8]]) -> Key {
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+//Deterministic authenticated encryption: the nonce is a MAC of the
+//plaintext, so equal strings seal to equal output and lookups by path work
+fn seal(key: &Key, plain: &str) -> String {
+    let nonce: [u8; 24] = mac(key, &[plain.as_bytes()])[..24].try_into().unwrap();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        XChaCha20Poly1305::new(key.into())
+            .encrypt(XNonce::from_slice(&nonce), plain.as_bytes())
+            .unwrap()
+    );
+    URL_SAFE_NO_PAD.encode(sealed)
+}
+
+fn open(key: &Key, sealed: &str) -> Option<String> {
+    let sealed = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+    if sealed.len() < 24 {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(24);
+    let plain = XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .ok()?;
+    String::from_utf8(plain).ok()
+}
+
+fn map_components(path: &str, mut f: impl FnMut(&str) -> Option<String>) -> Option<String> {
+    path.split('/')
+        .map(|c| match c {
+            "" | "." | ".." => Some(c.to_string()),
+            c => f(c)
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|parts| parts.join("/"))
+}
@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+
+use crate::file_uploader::file_upload::{FileEntryDTO, RemoteConflict};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+    //Keep the remote version at the path and the local one as a conflict copy
+    #[default]
+    KeepBoth,
+    LocalWins,
+    RemoteWins,
+    NewestWins
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    KeepBoth,
+    Local,
+    Remote
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::KeepBoth => "keep_both",
+            Resolution::Local => "local",
+            Resolution::Remote => "remote"
+        }
+    }
+}
+
+pub fn resolve(strategy: ConflictStrategy, local: &FileEntryDTO, remote: &RemoteConflict) -> Resolution {
+    match strategy {
+        ConflictStrategy::KeepBoth => Resolution::KeepBoth,
+        ConflictStrategy::LocalWins => Resolution::Local,
+        ConflictStrategy::RemoteWins => Resolution::Remote,
+        ConflictStrategy::NewestWins => {
+            if local.modified_time >= remote.remote_modified_time.unwrap_or(0) {
+                Resolution::Local
+            } else {
+                Resolution::Remote
+            }
+        }
+    }
+}
+
+//notes.txt -> notes (conflict from laptop 2026-10-18 142501).txt
+pub fn conflict_copy_path(path: &Path, device: &str, when: DateTime<Local>) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let suffix = format!("(conflict from {} {})", device, when.format("%Y-%m-%d %H%M%S"));
+    let name = match path.extension() {
+        Some(ext) => format!("{} {}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{} {}", stem, suffix)
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn copy_of(path: &str) -> PathBuf {
+        let when = Local.with_ymd_and_hms(2026, 10, 18, 14, 25, 1).unwrap();
+        conflict_copy_path(Path::new(path), "laptop", when)
+    }
+
+    #[test]
+    fn names_the_copy_before_the_extension() {
+        assert_eq!(copy_of("/home/notes.txt"), Path::new("/home/notes (conflict from laptop 2026-10-18 142501).txt"));
+        assert_eq!(copy_of("/home/backup.tar.gz"), Path::new("/home/backup.tar (conflict from laptop 2026-10-18 142501).gz"));
+    }
+
+    #[test]
+    fn names_without_an_extension_get_the_suffix_at_the_end() {
+        assert_eq!(copy_of("/home/Makefile"), Path::new("/home/Makefile (conflict from laptop 2026-10-18 142501)"));
+        assert_eq!(copy_of("/home/.bashrc"), Path::new("/home/.bashrc (conflict from laptop 2026-10-18 142501)"));
+        assert_eq!(copy_of("/home/.config.toml"), Path::new("/home/.config (conflict from laptop 2026-10-18 142501).toml"));
+    }
+
+    fn sides(local_modified: i64, remote_modified: Option<i64>) -> (FileEntryDTO, RemoteConflict) {
+        let local = FileEntryDTO {
+            file_name: "a.txt".to_string(),
+            file_path: "/a.txt".to_string(),
+            file_hash: Some("local".to_string()),
+            file_size: 1,
+            modified_time: local_modified,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        };
+        let remote = RemoteConflict {
+            file_path: "/a.txt".to_string(),
+            remote_hash: Some("remote".to_string()),
+            remote_size: Some(1),
+            remote_modified_time: remote_modified,
+            device_id: Some("phone".to_string())
+        };
+        (local, remote)
+    }
+
+    #[test]
+    fn resolves_by_strategy() {
+        let (local, remote) = sides(10, Some(20));
+        assert_eq!(resolve(ConflictStrategy::KeepBoth, &local, &remote), Resolution::KeepBoth);
+        assert_eq!(resolve(ConflictStrategy::LocalWins, &local, &remote), Resolution::Local);
+        assert_eq!(resolve(ConflictStrategy::RemoteWins, &local, &remote), Resolution::Remote);
+    }
+
+    #[test]
+    fn newest_wins_keeps_the_local_version_on_a_tie() {
+        let newest = |local, remote| {
+            let (local, remote) = sides(local, remote);
+            resolve(ConflictStrategy::NewestWins, &local, &remote)
+        };
+        assert_eq!(newest(10, Some(20)), Resolution::Remote);
+        assert_eq!(newest(20, Some(10)), Resolution::Local);
+        assert_eq!(newest(20, Some(20)), Resolution::Local);
+        assert_eq!(newest(20, None), Resolution::Local);
+    }
+}
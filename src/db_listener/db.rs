@@ -1,12 +1,30 @@
-use std::{collections::HashMap, env, path::PathBuf, sync::mpsc, time::{Duration, SystemTime}};
+use std::{collections::{HashMap, HashSet}, env, path::PathBuf, sync::mpsc, time::{Duration, SystemTime}};
 
 use notify_debouncer_full::DebouncedEvent;
 use serde::Serialize;
-use sqlite::{Connection, State};
+use sqlite::{Connection, State, Statement};
 use walkdir::WalkDir;
 use std::sync::mpsc::{Receiver, Sender, channel};
 
-use crate::{file_hasher::hasher::HasherCmd, file_uploader::file_upload::{FileEntryDTO, FileUploaderCmd, Operations}};
+use crate::{config::settings::Config, db_listener::conflicts::{ConflictStrategy, Resolution, conflict_copy_path, resolve}, duplicates::report::{DuplicateGroup, find_duplicates}, file_hasher::hasher::HasherCmd, file_uploader::{batch::SyncBatch, file_upload::{FileEntryDTO, FileUploaderCmd, Operations, RemoteConflict, SyncOutcome, UploaderStatus}, response::{ItemResult, ItemStatus, SyncResponse}}};
+use tokio::sync::watch;
+
+//The sqlite file holding the index, relative to the working directory
+pub const INDEX_PATH: &str = "memory";
+pub const BUSY_TIMEOUT_MS: usize = 5000;
+
+const ENTRY_COLUMNS: &str = "filepath, filehash, size, modified, filename, fingerprint";
+
+//Columns added after the first release, appended to older databases on open
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("fingerprint", "TEXT"),
+    //Hash the server last confirmed for the path, sent as base_hash
+    ("synced_hash", "TEXT"),
+    //Outcome of the last sync of the path, see ItemStatus::as_str, plus "pending"
+    ("sync_state", "TEXT"),
+    //Version id the server gave the content last stored from this device
+    ("remote_version", "TEXT"),
+];
 
 #[derive(Debug, Clone,Serialize)]
 pub struct FileEntry {
@@ -15,6 +33,8 @@ pub struct FileEntry {
     pub hash: Option<String>,
     pub size: u64,
     pub modified: SystemTime,
+    //Size plus head, tail and sampled blocks, only kept for large files
+    pub fingerprint: Option<String>,
 }
 
 #[derive(Debug)]
@@ -25,10 +45,25 @@ pub enum DbCmd{
     BulkInsert(Vec<FileEntry>),
     BulkDelete(Vec<FileEntry>),
     BulkUpdate(Vec<FileEntry>),
+    BulkTouch(Vec<FileEntry>),
+    Duplicates(Vec<PathBuf>, Sender<Vec<DuplicateGroup>>),
+    //Fetch and apply what other devices changed on the server
+    PullRemote,
+    //Replay the queued operations, sent when the server is reachable again
+    FlushPending,
     Delete(PathBuf),
     Update(FileEntry)
 }
 
+//Result of comparing a file on disk against its index entry
+#[derive(PartialEq, Eq, Debug)]
+pub enum MetadataMatch {
+    Same,
+    //Only the mtime moved, the content may or may not have changed
+    MtimeOnly,
+    Changed
+}
+
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub enum ParserCmd {
     Insert,
@@ -41,23 +76,25 @@ pub struct Db{
     tx: Sender<DbCmd>,
     rx: Receiver<DbCmd>,
     tx_hasher: Sender<HasherCmd>,
-    tx_uploader: tokio::sync::mpsc::Sender<FileUploaderCmd>
+    tx_uploader: tokio::sync::mpsc::Sender<FileUploaderCmd>,
+    conflict_strategy: ConflictStrategy,
+    device_id: String,
+    uploader_status: watch::Receiver<UploaderStatus>
 }
 
 impl Db{
-    pub fn new(tx_hasher: Sender<HasherCmd>, tx_uploader: tokio::sync::mpsc::Sender<FileUploaderCmd>) -> Self {
+    pub fn new(tx_hasher: Sender<HasherCmd>, tx_uploader: tokio::sync::mpsc::Sender<FileUploaderCmd>, uploader_status: watch::Receiver<UploaderStatus>, config: &Config) -> Self {
         let (tx, rx) = channel();
-        let connection = sqlite::open("memory").unwrap();
-        let query = "
-            CREATE TABLE IF NOT EXISTS filehash (filepath TEXT, filehash TEXT, size BIGDECIMAL, modified DATETIME, filename TEXT);
-        ";
-        connection.execute(query).unwrap();
+        let connection = open_index();
         Db{
             conn: connection,
             tx,
             rx,
             tx_hasher,
-            tx_uploader
+            tx_uploader,
+            conflict_strategy: config.conflict.strategy,
+            device_id: config.server.device_id.clone(),
+            uploader_status
         }
     }
 
@@ -79,6 +116,7 @@ impl Db{
                     hash: None,
                     size: metadata.len(),
                     modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    fingerprint: None,
                 };
 
                 paths.push(f);
@@ -86,7 +124,7 @@ impl Db{
         }
         let (tx_db, rx_db) = channel();
         
-        dbg!("sent to hasher for initialisation of {} files", paths.len());
+        tracing::debug!("sending {} files to the hasher for initialisation", paths.len());
 
         self.tx_hasher.send(HasherCmd::Generate(paths.clone(), tx_db)).unwrap();
         
@@ -101,6 +139,8 @@ impl Db{
 
     pub fn run(&self, path: &str) {
         self.initialise(path);
+        //Anything left over from a previous run that couldn't be uploaded
+        self.sync(HashMap::new());
         while let Ok(cmd) = self.rx.recv() {
             self.execute(cmd).unwrap();
         }
@@ -110,36 +150,25 @@ impl Db{
         match cmd {
             DbCmd::Get(path, sender) => {
                 let mut stmt = self.conn.prepare(
-                    "Select * from filehash where filepath = ?"
+                    format!("Select {} from filehash where filepath = ?", ENTRY_COLUMNS)
                 )?;
                 stmt.bind((1, path.to_str())).unwrap();
                 if let Ok(State::Row) = stmt.next() {
-                    let path: String = stmt.read(0)?;
-                    let hash: String = stmt.read(1)?;
-                    let size: i64 = stmt.read(2)?;
-                    let modified: i64 = stmt.read(3)?;
-                    let filename: String = stmt.read(4)?;
-
-                    sender.send(Some(FileEntry{
-                        filename,
-                        path: PathBuf::from(path),
-                        hash: Some(hash),
-                        size: size as u64,
-                        modified: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(modified as u64)
-                    })).unwrap();
+                    sender.send(Some(read_entry(&stmt)?)).unwrap();
                 };
                 sender.send(None).unwrap();
                 Ok(None)
             }
             DbCmd::Insert(file) => {
                 let mut stmt = self.conn.prepare(
-                    "Insert INTO filehash VALUES (?,?,?,?,?)"
+                    "Insert INTO filehash (filepath, filehash, size, modified, filename, fingerprint) VALUES (?,?,?,?,?,?)"
                 )?;
                 stmt.bind((1, file.path.to_str()))?;
                 stmt.bind((2, file.hash.unwrap_or("".to_string()).as_str()))?;
                 stmt.bind((3, file.size as i64))?;
                 stmt.bind((4, file.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64))?;
                 stmt.bind((5, file.filename.as_str()))?;
+                stmt.bind((6, file.fingerprint.as_deref()))?;
 
                 stmt.next()?;
                 Ok(None)
@@ -156,12 +185,13 @@ impl Db{
             
             DbCmd::Update(file) => {
                 let mut stmt = self.conn.prepare(
-                    "INSERT INTO filehash (filepath, filehash, size, modified, filename)
-                    VALUES (?, ?, ?, ?, ?)
+                    "INSERT INTO filehash (filepath, filehash, size, modified, filename, fingerprint)
+                    VALUES (?, ?, ?, ?, ?, ?)
                     ON CONFLICT(filepath) DO UPDATE SET
                          filehash = excluded.filehash,
                          size = excluded.size,
-                         modified = excluded.modified"
+                         modified = excluded.modified,
+                         fingerprint = excluded.fingerprint"
                 )?;
 
                 stmt.bind((1, file.path.to_str().unwrap()))?;
@@ -169,6 +199,7 @@ impl Db{
                 stmt.bind((3, file.size as i64))?;
                 stmt.bind((4, file.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64))?;
                 stmt.bind((5, file.filename.as_str()))?;
+                stmt.bind((6, file.fingerprint.as_deref()))?;
                 stmt.next()?;
 
                 Ok(None)
@@ -177,7 +208,14 @@ impl Db{
             DbCmd::BulkInsert(files) => {
                 self.conn.execute("BEGIN TRANSACTION")?;
                 let mut stmt = self.conn.prepare(
-                    "INSERT OR REPLACE INTO filehash (filepath, filehash, size, modified, filename) VALUES (?, ?, ?, ?, ?)"
+                    "INSERT INTO filehash (filepath, filehash, size, modified, filename, fingerprint)
+                    VALUES (?, ?, ?, ?, ?, ?)
+                    ON CONFLICT(filepath) DO UPDATE SET
+                         filehash = excluded.filehash,
+                         size = excluded.size,
+                         modified = excluded.modified,
+                         filename = excluded.filename,
+                         fingerprint = excluded.fingerprint"
                 )?;
 
                 for file in files {
@@ -192,6 +230,7 @@ impl Db{
                             .as_secs() as i64,
                     ))?;
                     stmt.bind((5, file.filename.as_str()))?;
+                    stmt.bind((6, file.fingerprint.as_deref()))?;
 
                     stmt.next()?;
                     stmt.reset()?;
@@ -240,7 +279,7 @@ impl Db{
                 self.conn.execute("BEGIN TRANSACTION")?;
                 let mut stmt = self.conn.prepare(
                     "UPDATE filehash
-                     SET filehash = ?, size = ?, modified = ?, filename = ?
+                     SET filehash = ?, size = ?, modified = ?, filename = ?, fingerprint = ?
                      WHERE filepath = ?"
                 )?;
 
@@ -249,7 +288,8 @@ impl Db{
                     stmt.bind((2, file.size as i64))?;
                     stmt.bind((3, file.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64))?;
                     stmt.bind((4, file.filename.as_str()))?;
-                    stmt.bind((5, file.path.to_str().unwrap()))?;
+                    stmt.bind((5, file.fingerprint.as_deref()))?;
+                    stmt.bind((6, file.path.to_str().unwrap()))?;
                     stmt.next()?;
                     stmt.reset()?;
                 }
@@ -257,133 +297,205 @@ impl Db{
                 Ok(None)
             }
 
-            //Walk the entire directory and compare the metadata of file with the metadata in DB;; //3 case: //Diff -> update hash and metadata update the db entry //Missing -> insert //Present, same hash -> ignore //Present in DB, not in directory -> Remove //Get the entire db in memory in Bulk, as a hashMap of <Path, FileEntry> //Create the HashMap of directory files as <Path, FileEntry> //Make comparisons and store in a separate map with <DbCmd, Vec<FileEntry>> //Execute each command over the vector in batch //Send the same command over to the Server for sync
-            DbCmd::ProcessEvents(events) => {
+            DbCmd::PullRemote => {
+                self.pull_remote()?;
+                Ok(None)
+            }
+
+            DbCmd::FlushPending => {
+                self.sync(HashMap::new());
+                Ok(None)
+            }
+
+            DbCmd::Duplicates(roots, sender) => {
+                sender.send(find_duplicates(&self.conn, &roots)?).unwrap();
+                Ok(None)
+            }
+
+            //Content is known to be unchanged, only move the mtime forward
+            DbCmd::BulkTouch(files) => {
+                self.conn.execute("BEGIN TRANSACTION")?;
                 let mut stmt = self.conn.prepare(
-                    "SELECT * FROM filehash"
+                    "UPDATE filehash SET modified = ?, fingerprint = COALESCE(?, fingerprint) WHERE filepath = ?"
                 )?;
 
-                let mut db_map: HashMap<PathBuf, FileEntry> = HashMap::new();
+                for file in files {
+                    stmt.bind((1, file.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64))?;
+                    stmt.bind((2, file.fingerprint.as_deref()))?;
+                    stmt.bind((3, file.path.to_str().unwrap()))?;
+                    stmt.next()?;
+                    stmt.reset()?;
+                }
+                self.conn.execute("COMMIT")?;
+                Ok(None)
+            }
+
+            //Walk the entire directory and compare the metadata of file with the metadata in DB;; //3 case: //Diff -> update hash and metadata update the db entry //Missing -> insert //Present, same hash -> ignore //Present in DB, not in directory -> Remove //Get the entire db in memory in Bulk, as a hashMap of <Path, FileEntry> //Create the HashMap of directory files as <Path, FileEntry> //Make comparisons and store in a separate map with <DbCmd, Vec<FileEntry>> //Execute each command over the vector in batch //Send the same command over to the Server for sync
+            DbCmd::ProcessEvents(events) => {
+                tracing::debug!("rescanning after {} watcher events", events.len());
+                let args: Vec<String> = env::args().collect();
+                self.rescan(&args[1])?;
+                Ok(None)
+            }
+        }
+
+    }
 
-                while let Ok(State::Row) = stmt.next() {
-                    let path: String = stmt.read(0)?;
-                    let hash: String = stmt.read(1)?;
-                    let size: i64 = stmt.read(2)?;
-                    let modified: i64 = stmt.read(3)?;
-                    let filename: String = stmt.read(4)?;
+    fn rescan(&self, path: &str) -> sqlite::Result<()> {
+        let mut stmt = self.conn.prepare(
+            format!("SELECT {} FROM filehash", ENTRY_COLUMNS)
+        )?;
 
-                    let path_buf = PathBuf::from(path);
+        let mut db_map: HashMap<PathBuf, FileEntry> = HashMap::new();
 
-                    let f = FileEntry {
-                        path: path_buf.clone(),
-                        hash: Some(hash),
-                        size: size as u64,
-                        modified: SystemTime::UNIX_EPOCH + Duration::from_secs(modified as u64),
-                        filename
-                    };
+        while let Ok(State::Row) = stmt.next() {
+            let f = read_entry(&stmt)?;
+            db_map.insert(f.path.clone(), f);
+        }
+        let mut directory_map: HashMap<PathBuf, FileEntry> = HashMap::new();
 
-                    db_map.insert(path_buf, f);
-                }
-                let args: Vec<String> = env::args().collect();
-                let path = &args[1];
-                let mut directory_map: HashMap<PathBuf, FileEntry> = HashMap::new();
+        for entry in WalkDir::new(path).into_iter().filter_map(Result::ok) {
 
-                for entry in WalkDir::new(path).into_iter().filter_map(Result::ok) {
+            let metadata = match entry.metadata() {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
 
-                    let metadata = match entry.metadata() {
-                        Ok(m) => m,
-                        Err(_) => continue,
-                    };
+            if !metadata.is_file() {
+                continue;
+            }
 
-                    if !metadata.is_file() {
-                        continue;
-                    }
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.into_path();
 
-                    let filename = entry.file_name().to_string_lossy().into_owned();
-                    let path = entry.into_path();
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
 
-                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            let f = FileEntry {
+                path: path.clone(),
+                hash: None,
+                size: metadata.len(),
+                modified,
+                filename,
+                fingerprint: None
+            };
 
-                    let f = FileEntry {
-                        path: path.clone(),
-                        hash: None,
-                        size: metadata.len(),
-                        modified,
-                        filename
-                    };
+            directory_map.insert(path, f);
+        }
+        //3 cases 
+        //Present in Db but not in directory then should be removed
+        //Not present in DB Present in directory, then should be added
+        //Present in both, but metadata is different then update
+        //Present in both, and metadata same, then make no change
+        let mut parser_cmds: HashMap<ParserCmd, Vec<FileEntry>> = HashMap::new();
+        let mut fingerprint_candidates: Vec<FileEntry> = Vec::new();
+
+        for (path, fi) in &db_map {
+            if !directory_map.contains_key(path) {
+                parser_cmds
+                    .entry(ParserCmd::Delete)
+                    .or_default()
+                    .push(fi.clone());
+            }
+        }
 
-                    directory_map.insert(path, f);
-                }
-                //3 cases 
-                //Present in Db but not in directory then should be removed
-                //Not present in DB Present in directory, then should be added
-                //Present in both, but metadata is different then update
-                //Present in both, and metadata same, then make no change
-                let mut parser_cmds: HashMap<ParserCmd, Vec<FileEntry>> = HashMap::new();
-
-                for (path, fi) in &db_map {
-                    if !directory_map.contains_key(path) {
-                        parser_cmds
-                            .entry(ParserCmd::Delete)
-                            .or_default()
-                            .push(fi.clone());
+        for (path, fi) in directory_map {
+            if db_map.contains_key(&path) {
+                let file1 = fi;
+                let file2 = db_map.get(&file1.path).unwrap();
+                match self.compare_metadata(&file1, file2) {
+                    MetadataMatch::Same => {}
+                    //Large files get a cheap fingerprint check before a full read
+                    MetadataMatch::MtimeOnly if file2.fingerprint.is_some() => {
+                        fingerprint_candidates.push(file1);
                     }
-                }
-
-                for (path, fi) in directory_map {
-                    if db_map.contains_key(&path) {
-                        let file1 = fi;
-                        let file2 = db_map.get(&file1.path).unwrap();
-                        if !self.is_metadata_same(&file1, file2) {
-                            parser_cmds
-                                .entry(ParserCmd::Update)
-                                .or_default()
-                                .push(file1);
-                        }
-                    } else {
+                    MetadataMatch::MtimeOnly | MetadataMatch::Changed => {
                         parser_cmds
-                            .entry(ParserCmd::Insert)
+                            .entry(ParserCmd::Update)
                             .or_default()
-                            .push(fi);
+                            .push(file1);
                     }
                 }
+            } else {
+                parser_cmds
+                    .entry(ParserCmd::Insert)
+                    .or_default()
+                    .push(fi);
+            }
+        }
 
-                dbg!(&parser_cmds);
-                self.execute_parser_cmds(parser_cmds);
-
-                Ok(None)            
+        if !fingerprint_candidates.is_empty() {
+            let (touched, changed) = self.split_by_fingerprint(fingerprint_candidates, &db_map);
+            if !touched.is_empty() {
+                self.execute(DbCmd::BulkTouch(touched))?;
+            }
+            if !changed.is_empty() {
+                parser_cmds
+                    .entry(ParserCmd::Update)
+                    .or_default()
+                    .extend(changed);
             }
         }
 
+        tracing::debug!("{:?}", parser_cmds);
+        self.execute_parser_cmds(parser_cmds, &db_map);
+
+        Ok(())
     }
 
-    fn is_metadata_same(&self, file1: &FileEntry, file2: &FileEntry) -> bool {
+    fn compare_metadata(&self, file1: &FileEntry, file2: &FileEntry) -> MetadataMatch {
         let t1 = file1.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
         let t2 = file2.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
 
-        file1.size == file2.size && t1 == t2
+        if file1.size != file2.size {
+            MetadataMatch::Changed
+        } else if t1 != t2 {
+            MetadataMatch::MtimeOnly
+        } else {
+            MetadataMatch::Same
+        }
+    }
+
+    fn is_content_same(&self, file: &FileEntry, indexed: &HashMap<PathBuf, FileEntry>) -> bool {
+        let old = indexed.get(&file.path).and_then(|e| e.hash.as_deref());
+        match (file.hash.as_deref(), old) {
+            (Some(new), Some(old)) => !old.is_empty() && new == old,
+            _ => false
+        }
+    }
+
+    //Fingerprints the candidates and splits them into (unchanged, changed).
+    //Unchanged files only need their mtime refreshed, changed ones go on to a full hash
+    fn split_by_fingerprint(&self, files: Vec<FileEntry>, db_map: &HashMap<PathBuf, FileEntry>) -> (Vec<FileEntry>, Vec<FileEntry>) {
+        let (tx, rx) = mpsc::channel();
+        self.tx_hasher.send(HasherCmd::Fingerprint(files, tx)).unwrap();
+        let files = rx.recv().unwrap();
+
+        files.into_iter().partition(|f| {
+            let indexed = db_map.get(&f.path).and_then(|e| e.fingerprint.as_ref());
+            f.fingerprint.is_some() && f.fingerprint.as_ref() == indexed
+        })
     }
 
-    fn execute_parser_cmds(&self, parser_cmd: HashMap<ParserCmd, Vec<FileEntry>>) {
+    fn execute_parser_cmds(&self, parser_cmd: HashMap<ParserCmd, Vec<FileEntry>>, indexed: &HashMap<PathBuf, FileEntry>) {
         let (tx, rx) = mpsc::channel::<Vec<FileEntry>>();
+        //Read before the deletes below remove the rows
+        let synced = self.synced_hashes().unwrap();
 
         let mut files_to_hash: Vec<FileEntry> = Vec::new();
-        let mut command_map: Vec<ParserCmd> = Vec::new(); // command per file, same order
+        let mut commands: HashMap<PathBuf, ParserCmd> = HashMap::new();
+        let deleted = parser_cmd.get(&ParserCmd::Delete).cloned();
 
         // Flatten input map
-        for (cmd, files) in parser_cmd.clone() {
+        for (cmd, files) in parser_cmd {
             match cmd {
                 ParserCmd::Insert | ParserCmd::Update => {
                     for file in files {
+                        commands.insert(file.path.clone(), cmd.clone());
                         files_to_hash.push(file);
-                        command_map.push(cmd.clone());
                     }
                 }
                 ParserCmd::Delete => {
-                    dbg!("DELETING FILES" , &files.len());
-                    for _ in 0..files.len() {
-                        command_map.push(cmd.clone());
-                    }
+                    tracing::debug!("deleting {} files", files.len());
                     self.execute(DbCmd::BulkDelete(files)).unwrap();
                 }
             }
@@ -399,23 +511,21 @@ impl Db{
             hashes = rx.recv().unwrap();
         }
 
-        if parser_cmd.contains_key(&ParserCmd::Delete) {
-            hashes.extend_from_slice(parser_cmd.get(&ParserCmd::Delete).unwrap());
-        }
-
         // Rebuild parser_cmd with owned hashed files
         let mut parser_cmd: HashMap<ParserCmd, Vec<FileEntry>> = HashMap::new();
 
-        //zip combines two iterators
-        //Since we have appended the commands in the order in which they are pushed
-        //into files_to_hash. Assuming the response from the hasher is in order
-        //The two iterators commands and hasher maintain order
-        for (cmd, file_with_hash) in command_map.into_iter().zip(hashes.into_iter()) {
+        //The hasher leaves out files it couldn't read, so the commands are
+        //looked up by path. Those files come up again with the next scan.
+        for file_with_hash in hashes {
+            let cmd = commands[&file_with_hash.path].clone();
             parser_cmd
                 .entry(cmd)
                 .or_default()
                 .push(file_with_hash);
         }
+        if let Some(deleted) = deleted {
+            parser_cmd.insert(ParserCmd::Delete, deleted);
+        }
         //Create File Upload payload
         //
         let mut payload: HashMap<Operations, Vec<FileEntryDTO>> = HashMap::new();
@@ -428,8 +538,19 @@ impl Db{
                     payload.insert(Operations::Insert, files.iter().map(FileEntryDTO::from).collect());
                 }
                 ParserCmd::Update => {
-                    self.execute(DbCmd::BulkUpdate(files.clone())).unwrap();
-                    payload.insert(Operations::Update, files.iter().map(FileEntryDTO::from).collect());
+                    //touch, git checkout and restores of mtimes leave the content as indexed,
+                    //those only need the new mtime recorded and nothing uploaded
+                    let (touched, changed): (Vec<FileEntry>, Vec<FileEntry>) = files
+                        .into_iter()
+                        .partition(|f| self.is_content_same(f, indexed));
+
+                    if !touched.is_empty() {
+                        self.execute(DbCmd::BulkTouch(touched)).unwrap();
+                    }
+                    if !changed.is_empty() {
+                        self.execute(DbCmd::BulkUpdate(changed.clone())).unwrap();
+                        payload.insert(Operations::Update, changed.iter().map(FileEntryDTO::from).collect());
+                    }
                 }
                 ParserCmd::Delete => {
                     payload.insert(Operations::Delete, files.iter().map(FileEntryDTO::from).collect());
@@ -437,14 +558,926 @@ impl Db{
             }
         }
 
-        if !payload.is_empty() {
+        for dto in payload.values_mut().flatten() {
+            dto.base_hash = synced.get(&dto.file_path).cloned();
+        }
+
+        self.sync(payload);
+    }
+
+    fn synced_hashes(&self) -> sqlite::Result<HashMap<String, String>> {
+        let mut stmt = self.conn.prepare("SELECT filepath, synced_hash FROM filehash WHERE synced_hash IS NOT NULL")?;
+        let mut hashes = HashMap::new();
+        while let Ok(State::Row) = stmt.next() {
+            hashes.insert(stmt.read::<String, _>(0)?, stmt.read::<String, _>(1)?);
+        }
+        Ok(hashes)
+    }
+
+    //Entries are (path, hash, version id), a missing version keeps the known one
+    fn mark_synced<'a>(&self, entries: impl Iterator<Item = (&'a str, &'a str, Option<&'a str>)>) -> sqlite::Result<()> {
+        self.conn.execute("BEGIN TRANSACTION")?;
+        let mut stmt = self.conn.prepare(
+            "UPDATE filehash SET synced_hash = ?, sync_state = 'synced', remote_version = COALESCE(?, remote_version) WHERE filepath = ?"
+        )?;
+        for (path, hash, version) in entries {
+            stmt.bind((1, hash))?;
+            stmt.bind((2, version))?;
+            stmt.bind((3, path))?;
+            stmt.next()?;
+            stmt.reset()?;
+        }
+        self.conn.execute("COMMIT")?;
+        Ok(())
+    }
+
+    fn set_sync_state<'a>(&self, entries: impl Iterator<Item = (&'a str, &'a str)>) -> sqlite::Result<()> {
+        let mut stmt = self.conn.prepare("UPDATE filehash SET sync_state = ? WHERE filepath = ?")?;
+        for (path, state) in entries {
+            stmt.bind((1, state))?;
+            stmt.bind((2, path))?;
+            stmt.next()?;
+            stmt.reset()?;
+        }
+        Ok(())
+    }
+
+    //Splits a stored batch by the per-item results. Stored items are marked
+    //synced, rejected ones flagged, and what is returned is to be queued again
+    //and to go through conflict resolution. An item the server didn't report
+    //on is retried.
+    fn apply_results(&self, batch: SyncBatch, response: SyncResponse) -> sqlite::Result<(SyncBatch, SyncBatch, Vec<RemoteConflict>)> {
+        let mut results: HashMap<String, ItemResult> = response.results
+            .into_iter()
+            .map(|item| (item.file_path.clone(), item))
+            .collect();
+
+        let mut synced: Vec<(String, String, Option<String>)> = Vec::new();
+        let mut flagged: Vec<(String, ItemStatus)> = Vec::new();
+        let mut retry: SyncBatch = HashMap::new();
+        let mut conflicted: SyncBatch = HashMap::new();
+        let mut conflicts = Vec::new();
+
+        for (op, entries) in batch {
+            for dto in entries {
+                let Some(item) = results.remove(&dto.file_path) else {
+                    tracing::warn!("server didn't report on {}, queued for retry", dto.file_path);
+                    retry.entry(op).or_default().push(dto);
+                    continue;
+                };
+
+                match item.status {
+                    ItemStatus::Ok => {
+                        if op != Operations::Delete && let Some(hash) = dto.file_hash.clone() {
+                            synced.push((dto.file_path, hash, item.version_id));
+                        }
+                    }
+                    ItemStatus::Conflict => {
+                        conflicts.push(item.to_conflict());
+                        conflicted.entry(op).or_default().push(dto);
+                    }
+                    ItemStatus::Rejected => {
+                        tracing::warn!("server rejected {}: {}", dto.file_path, item.message.as_deref().unwrap_or("no reason given"));
+                        flagged.push((dto.file_path, item.status));
+                    }
+                    ItemStatus::Mismatch => {
+                        tracing::warn!("{} didn't arrive intact ({}), queued for re-upload", dto.file_path, item.message.as_deref().unwrap_or("mismatch"));
+                        flagged.push((dto.file_path.clone(), item.status));
+                        //Under the old key the server would answer with the
+                        //same bad result, merge_pending hands out a new one
+                        retry.entry(op).or_default().push(FileEntryDTO { idempotency_key: None, ..dto });
+                    }
+                    ItemStatus::Unknown => {
+                        tracing::warn!("server answered {} with a status this version doesn't know, queued for retry", dto.file_path);
+                        flagged.push((dto.file_path.clone(), item.status));
+                        retry.entry(op).or_default().push(dto);
+                    }
+                    ItemStatus::QuotaExceeded => {
+                        tracing::warn!("server is out of space for {}, queued for retry", dto.file_path);
+                        flagged.push((dto.file_path.clone(), item.status));
+                        retry.entry(op).or_default().push(dto);
+                    }
+                }
+            }
+        }
+
+        self.mark_synced(synced.iter().map(|(p, h, v)| (p.as_str(), h.as_str(), v.as_deref())))?;
+        self.set_sync_state(flagged.iter().map(|(p, status)| (p.as_str(), status.as_str())))?;
+        Ok((retry, conflicted, conflicts))
+    }
+
+    //Applies the configured strategy to every conflicting path. Operations in
+    //the batch that didn't conflict, and local versions that won, are queued
+    //to go out with the next sync.
+    fn handle_conflicts(&self, batch: SyncBatch, conflicts: Vec<RemoteConflict>) -> sqlite::Result<()> {
+        let conflicts: HashMap<String, RemoteConflict> = conflicts
+            .into_iter()
+            .map(|c| (c.file_path.clone(), c))
+            .collect();
+
+        let mut requeue: SyncBatch = HashMap::new();
+        let mut fetch: Vec<(FileEntryDTO, Option<PathBuf>)> = Vec::new();
+        let mut kept: Vec<(Operations, FileEntryDTO)> = Vec::new();
+
+        for (op, entries) in batch {
+            for mut dto in entries {
+                let Some(conflict) = conflicts.get(&dto.file_path) else {
+                    requeue.entry(op).or_default().push(dto);
+                    continue;
+                };
+
+                let mut resolution = resolve(self.conflict_strategy, &dto, conflict);
+                //Nothing to keep a copy of when the server deleted it
+                if resolution == Resolution::KeepBoth && conflict.remote_hash.is_none() {
+                    resolution = Resolution::Local;
+                }
+                tracing::warn!("conflict on {}, resolved as {}", dto.file_path, resolution.as_str());
+                self.record_conflict(&dto, conflict, resolution)?;
+
+                match resolution {
+                    Resolution::Local => {
+                        //Resend on top of whatever the server has now
+                        let op = match (op, &conflict.remote_hash) {
+                            (Operations::Update, None) => Operations::Insert,
+                            (Operations::Delete, None) => continue,
+                            (op, _) => op
+                        };
+                        dto.base_hash = conflict.remote_hash.clone();
+                        requeue.entry(op).or_default().push(dto);
+                    }
+                    Resolution::Remote if conflict.remote_hash.is_none() => {
+                        let path = PathBuf::from(&dto.file_path);
+                        let _ = std::fs::remove_file(&path);
+                        self.execute(DbCmd::Delete(path))?;
+                    }
+                    Resolution::Remote => fetch.push((conflict.to_dto(), None)),
+                    //The local file is moved aside by the uploader once the
+                    //remote version is on disk, a failed download leaves it
+                    //where the next scan expects it and its operation queued to
+                    //run into the same conflict again
+                    Resolution::KeepBoth => {
+                        let path = PathBuf::from(&dto.file_path);
+                        let copy = conflict_copy_path(&path, &self.device_id, chrono::Local::now());
+                        fetch.push((conflict.to_dto(), Some(copy)));
+                        kept.push((op, dto));
+                    }
+                }
+            }
+        }
+
+        let mut unresolved: SyncBatch = HashMap::new();
+        if !fetch.is_empty() {
             let (tx, rx) = mpsc::channel();
-            self.tx_uploader.blocking_send(FileUploaderCmd::Sync(payload, tx)).unwrap();
+            let fetched = match self.tx_uploader.blocking_send(FileUploaderCmd::Fetch(fetch, tx)) {
+                Ok(()) => rx.recv().ok(),
+                Err(_) => None
+            };
+            let landed: HashSet<String> = fetched
+                .iter()
+                .flatten()
+                .map(|f| f.path.to_string_lossy().to_string())
+                .collect();
+            for (op, dto) in kept {
+                if !landed.contains(&dto.file_path) {
+                    unresolved.entry(op).or_default().push(dto);
+                }
+            }
+
+            if let Some(fetched) = fetched {
+                let synced: Vec<(String, String)> = fetched
+                    .iter()
+                    .filter_map(|f| Some((f.path.to_string_lossy().to_string(), f.hash.clone()?)))
+                    .collect();
+                self.execute(DbCmd::BulkInsert(fetched))?;
+                self.mark_synced(synced.iter().map(|(p, h)| (p.as_str(), h.as_str(), None)))?;
+            }
+        }
+
+        let mut payloads = Vec::new();
+        if !requeue.is_empty() {
+            payloads.push((requeue, "resending after conflict".to_string()));
+        }
+        if !unresolved.is_empty() {
+            payloads.push((unresolved, "remote version of a conflict didn't download".to_string()));
+        }
+        if !payloads.is_empty() {
+            self.requeue(0, payloads)?;
+        }
+        Ok(())
+    }
+
+    fn record_conflict(&self, local: &FileEntryDTO, remote: &RemoteConflict, resolution: Resolution) -> sqlite::Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO conflicts (filepath, local_hash, remote_hash, remote_device, resolution, detected_at) VALUES (?, ?, ?, ?, ?, ?)"
+        )?;
+        stmt.bind((1, local.file_path.as_str()))?;
+        stmt.bind((2, local.file_hash.as_deref()))?;
+        stmt.bind((3, remote.remote_hash.as_deref()))?;
+        stmt.bind((4, remote.device_id.as_deref()))?;
+        stmt.bind((5, resolution.as_str()))?;
+        stmt.bind((6, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    //The uploader downloads and renames files into place, the index is updated
+    //here with what landed on disk. Since this runs on the Db thread before any
+    //queued watcher events, the rescan those trigger finds nothing new.
+    fn pull_remote(&self) -> sqlite::Result<()> {
+        if *self.uploader_status.borrow() != UploaderStatus::Ready {
+            return Ok(());
+        }
+        let cursor = self.meta_get("remote_cursor")?;
+        let synced = self.synced_hashes()?;
+        let (tx, rx) = mpsc::channel();
+        if self.tx_uploader.blocking_send(FileUploaderCmd::Get(cursor, synced, tx)).is_err() {
+            return Ok(());
+        }
+
+        let result = match rx.recv() {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => {
+                tracing::warn!("pulling remote changes failed: {}", e);
+                return Ok(());
+            }
+            Err(_) => return Ok(())
+        };
+
+        let mut written = Vec::new();
+        let mut deleted = Vec::new();
+        for (op, entry) in result.applied {
+            match op {
+                Operations::Delete => deleted.push(entry),
+                Operations::Insert | Operations::Update => written.push(entry)
+            }
+        }
+        if !written.is_empty() {
+            let synced: Vec<(String, String)> = written
+                .iter()
+                .filter_map(|f| Some((f.path.to_string_lossy().to_string(), f.hash.clone()?)))
+                .collect();
+            self.execute(DbCmd::BulkInsert(written))?;
+            self.mark_synced(synced.iter().map(|(p, h)| (p.as_str(), h.as_str(), None)))?;
+        }
+        if !deleted.is_empty() {
+            self.execute(DbCmd::BulkDelete(deleted))?;
+        }
+
+        //Local edits the feed would have overwritten, resolved like a 409
+        if !result.conflicts.is_empty() {
+            let (locals, conflicts): (Vec<_>, Vec<_>) = result.conflicts.into_iter().unzip();
+            self.handle_conflicts(HashMap::from([(Operations::Update, locals)]), conflicts)?;
+        }
+
+        if let Some(cursor) = result.cursor {
+            self.meta_set("remote_cursor", &cursor)?;
+        }
+
+        //Also the regular chance to retry whatever is still pending
+        self.sync(HashMap::new());
+        Ok(())
+    }
+
+    fn meta_get(&self, key: &str) -> sqlite::Result<Option<String>> {
+        let mut stmt = self.conn.prepare("SELECT value FROM meta WHERE key = ?")?;
+        stmt.bind((1, key))?;
+        if let Ok(State::Row) = stmt.next() {
+            return Ok(Some(stmt.read(0)?));
+        }
+        Ok(None)
+    }
+
+    fn meta_set(&self, key: &str, value: &str) -> sqlite::Result<()> {
+        let mut stmt = self.conn.prepare("INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)")?;
+        stmt.bind((1, key))?;
+        stmt.bind((2, value))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    //Sends the payload together with whatever failed before. What goes out is
+    //written to pending_ops first and only leaves it with an answer, so a
+    //crash mid-upload loses nothing and the replay carries the same keys.
+    fn sync(&self, payload: HashMap<Operations, Vec<FileEntryDTO>>) {
+        let (payload, taken) = self.merge_pending(payload).unwrap();
+        if payload.is_empty() {
+            self.requeue(taken, Vec::new()).unwrap();
+            return;
+        }
+
+        //No point waiting on the uploader, the changes are kept coalesced
+        //in the queue until the server is back
+        let status = *self.uploader_status.borrow();
+        if status != UploaderStatus::Ready {
+            self.requeue(taken, vec![(payload, status.as_str().to_string())]).unwrap();
+            return;
+        }
+        let queued = self.requeue(taken, vec![(payload.clone(), "sending".to_string())]).unwrap();
+        //The rows just written, each batch settles its own as it is answered
+        let sent = (taken, queued);
+
+        let (tx, rx) = mpsc::channel();
+        if let Err(e) = self.tx_uploader.blocking_send(FileUploaderCmd::Sync(payload, tx)) {
+            let FileUploaderCmd::Sync(payload, _) = e.0 else { unreachable!() };
+            self.requeue(queued, vec![(payload, "uploader stopped".to_string())]).unwrap();
+            return;
+        }
+
+        //The uploader acknowledges every batch on its own, a failed batch
+        //doesn't undo the ones that went through. A batch nothing was heard
+        //of keeps its rows and goes out again with the next sync.
+        let mut conflicted_batches = Vec::new();
+        for outcome in rx {
+            match outcome {
+                SyncOutcome::Done(batch, response, unsent) => {
+                    let paths = batch_paths(&batch);
+                    let (retry, conflicted, conflicts) = self.apply_results(batch, response).unwrap();
+                    let settled: Vec<String> = paths.into_iter()
+                        .chain(batch_paths(&unsent))
+                        .filter(|path| !conflicts.iter().any(|c| &c.file_path == path))
+                        .collect();
+                    let requeue = vec![
+                        (retry, "server asked to retry".to_string()),
+                        (unsent, "content couldn't be read".to_string())
+                    ];
+                    self.settle(sent, &settled, requeue).unwrap();
+                    if !conflicts.is_empty() {
+                        conflicted_batches.push((conflicted, conflicts));
+                    }
+                }
+                SyncOutcome::Conflict(batch, conflicts) => conflicted_batches.push((batch, conflicts)),
+                SyncOutcome::Failed(batch, reason) => {
+                    tracing::warn!("sync batch failed, queued for retry: {}", reason);
+                    self.settle(sent, &batch_paths(&batch), vec![(batch, reason)]).unwrap();
+                }
+            }
+        }
+
+        //Conflicted rows stay until the conflict is handled, which requeues
+        //what still has to go out
+        for (batch, conflicts) in conflicted_batches {
+            let paths = batch_paths(&batch);
+            self.handle_conflicts(batch, conflicts).unwrap();
+            self.settle(sent, &paths, Vec::new()).unwrap();
+        }
+    }
+
+    //Swaps the pending rows up to replacing for the payloads, in one
+    //transaction, and returns the last id queued
+    fn requeue(&self, replacing: i64, payloads: Vec<(HashMap<Operations, Vec<FileEntryDTO>>, String)>) -> sqlite::Result<i64> {
+        self.conn.execute("BEGIN TRANSACTION")?;
+        let mut clear = self.conn.prepare("DELETE FROM pending_ops WHERE id <= ?")?;
+        clear.bind((1, replacing))?;
+        clear.next()?;
+        self.insert_pending(payloads)?;
+
+        let mut last = self.conn.prepare("SELECT COALESCE(MAX(id), 0) FROM pending_ops")?;
+        last.next()?;
+        let last = last.read::<i64, _>(0)?;
+        self.conn.execute("COMMIT")?;
+        Ok(last)
+    }
+
+    //Swaps the rows of these paths among the sent ones, ids after sent.0 up
+    //to sent.1, for the payloads, in one transaction
+    fn settle(&self, sent: (i64, i64), paths: &[String], payloads: Vec<(HashMap<Operations, Vec<FileEntryDTO>>, String)>) -> sqlite::Result<()> {
+        self.conn.execute("BEGIN TRANSACTION")?;
+        let mut clear = self.conn.prepare("DELETE FROM pending_ops WHERE id > ? AND id <= ? AND filepath = ?")?;
+        for path in paths {
+            clear.bind((1, sent.0))?;
+            clear.bind((2, sent.1))?;
+            clear.bind((3, path.as_str()))?;
+            clear.next()?;
+            clear.reset()?;
+        }
+        self.insert_pending(payloads)?;
+        self.conn.execute("COMMIT")?;
+        Ok(())
+    }
+
+    //Callers hold a transaction
+    fn insert_pending(&self, payloads: Vec<(HashMap<Operations, Vec<FileEntryDTO>>, String)>) -> sqlite::Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO pending_ops (operation, filepath, entry, last_error) VALUES (?, ?, ?, ?)"
+        )?;
+
+        //Paths already flagged by the server keep showing why
+        let mut state = self.conn.prepare(
+            "UPDATE filehash SET sync_state = 'pending' WHERE filepath = ? AND (sync_state IS NULL OR sync_state = 'synced')"
+        )?;
+
+        for (payload, reason) in payloads {
+            for (op, entries) in payload {
+                for dto in entries {
+                    stmt.bind((1, op.as_str()))?;
+                    stmt.bind((2, dto.file_path.as_str()))?;
+                    stmt.bind((3, serde_json::to_string(&dto).unwrap().as_str()))?;
+                    stmt.bind((4, reason.as_str()))?;
+                    stmt.next()?;
+                    stmt.reset()?;
+
+                    state.bind((1, dto.file_path.as_str()))?;
+                    state.next()?;
+                    state.reset()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Replays every pending operation in the order it was queued, followed by
+    //the payload, and returns the last id taken. Operations on the same path
+    //are coalesced into the one that takes the server straight to the latest
+    //state. Operations that don't have an idempotency key yet get one here,
+    //a coalesced operation is a new one and gets a new key.
+    fn merge_pending(&self, payload: HashMap<Operations, Vec<FileEntryDTO>>) -> sqlite::Result<(HashMap<Operations, Vec<FileEntryDTO>>, i64)> {
+        let mut order: Vec<String> = Vec::new();
+        let mut latest: HashMap<String, (Operations, Operations, FileEntryDTO)> = HashMap::new();
+        let mut push = |op: Operations, dto: FileEntryDTO| {
+            match latest.get_mut(&dto.file_path) {
+                Some((_, last, entry)) => {
+                    //The server is still at the state the first operation was based on
+                    let base_hash = entry.base_hash.take();
+                    *last = op;
+                    *entry = FileEntryDTO { base_hash, ..dto };
+                }
+                None => {
+                    order.push(dto.file_path.clone());
+                    latest.insert(dto.file_path.clone(), (op, op, dto));
+                }
+            }
+        };
+
+        let mut taken = 0;
+        let mut stmt = self.conn.prepare("SELECT id, operation, entry FROM pending_ops ORDER BY id")?;
+        while let Ok(State::Row) = stmt.next() {
+            taken = stmt.read::<i64, _>(0)?;
+            let op: String = stmt.read(1)?;
+            let entry: String = stmt.read(2)?;
+
+            if let (Some(op), Ok(dto)) = (Operations::parse(&op), serde_json::from_str::<FileEntryDTO>(&entry)) {
+                push(op, dto);
+            }
+        }
+        for op in [Operations::Insert, Operations::Update, Operations::Delete] {
+            for dto in payload.get(&op).into_iter().flatten() {
+                push(op, dto.clone());
+            }
+        }
+
+        let mut merged: HashMap<Operations, Vec<FileEntryDTO>> = HashMap::new();
+        for path in order {
+            let (first, last, dto) = latest.remove(&path).unwrap();
+            if let Some(op) = coalesce(first, last) {
+                let idempotency_key = dto.idempotency_key.clone().or_else(|| Some(format!("{:032x}", fastrand::u128(..))));
+                merged.entry(op).or_default().push(FileEntryDTO { idempotency_key, ..dto });
+            }
+        }
+
+        Ok((merged, taken))
+    }
+
+}
+
+
+
+fn batch_paths(batch: &SyncBatch) -> Vec<String> {
+    batch.values().flatten().map(|dto| dto.file_path.clone()).collect()
+}
+
+//Whether the server had the file before the first operation decides what
+//the combined operation is. An insert that never arrived followed by a
+//delete needs nothing sent at all.
+fn coalesce(first: Operations, last: Operations) -> Option<Operations> {
+    let server_had_it = first != Operations::Insert;
+    match (server_had_it, last) {
+        (true, Operations::Delete) => Some(Operations::Delete),
+        (false, Operations::Delete) => None,
+        (true, _) => Some(Operations::Update),
+        (false, _) => Some(Operations::Insert)
+    }
+}
+
+fn read_entry(stmt: &Statement) -> sqlite::Result<FileEntry> {
+    let path: String = stmt.read(0)?;
+    let hash: String = stmt.read(1)?;
+    let size: i64 = stmt.read(2)?;
+    let modified: i64 = stmt.read(3)?;
+    let filename: String = stmt.read(4)?;
+    let fingerprint: Option<String> = stmt.read(5)?;
+
+    Ok(FileEntry {
+        filename,
+        path: PathBuf::from(path),
+        hash: Some(hash),
+        size: size as u64,
+        modified: SystemTime::UNIX_EPOCH + Duration::from_secs(modified as u64),
+        fingerprint
+    })
+}
+
+//Opens the index and brings its schema up to date. Also used by commands
+//that only read the index, like duplicates.
+pub fn open_index() -> Connection {
+    let mut connection = sqlite::open(INDEX_PATH).unwrap();
+    //The uploader keeps its own connection to the same file, wait for its
+    //writes instead of failing with SQLITE_BUSY
+    connection.set_busy_timeout(BUSY_TIMEOUT_MS).unwrap();
+    create_schema(&connection);
+    connection
+}
+
+fn create_schema(connection: &Connection) {
+    let query = "
+        CREATE TABLE IF NOT EXISTS filehash (filepath TEXT, filehash TEXT, size BIGDECIMAL, modified DATETIME, filename TEXT);
+        CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT);
+        CREATE TABLE IF NOT EXISTS conflicts (id INTEGER PRIMARY KEY AUTOINCREMENT, filepath TEXT, local_hash TEXT, remote_hash TEXT, remote_device TEXT, resolution TEXT, detected_at INTEGER);
+        CREATE TABLE IF NOT EXISTS pending_ops (id INTEGER PRIMARY KEY AUTOINCREMENT, operation TEXT, filepath TEXT, entry TEXT, last_error TEXT);
+    ";
+    connection.execute(query).unwrap();
+    migrate(connection);
+}
 
-            let _ = rx.recv().unwrap();
+fn migrate(conn: &Connection) {
+    let mut columns: Vec<String> = Vec::new();
+    conn.iterate("PRAGMA table_info(filehash)", |row| {
+        for (name, value) in row {
+            if *name == "name" && let Some(value) = value {
+                columns.push(value.to_string());
+            }
+        }
+        true
+    }).unwrap();
+
+    for (column, kind) in MIGRATIONS {
+        if !columns.iter().any(|c| c == column) {
+            conn.execute(format!("ALTER TABLE filehash ADD COLUMN {} {}", column, kind)).unwrap();
         }
     }
 
+    conn.execute("CREATE INDEX IF NOT EXISTS filehash_content ON filehash (filehash, size);").unwrap();
+    unique_paths(conn);
 }
 
+//The baseline schema had no unique path, so every restart appended another
+//row per file and the older rows went stale. Upserts (ON CONFLICT(filepath))
+//need the unique index, and it can't be created while copies exist. Only
+//rows a newer row of the same path supersedes are dropped, and only once:
+//after the index exists there is nothing left to drop.
+fn unique_paths(conn: &Connection) {
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'filehash_filepath'").unwrap();
+    stmt.next().unwrap();
+    if stmt.read::<i64, _>(0).unwrap() > 0 {
+        return;
+    }
+    conn.execute("
+        BEGIN TRANSACTION;
+        DELETE FROM filehash WHERE rowid NOT IN (SELECT MAX(rowid) FROM filehash GROUP BY filepath);
+        CREATE UNIQUE INDEX filehash_filepath ON filehash (filepath);
+        COMMIT;
+    ").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use crate::config::settings::HasherConfig;
+    use crate::file_hasher::hasher::Hasher;
+    use crate::progress::events::{Progress, ProgressEvent};
+
+    use super::*;
+
+    const FINGERPRINT_MIN_SIZE: u64 = 1024 * 1024;
+
+    //An index in memory, with a hasher thread and an uploader nobody answers
+    fn index(status: UploaderStatus) -> (Db, tokio::sync::mpsc::Receiver<FileUploaderCmd>) {
+        index_with(status, Progress::new())
+    }
+
+    //Files of FINGERPRINT_MIN_SIZE and up get a fingerprint
+    fn index_with(status: UploaderStatus, progress: Progress) -> (Db, tokio::sync::mpsc::Receiver<FileUploaderCmd>) {
+        let hasher = Hasher::new(progress, HasherConfig { fingerprint_min_size: FINGERPRINT_MIN_SIZE, ..HasherConfig::default() });
+        let tx_hasher = hasher.get_sender();
+        std::thread::spawn(move || hasher.run());
+
+        let conn = sqlite::open(":memory:").unwrap();
+        create_schema(&conn);
+        let (tx, rx) = channel();
+        let (tx_uploader, rx_uploader) = tokio::sync::mpsc::channel(8);
+        let (_, uploader_status) = watch::channel(status);
+        let db = Db {
+            conn,
+            tx,
+            rx,
+            tx_hasher,
+            tx_uploader,
+            conflict_strategy: ConflictStrategy::default(),
+            device_id: "laptop".to_string(),
+            uploader_status
+        };
+        (db, rx_uploader)
+    }
+
+    fn column(db: &Db, path: &Path, column: &str) -> Option<String> {
+        let mut stmt = db.conn.prepare(format!("SELECT {} FROM filehash WHERE filepath = ?", column)).unwrap();
+        stmt.bind((1, path.to_str().unwrap())).unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        stmt.read(0).unwrap()
+    }
+
+    fn dto(path: &str, hash: &str) -> FileEntryDTO {
+        FileEntryDTO {
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            file_path: path.to_string(),
+            file_hash: Some(hash.to_string()),
+            file_size: 1,
+            modified_time: 1,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        }
+    }
+
+    #[test]
+    fn a_restart_keeps_what_the_server_confirmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, b"content").unwrap();
+        let (db, _uploader) = index(UploaderStatus::Offline);
+
+        db.initialise(dir.path().to_str().unwrap());
+        let hash = column(&db, &path, "filehash").unwrap();
+        db.mark_synced([(path.to_str().unwrap(), hash.as_str(), Some("v1"))].into_iter()).unwrap();
+
+        db.initialise(dir.path().to_str().unwrap());
+        assert_eq!(column(&db, &path, "synced_hash"), Some(hash));
+        assert_eq!(column(&db, &path, "remote_version").as_deref(), Some("v1"));
+        assert_eq!(column(&db, &path, "sync_state").as_deref(), Some("synced"));
+    }
+
+    #[test]
+    fn coalesces_by_what_the_server_had_first() {
+        use Operations::*;
+        let table = [
+            (Insert, Insert, Some(Insert)),
+            (Insert, Update, Some(Insert)),
+            (Insert, Delete, None),
+            (Update, Insert, Some(Update)),
+            (Update, Update, Some(Update)),
+            (Update, Delete, Some(Delete)),
+            (Delete, Insert, Some(Update)),
+            (Delete, Update, Some(Update)),
+            (Delete, Delete, Some(Delete))
+        ];
+        for (first, last, merged) in table {
+            assert_eq!(coalesce(first, last), merged, "{:?} then {:?}", first, last);
+        }
+    }
+
+    #[test]
+    fn merges_queued_operations_with_the_payload() {
+        let (db, _uploader) = index(UploaderStatus::Offline);
+        let with = |dto: FileEntryDTO, base: Option<&str>, key: Option<&str>| FileEntryDTO {
+            base_hash: base.map(str::to_string),
+            idempotency_key: key.map(str::to_string),
+            ..dto
+        };
+        db.requeue(0, vec![(HashMap::from([
+            (Operations::Insert, vec![with(dto("/created-then-deleted", "1"), None, Some("k1")), with(dto("/created-then-modified", "1"), None, Some("k2"))]),
+            (Operations::Delete, vec![with(dto("/deleted-then-created", "1"), Some("old"), Some("k3"))]),
+            (Operations::Update, vec![with(dto("/modified", "2"), Some("1"), Some("k4"))])
+        ]), "offline".to_string())]).unwrap();
+
+        let payload = HashMap::from([
+            (Operations::Insert, vec![dto("/deleted-then-created", "2"), dto("/new", "1")]),
+            (Operations::Update, vec![with(dto("/created-then-modified", "2"), Some("1"), None)]),
+            (Operations::Delete, vec![dto("/created-then-deleted", "1")])
+        ]);
+        let (merged, taken) = db.merge_pending(payload).unwrap();
+        assert_eq!(taken, 4);
+
+        let find = |op: Operations, path: &str| merged.get(&op).into_iter().flatten().find(|d| d.file_path == path).cloned();
+        assert_eq!(merged.values().flatten().count(), 4);
+        assert!(merged.values().flatten().all(|d| d.file_path != "/created-then-deleted"));
+
+        //The server never had it, nothing to base the insert on
+        let created = find(Operations::Insert, "/created-then-modified").unwrap();
+        assert_eq!((created.file_hash.as_deref(), created.base_hash.as_deref()), (Some("2"), None));
+        assert!(created.idempotency_key.is_some_and(|k| k != "k2"));
+
+        //Based on what the server had before the delete
+        let recreated = find(Operations::Update, "/deleted-then-created").unwrap();
+        assert_eq!((recreated.file_hash.as_deref(), recreated.base_hash.as_deref()), (Some("2"), Some("old")));
+
+        let modified = find(Operations::Update, "/modified").unwrap();
+        assert_eq!(modified.idempotency_key.as_deref(), Some("k4"));
+        assert!(find(Operations::Insert, "/new").unwrap().idempotency_key.is_some());
+    }
+
+    //Queued operations as (operation, path, reason, entry)
+    fn pending(db: &Db) -> Vec<(String, String, String, FileEntryDTO)> {
+        let mut stmt = db.conn.prepare("SELECT operation, filepath, last_error, entry FROM pending_ops ORDER BY id").unwrap();
+        let mut rows = Vec::new();
+        while let Ok(State::Row) = stmt.next() {
+            let entry: String = stmt.read(3).unwrap();
+            rows.push((stmt.read(0).unwrap(), stmt.read(1).unwrap(), stmt.read(2).unwrap(), serde_json::from_str(&entry).unwrap()));
+        }
+        rows
+    }
+
+    fn resolutions(db: &Db) -> Vec<String> {
+        let mut stmt = db.conn.prepare("SELECT resolution FROM conflicts ORDER BY id").unwrap();
+        let mut rows = Vec::new();
+        while let Ok(State::Row) = stmt.next() {
+            rows.push(stmt.read(0).unwrap());
+        }
+        rows
+    }
+
+    fn conflict(path: &str, remote_hash: Option<&str>) -> RemoteConflict {
+        RemoteConflict {
+            file_path: path.to_string(),
+            remote_hash: remote_hash.map(str::to_string),
+            remote_size: Some(1),
+            remote_modified_time: Some(1),
+            device_id: Some("phone".to_string())
+        }
+    }
+
+    #[test]
+    fn local_wins_resends_on_top_of_the_remote_version() {
+        let (mut db, _uploader) = index(UploaderStatus::Offline);
+        db.conflict_strategy = ConflictStrategy::LocalWins;
+        let batch = HashMap::from([(Operations::Update, vec![dto("/a.txt", "local"), dto("/b.txt", "untouched")])]);
+
+        db.handle_conflicts(batch, vec![conflict("/a.txt", Some("remote"))]).unwrap();
+        let mut queued: Vec<_> = pending(&db).into_iter().map(|(op, path, _, entry)| (op, path, entry.base_hash)).collect();
+        queued.sort();
+        assert_eq!(queued, [
+            ("update".to_string(), "/a.txt".to_string(), Some("remote".to_string())),
+            ("update".to_string(), "/b.txt".to_string(), None)
+        ]);
+        assert_eq!(resolutions(&db), ["local"]);
+    }
+
+    #[test]
+    fn remote_wins_over_a_local_edit_of_a_remotely_deleted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, b"local").unwrap();
+        let (mut db, _uploader) = index(UploaderStatus::Offline);
+        db.conflict_strategy = ConflictStrategy::RemoteWins;
+        db.initialise(dir.path().to_str().unwrap());
+
+        let local = path.to_str().unwrap();
+        db.handle_conflicts(HashMap::from([(Operations::Update, vec![dto(local, "local")])]), vec![conflict(local, None)]).unwrap();
+        assert!(!path.exists());
+        assert!(pending(&db).is_empty());
+        assert_eq!(resolutions(&db), ["remote"]);
+    }
+
+    #[test]
+    fn keep_both_fetches_the_remote_version_next_to_a_copy() {
+        let (db, mut uploader) = index(UploaderStatus::Ready);
+        let answering = std::thread::spawn(move || {
+            let Some(FileUploaderCmd::Fetch(files, sender)) = uploader.blocking_recv() else { panic!("expected a fetch") };
+            let (remote, copy) = files.into_iter().next().unwrap();
+            let copy = copy.unwrap().to_string_lossy().to_string();
+            sender.send(vec![FileEntry {
+                filename: remote.file_name,
+                path: PathBuf::from(&remote.file_path),
+                hash: remote.file_hash,
+                size: 1,
+                modified: SystemTime::UNIX_EPOCH,
+                fingerprint: None
+            }]).unwrap();
+            //Unanswered, the next conflict's download fails
+            let Some(FileUploaderCmd::Fetch(..)) = uploader.blocking_recv() else { panic!("expected a fetch") };
+            copy
+        });
+
+        db.handle_conflicts(HashMap::from([(Operations::Update, vec![dto("/home/a.txt", "local")])]), vec![conflict("/home/a.txt", Some("remote"))]).unwrap();
+        assert!(pending(&db).is_empty());
+        assert_eq!(column(&db, Path::new("/home/a.txt"), "synced_hash").as_deref(), Some("remote"));
+
+        //Left where it is and queued, to run into the conflict again
+        db.handle_conflicts(HashMap::from([(Operations::Update, vec![dto("/home/b.txt", "local")])]), vec![conflict("/home/b.txt", Some("remote"))]).unwrap();
+        let queued: Vec<_> = pending(&db).into_iter().map(|(op, path, reason, _)| (op, path, reason)).collect();
+        assert_eq!(queued, [("update".to_string(), "/home/b.txt".to_string(), "remote version of a conflict didn't download".to_string())]);
+
+        assert!(answering.join().unwrap().starts_with("/home/a (conflict from laptop "));
+        assert_eq!(resolutions(&db), ["keep_both", "keep_both"]);
+    }
 
+    //Overwrites part of the file in place, keeping its size, and gives it a new mtime
+    fn edit(path: &Path, offset: u64, content: &[u8], modified: SystemTime) {
+        let mut file = File::options().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(content).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    fn later() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(3600)
+    }
+
+    fn hashed(progress: &mut tokio::sync::broadcast::Receiver<ProgressEvent>) -> Vec<PathBuf> {
+        std::iter::from_fn(|| progress.try_recv().ok())
+            .filter_map(|event| match event {
+                ProgressEvent::FileHashed { path, .. } => Some(path),
+                _ => None
+            })
+            .collect()
+    }
+
+    fn large_file(dir: &Path) -> PathBuf {
+        let path = dir.join("video.mp4");
+        std::fs::write(&path, vec![7u8; 2 * FINGERPRINT_MIN_SIZE as usize]).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_touched_large_file_is_neither_hashed_nor_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = large_file(dir.path());
+        let progress = Progress::new();
+        let (db, _uploader) = index_with(UploaderStatus::Offline, progress.clone());
+        db.initialise(dir.path().to_str().unwrap());
+        let hash = column(&db, &path, "filehash");
+        assert!(column(&db, &path, "fingerprint").is_some());
+
+        let mut events = progress.subscribe();
+        let touched = later();
+        File::options().write(true).open(&path).unwrap().set_modified(touched).unwrap();
+        db.rescan(dir.path().to_str().unwrap()).unwrap();
+
+        assert!(hashed(&mut events).is_empty());
+        assert!(pending(&db).is_empty());
+        assert_eq!(column(&db, &path, "filehash"), hash);
+        let modified: i64 = column(&db, &path, "modified").unwrap().parse().unwrap();
+        assert_eq!(modified, touched.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64);
+    }
+
+    #[test]
+    fn a_large_file_whose_fingerprint_changed_is_hashed_and_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = large_file(dir.path());
+        let progress = Progress::new();
+        let (db, _uploader) = index_with(UploaderStatus::Offline, progress.clone());
+        db.initialise(dir.path().to_str().unwrap());
+        let hash = column(&db, &path, "filehash");
+
+        let mut events = progress.subscribe();
+        //Same size, the first block is always part of the fingerprint
+        edit(&path, 0, b"changed", later());
+        db.rescan(dir.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(hashed(&mut events), vec![path.clone()]);
+        assert_ne!(column(&db, &path, "filehash"), hash);
+        let queued: Vec<_> = pending(&db).into_iter().map(|(op, p, _, _)| (op, p)).collect();
+        assert_eq!(queued, [("update".to_string(), path.to_string_lossy().to_string())]);
+    }
+
+    #[test]
+    fn a_touched_file_is_recorded_without_an_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, b"same").unwrap();
+        let (db, _uploader) = index(UploaderStatus::Offline);
+        db.initialise(dir.path().to_str().unwrap());
+        let hash = column(&db, &path, "filehash");
+
+        let touched = later();
+        edit(&path, 0, b"same", touched);
+        db.rescan(dir.path().to_str().unwrap()).unwrap();
+
+        assert!(pending(&db).is_empty());
+        assert_eq!(column(&db, &path, "filehash"), hash);
+        let modified: i64 = column(&db, &path, "modified").unwrap().parse().unwrap();
+        assert_eq!(modified, touched.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64);
+    }
+
+    #[test]
+    fn an_edit_of_the_same_size_is_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, b"same").unwrap();
+        let (db, _uploader) = index(UploaderStatus::Offline);
+        db.initialise(dir.path().to_str().unwrap());
+        let hash = column(&db, &path, "filehash");
+
+        edit(&path, 0, b"diff", later());
+        db.rescan(dir.path().to_str().unwrap()).unwrap();
+
+        assert_ne!(column(&db, &path, "filehash"), hash);
+        let queued: Vec<_> = pending(&db).into_iter().map(|(op, p, _, entry)| (op, p, entry.file_hash)).collect();
+        assert_eq!(queued, [("update".to_string(), path.to_string_lossy().to_string(), column(&db, &path, "filehash"))]);
+    }
+}
@@ -1 +1,3 @@
 pub mod db;
+pub mod conflicts;
+pub mod status;
@@ -0,0 +1,54 @@
+use sqlite::State;
+
+use crate::db_listener::db::open_index;
+
+//pocket-drive status: what is indexed, what is waiting to go up and which
+//conflicts were resolved recently
+pub fn run() {
+    let conn = open_index();
+
+    let count = |sql: &str| -> i64 {
+        let mut stmt = conn.prepare(sql).unwrap();
+        match stmt.next() {
+            Ok(State::Row) => stmt.read(0).unwrap(),
+            _ => 0
+        }
+    };
+
+    println!("indexed files:     {}", count("SELECT COUNT(*) FROM filehash"));
+    println!("never synced:      {}", count("SELECT COUNT(*) FROM filehash WHERE synced_hash IS NULL"));
+    println!("pending operations: {}", count("SELECT COUNT(*) FROM pending_ops"));
+
+    let mut stmt = conn.prepare(
+        "SELECT filepath, sync_state FROM filehash WHERE sync_state IN ('rejected', 'quota_exceeded', 'mismatch') ORDER BY filepath"
+    ).unwrap();
+    let mut header = false;
+    while let Ok(State::Row) = stmt.next() {
+        if !header {
+            println!("needs attention:");
+            header = true;
+        }
+        let path: String = stmt.read(0).unwrap();
+        let state: String = stmt.read(1).unwrap();
+        println!("    {}  {}", state, path);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT filepath, remote_device, resolution, detected_at FROM conflicts ORDER BY id DESC LIMIT 20"
+    ).unwrap();
+    header = false;
+    while let Ok(State::Row) = stmt.next() {
+        if !header {
+            println!("recent conflicts:");
+            header = true;
+        }
+        let path: String = stmt.read(0).unwrap();
+        let device: Option<String> = stmt.read(1).unwrap();
+        let resolution: String = stmt.read(2).unwrap();
+        let detected_at: i64 = stmt.read(3).unwrap();
+        let when = chrono::DateTime::from_timestamp(detected_at, 0)
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!("    {}  {}  from {}  resolved as {}", when, path, device.unwrap_or_else(|| "?".to_string()), resolution);
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use crate::{db_listener::db::open_index, duplicates::report::{DuplicateAction, apply, find_duplicates, to_csv, to_json, total_reclaimable}};
+
+pub const USAGE: &str = "usage: pocket-drive duplicates [--csv | --json] [--hardlink | --delete] [--dry-run] [root...]";
+
+#[derive(PartialEq, Eq)]
+enum Format {
+    Text,
+    Csv,
+    Json
+}
+
+//pocket-drive duplicates [--csv | --json] [--hardlink | --delete] [--dry-run] [root...]
+pub fn run(args: &[String]) {
+    let mut format = Format::Text;
+    let mut action = None;
+    let mut dry_run = false;
+    let mut roots: Vec<PathBuf> = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--csv" => format = Format::Csv,
+            "--json" => format = Format::Json,
+            "--hardlink" => action = Some(DuplicateAction::Hardlink),
+            "--delete" => action = Some(DuplicateAction::DeleteExtras),
+            "--dry-run" => dry_run = true,
+            flag if flag.starts_with("--") => {
+                eprintln!("unknown flag {}\n{}", flag, USAGE);
+                return;
+            }
+            root => roots.push(PathBuf::from(root))
+        }
+    }
+
+    let conn = open_index();
+    let groups = find_duplicates(&conn, &roots).unwrap();
+
+    match format {
+        Format::Csv => print!("{}", to_csv(&groups)),
+        Format::Json => println!("{}", to_json(&groups)),
+        Format::Text => {
+            for group in &groups {
+                println!("{} ({} bytes each)", group.hash, group.size);
+                for path in &group.paths {
+                    println!("    {}", path.display());
+                }
+            }
+            println!("{} groups, {} bytes reclaimable", groups.len(), total_reclaimable(&groups));
+        }
+    }
+
+    if let Some(action) = action {
+        for record in apply(&groups, action, dry_run) {
+            let verb = match (action, dry_run) {
+                (DuplicateAction::Hardlink, true) => "would link",
+                (DuplicateAction::Hardlink, false) => "linked",
+                (DuplicateAction::DeleteExtras, true) => "would delete",
+                (DuplicateAction::DeleteExtras, false) => "deleted"
+            };
+            match record.error {
+                Some(e) => eprintln!("skipped {}: {}", record.target.display(), e),
+                None => eprintln!("{} {} (keeping {})", verb, record.target.display(), record.keep.display())
+            }
+        }
+    }
+}
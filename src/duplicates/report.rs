@@ -0,0 +1,225 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs, io, path::{Path, PathBuf}};
+
+use serde::Serialize;
+use sqlite::{Connection, State};
+use walkdir::WalkDir;
+
+use crate::{file_hasher::hasher::hash_file, throttle::bucket::TokenBucket};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub size: u64,
+    //Sorted, the first path is the one actions keep
+    pub paths: Vec<PathBuf>,
+    //Distinct files on disk, paths already hardlinked to each other count once
+    pub copies: usize
+}
+
+impl DuplicateGroup {
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.copies.max(1) as u64 - 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateAction {
+    //Replace every extra copy with a hardlink to the kept path
+    Hardlink,
+    DeleteExtras
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActionRecord {
+    pub keep: PathBuf,
+    pub target: PathBuf,
+    pub applied: bool,
+    pub error: Option<String>
+}
+
+pub fn total_reclaimable(groups: &[DuplicateGroup]) -> u64 {
+    groups.iter().map(DuplicateGroup::reclaimable).sum()
+}
+
+//Groups files by identical hash and size. Roots that have entries in the index
+//are answered from it, any other root is walked and only files whose size
+//collides with another file get hashed. With no roots the whole index is used.
+pub fn find_duplicates(conn: &Connection, roots: &[PathBuf]) -> sqlite::Result<Vec<DuplicateGroup>> {
+    let mut stmt = conn.prepare("SELECT filepath, filehash, size FROM filehash WHERE filehash != ''")?;
+    let mut indexed: Vec<(PathBuf, Option<String>, u64)> = Vec::new();
+
+    while let Ok(State::Row) = stmt.next() {
+        let path: String = stmt.read(0)?;
+        let hash: String = stmt.read(1)?;
+        let size: i64 = stmt.read(2)?;
+        indexed.push((PathBuf::from(path), Some(hash), size as u64));
+    }
+
+    if roots.is_empty() {
+        return Ok(group(indexed));
+    }
+
+    let mut candidates = Vec::new();
+    for root in roots {
+        let before = candidates.len();
+        candidates.extend(indexed.iter().filter(|(p, _, _)| p.starts_with(root)).cloned());
+
+        if candidates.len() == before {
+            candidates.extend(walk_root(root));
+        }
+    }
+
+    Ok(group(candidates))
+}
+
+fn walk_root(root: &Path) -> Vec<(PathBuf, Option<String>, u64)> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            metadata.is_file().then(|| (entry.into_path(), None, metadata.len()))
+        })
+        .collect()
+}
+
+fn group(files: Vec<(PathBuf, Option<String>, u64)>) -> Vec<DuplicateGroup> {
+    let mut by_size: HashMap<u64, Vec<(PathBuf, Option<String>)>> = HashMap::new();
+    for (path, hash, size) in files {
+        by_size.entry(size).or_default().push((path, hash));
+    }
+
+    let unlimited = TokenBucket::new(None);
+    let mut by_content: BTreeMap<(u64, String), Vec<PathBuf>> = BTreeMap::new();
+
+    for (size, mut files) in by_size {
+        if size == 0 || files.len() < 2 {
+            continue;
+        }
+        files.sort();
+        files.dedup_by(|a, b| a.0 == b.0);
+
+        for (path, hash) in files {
+            let hash = match hash {
+                Some(hash) => hash,
+                None => match hash_file(&path, &unlimited) {
+                    Ok(hash) => hash,
+                    Err(_) => continue
+                }
+            };
+            by_content.entry((size, hash)).or_default().push(path);
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = by_content
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((size, hash), mut paths)| {
+            paths.sort();
+            let copies = distinct_files(&paths);
+            DuplicateGroup { hash, size, paths, copies }
+        })
+        .collect();
+
+    groups.sort_by_key(|g| std::cmp::Reverse(g.reclaimable()));
+    groups
+}
+
+//Paths are told apart by (device, inode). One that can't be read counts as
+//a file of its own
+fn distinct_files(paths: &[PathBuf]) -> usize {
+    let mut seen = HashSet::new();
+    for path in paths {
+        match file_id(path) {
+            Some(id) => seen.insert(Ok(id)),
+            None => seen.insert(Err(path))
+        };
+    }
+    seen.len()
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+//Applies the action to every extra copy. A dry run only reports what would
+//happen. Before touching anything both files are hashed again, so a stale
+//index can never cause a file with different content to be removed.
+pub fn apply(groups: &[DuplicateGroup], action: DuplicateAction, dry_run: bool) -> Vec<ActionRecord> {
+    let unlimited = TokenBucket::new(None);
+    let mut records = Vec::new();
+
+    for group in groups {
+        let keep = &group.paths[0];
+        for target in &group.paths[1..] {
+            let mut record = ActionRecord {
+                keep: keep.clone(),
+                target: target.clone(),
+                applied: false,
+                error: None
+            };
+
+            if !dry_run {
+                let verified = hash_file(keep, &unlimited).ok().as_ref() == Some(&group.hash)
+                    && hash_file(target, &unlimited).ok().as_ref() == Some(&group.hash);
+
+                let result = if !verified {
+                    Err(io::Error::other("content changed since it was indexed"))
+                } else {
+                    match action {
+                        DuplicateAction::Hardlink => replace_with_hardlink(keep, target),
+                        DuplicateAction::DeleteExtras => fs::remove_file(target)
+                    }
+                };
+
+                match result {
+                    Ok(()) => record.applied = true,
+                    Err(e) => record.error = Some(e.to_string())
+                }
+            }
+
+            records.push(record);
+        }
+    }
+
+    records
+}
+
+//Links next to the target first and renames over it, so the target is never missing
+fn replace_with_hardlink(keep: &Path, target: &Path) -> io::Result<()> {
+    let mut tmp = target.as_os_str().to_owned();
+    tmp.push(".pocket-drive-link");
+    let tmp = PathBuf::from(tmp);
+
+    fs::hard_link(keep, &tmp)?;
+    if let Err(e) = fs::rename(&tmp, target) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub fn to_csv(groups: &[DuplicateGroup]) -> String {
+    let mut out = String::from("hash,size,path\n");
+    for group in groups {
+        for path in &group.paths {
+            let path = path.to_string_lossy().replace('"', "\"\"");
+            out.push_str(&format!("{},{},\"{}\"\n", group.hash, group.size, path));
+        }
+    }
+    out
+}
+
+pub fn to_json(groups: &[DuplicateGroup]) -> String {
+    serde_json::to_string_pretty(&serde_json::json!({
+        "groups": groups,
+        "reclaimable_bytes": total_reclaimable(groups)
+    })).unwrap()
+}
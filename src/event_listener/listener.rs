@@ -1,21 +1,23 @@
 use notify_debouncer_full::DebouncedEvent;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
-use crate::db_listener::db::DbCmd;
+use crate::{db_listener::db::DbCmd, event_listener::self_writes::SelfWrites};
 
 pub struct EventListener {
     db_tx: std::sync::mpsc::Sender<DbCmd>,
     receiver: Receiver<Vec<DebouncedEvent>>,
-    sender: Sender<Vec<DebouncedEvent>>
+    sender: Sender<Vec<DebouncedEvent>>,
+    self_writes: SelfWrites
 }
 
 impl EventListener {
-    pub fn new(db_tx : std::sync::mpsc::Sender<DbCmd>) -> Self {
+    pub fn new(db_tx : std::sync::mpsc::Sender<DbCmd>, self_writes: SelfWrites) -> Self {
         let (tx_parser, rx_parser) = mpsc::channel(1024);
         Self {
             db_tx,
             receiver: rx_parser,
-            sender: tx_parser
+            sender: tx_parser,
+            self_writes
         }
     }
 
@@ -25,6 +27,14 @@ impl EventListener {
 
     pub async fn run(mut self) {
         while let Some(batch) = self.receiver.recv().await {
+            //Drop the events our own downloads caused
+            let batch: Vec<DebouncedEvent> = batch
+                .into_iter()
+                .filter(|e| !self.self_writes.is_self_inflicted(&e.paths))
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
             let _ = self.db_tx.send(DbCmd::ProcessEvents(batch));
         }
     }
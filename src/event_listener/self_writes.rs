@@ -0,0 +1,37 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+//Long enough to cover the watcher's debounce window
+const SELF_WRITE_WINDOW: Duration = Duration::from_secs(15);
+
+//Paths pocket-drive itself just wrote, e.g. downloads being renamed into
+//place. Watcher events that only touch these are ours and are not synced back.
+#[derive(Clone, Default, Debug)]
+pub struct SelfWrites {
+    paths: Arc<Mutex<HashMap<PathBuf, Instant>>>
+}
+
+impl SelfWrites {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, path: &Path) {
+        let now = Instant::now();
+        let mut paths = self.paths.lock().unwrap();
+        paths.retain(|_, until| *until > now);
+        paths.insert(normalise(path), now + SELF_WRITE_WINDOW);
+    }
+
+    pub fn is_self_inflicted(&self, event_paths: &[PathBuf]) -> bool {
+        let now = Instant::now();
+        let paths = self.paths.lock().unwrap();
+        !event_paths.is_empty() && event_paths.iter().all(|p| {
+            paths.get(&normalise(p)).is_some_and(|until| *until > now)
+        })
+    }
+}
+
+//The watcher reports absolute paths while the index may hold relative ones
+fn normalise(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -1,31 +1,63 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder, prelude::*};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::sync::mpsc::{self, Sender, Receiver};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::path::PathBuf;
+use std::path::Path;
 use blake2::{Blake2s256, Digest};
-use file_hashing::get_hash_file;
 
+use crate::config::settings::HasherConfig;
 use crate::db_listener::db::FileEntry;
+use crate::file_hasher::priority::lower_current_thread_priority;
+use crate::progress::events::{BatchKind, Progress, ProgressEvent};
+use crate::throttle::bucket::TokenBucket;
+
+const READ_CHUNK: usize = 64 * 1024;
+const FINGERPRINT_BLOCK: u64 = 64 * 1024;
+const FINGERPRINT_SAMPLES: u64 = 8;
 
 
 pub enum HasherCmd {
-    Generate(Vec<FileEntry>, Sender<Vec<FileEntry>>)
+    Generate(Vec<FileEntry>, Sender<Vec<FileEntry>>),
+    //Only fills in FileEntry.fingerprint, leaving the hash untouched
+    Fingerprint(Vec<FileEntry>, Sender<Vec<FileEntry>>)
 }
 
 pub struct Hasher{
     tx_hasher: Sender<HasherCmd>,
-    rx_hasher: Receiver<HasherCmd>
+    rx_hasher: Receiver<HasherCmd>,
+    progress: Progress,
+    pool: ThreadPool,
+    throttle: Arc<TokenBucket>,
+    fingerprint_min_size: u64
 }
 
 impl Hasher {
     
-    pub fn new() -> Self {
+    pub fn new(progress: Progress, config: HasherConfig) -> Self {
         let (tx, rx) = mpsc::channel();
+
+        //Own pool instead of the global one so the scan can't take every core
+        let low_priority = config.low_priority;
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .thread_name(|i| format!("hasher-{}", i))
+            .start_handler(move |_| {
+                if low_priority {
+                    lower_current_thread_priority();
+                }
+            })
+            .build()
+            .unwrap();
+
         Self {
             tx_hasher: tx,
-            rx_hasher: rx
+            rx_hasher: rx,
+            progress,
+            pool,
+            throttle: Arc::new(TokenBucket::from_mbps(config.max_read_mbps)),
+            fingerprint_min_size: config.fingerprint_min_size
         }
     }
 
@@ -45,6 +77,18 @@ impl Hasher {
                 let hashed_files = self.generate_hash_in_bulk(files);
                 sender.send(hashed_files).unwrap();
             }
+            HasherCmd::Fingerprint(files, sender) => {
+                let throttle = self.throttle.clone();
+                let min_size = self.fingerprint_min_size;
+                let files = self.pool.install(|| files
+                    .into_par_iter()
+                    .map(|mut f| {
+                        f.fingerprint = fingerprint_if_large(&f.path, f.size, min_size, &throttle);
+                        f
+                    })
+                    .collect());
+                sender.send(files).unwrap();
+            }
         }
 
     }
@@ -53,47 +97,136 @@ impl Hasher {
     fn generate_hash_in_bulk(&self, paths: Vec<FileEntry>) -> Vec<FileEntry>{
         let total = paths.len() as u64;
 
-        let pb = ProgressBar::new(total);
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
-            )
-            .unwrap(),
-        );
+        self.progress.emit(ProgressEvent::ScanStarted { total_files: total });
 
+        let progress = self.progress.clone();
+        let throttle = self.throttle.clone();
+        let min_size = self.fingerprint_min_size;
         let counter = Arc::new(AtomicUsize::new(0));
-        let results: Vec<FileEntry> = paths
+        //A file deleted or locked since it was listed is left out, the next
+        //scan finds it again or finds it gone
+        let results: Vec<FileEntry> = self.pool.install(|| paths
             .into_par_iter()
-            .map(|mut p| {
-                let mut hash = Blake2s256::new();
-                let res = get_hash_file(&p.path, &mut hash).unwrap();
+            .filter_map(|mut p| {
+                let res = match hash_file(&p.path, &throttle) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        tracing::warn!("skipping {}: {}", p.path.display(), e);
+                        return None;
+                    }
+                };
 
                 let done = counter.fetch_add(1, Ordering::Relaxed) + 1;
-                pb.set_position(done as u64);
+                progress.emit(ProgressEvent::FileHashed {
+                    path: p.path.clone(),
+                    done: done as u64,
+                    total
+                });
                 p.hash = Some(res);
-                p
+                p.fingerprint = fingerprint_if_large(&p.path, p.size, min_size, &throttle);
+                Some(p)
 
             })
-            .collect();
+            .collect());
 
-        pb.finish_with_message("done");
+        self.progress.emit(ProgressEvent::BatchDone {
+            kind: BatchKind::Hash,
+            files: results.len() as u64,
+            success: true
+        });
         results
     }
 
-    fn generate_hash(&self, path: &str) -> String {
-        let path = PathBuf::from(path);
-        let mut hash = Blake2s256::new();
-        get_hash_file(&path, &mut hash).unwrap()
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self::new(Progress::new(), HasherConfig::default())
     }
+}
 
-    fn hash_equal(&self, hash1: &str, hash2: &str) -> bool{
-        hash1.eq(hash2)
+//Same digest and hex encoding as file_hashing::get_hash_file, but every read
+//goes through the shared bandwidth cap
+pub fn hash_file(path: &Path, throttle: &TokenBucket) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hash = Blake2s256::new();
+    let mut buf = vec![0u8; READ_CHUNK];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        throttle.acquire_blocking(n as u64);
+        hash.update(&buf[..n]);
     }
 
+    Ok(to_hex(&hash.finalize()))
 }
 
-impl Default for Hasher {
-    fn default() -> Self {
-        Self::new()
+//Hashes the size, the first and last block and a few evenly spaced blocks in
+//between. Reads a fixed amount no matter how big the file is.
+pub fn fingerprint_file(path: &Path, size: u64, throttle: &TokenBucket) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hash = Blake2s256::new();
+    hash.update(size.to_le_bytes());
+
+    let last_block = size.saturating_sub(FINGERPRINT_BLOCK);
+    let mut offsets = vec![0, last_block];
+    for i in 1..=FINGERPRINT_SAMPLES {
+        offsets.push(last_block / (FINGERPRINT_SAMPLES + 1) * i);
+    }
+
+    let mut buf = vec![0u8; FINGERPRINT_BLOCK as usize];
+    for offset in offsets {
+        file.seek(SeekFrom::Start(offset))?;
+        let n = file.read(&mut buf)?;
+        throttle.acquire_blocking(n as u64);
+        hash.update(offset.to_le_bytes());
+        hash.update(&buf[..n]);
+    }
+
+    Ok(to_hex(&hash.finalize()))
+}
+
+fn fingerprint_if_large(path: &Path, size: u64, min_size: u64, throttle: &TokenBucket) -> Option<String> {
+    if size < min_size {
+        return None;
+    }
+    fingerprint_file(path, size, throttle).ok()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn entry(path: &Path) -> FileEntry {
+        FileEntry {
+            filename: path.file_name().unwrap().to_string_lossy().to_string(),
+            path: path.to_path_buf(),
+            hash: None,
+            size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+            fingerprint: None
+        }
+    }
+
+    #[test]
+    fn a_file_gone_before_hashing_is_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept.txt");
+        std::fs::write(&kept, b"content").unwrap();
+
+        let hasher = Hasher::default();
+        let hashed = hasher.generate_hash_in_bulk(vec![entry(&dir.path().join("gone.txt")), entry(&kept)]);
+        assert_eq!(hashed.len(), 1);
+        assert_eq!(hashed[0].path, kept);
+        assert_eq!(hashed[0].hash, Some(hash_file(&kept, &TokenBucket::new(None)).unwrap()));
     }
 }
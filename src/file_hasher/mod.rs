@@ -1 +1,2 @@
 pub mod hasher;
+pub mod priority;
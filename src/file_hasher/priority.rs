@@ -0,0 +1,27 @@
+//Drops the calling thread to the lowest CPU priority and the idle IO class, so
+//background indexing only gets the disk when nothing interactive wants it.
+//Linux applies both per thread when given the thread id.
+#[cfg(target_os = "linux")]
+pub fn lower_current_thread_priority() {
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if libc::setpriority(libc::PRIO_PROCESS, tid, 19) != 0 {
+            tracing::warn!("failed to lower cpu priority");
+        }
+        if libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        ) != 0 {
+            tracing::warn!("failed to set idle io priority");
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lower_current_thread_priority() {}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use crate::{config::settings::BatchConfig, file_uploader::file_upload::{FileEntryDTO, Operations}};
+
+pub type SyncBatch = HashMap<Operations, Vec<FileEntryDTO>>;
+
+//Deletes carry no body, only inserts and updates count towards the byte limit
+fn body_size(op: Operations, dto: &FileEntryDTO) -> u64 {
+    match op {
+        Operations::Delete => 0,
+        Operations::Insert | Operations::Update => dto.file_size.max(0) as u64
+    }
+}
+
+//Greedily packs operations into batches of at most max_files entries and
+//max_bytes of file content. Files of at least dedicated_min_size, and any
+//single file larger than max_bytes, get a batch of their own.
+pub fn split_batches(operations: SyncBatch, limits: &BatchConfig) -> Vec<SyncBatch> {
+    let mut operations = operations;
+    let mut batches: Vec<SyncBatch> = Vec::new();
+    let mut current: SyncBatch = HashMap::new();
+    let mut files = 0;
+    let mut bytes = 0;
+
+    for op in [Operations::Insert, Operations::Update, Operations::Delete] {
+        for dto in operations.remove(&op).unwrap_or_default() {
+            let size = body_size(op, &dto);
+            if size >= limits.dedicated_min_size.max(1) {
+                batches.push(HashMap::from([(op, vec![dto])]));
+                continue;
+            }
+
+            let full = files >= limits.max_files.max(1)
+                || (files > 0 && bytes + size > limits.max_bytes);
+
+            if full {
+                batches.push(std::mem::take(&mut current));
+                files = 0;
+                bytes = 0;
+            }
+
+            files += 1;
+            bytes += size;
+            current.entry(op).or_default().push(dto);
+        }
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+    batches
+}
+
+//For every batch, the earlier batches that touch one of its paths. Batches
+//run concurrently, these have to finish first so operations on one path
+//still reach the server in order.
+pub fn path_dependencies(batches: &[SyncBatch]) -> Vec<Vec<usize>> {
+    let mut last_seen: HashMap<&str, usize> = HashMap::new();
+    batches
+        .iter()
+        .enumerate()
+        .map(|(i, batch)| {
+            let mut deps: Vec<usize> = batch
+                .values()
+                .flatten()
+                .filter_map(|dto| last_seen.insert(dto.file_path.as_str(), i))
+                .filter(|dep| *dep != i)
+                .collect();
+            deps.sort_unstable();
+            deps.dedup();
+            deps
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dto(path: &str, size: i64) -> FileEntryDTO {
+        FileEntryDTO {
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            file_path: path.to_string(),
+            file_hash: Some(format!("hash of {}", path)),
+            file_size: size,
+            modified_time: 1,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        }
+    }
+
+    fn limits(max_files: usize, max_bytes: u64, dedicated_min_size: u64) -> BatchConfig {
+        BatchConfig { max_files, max_bytes, dedicated_min_size }
+    }
+
+    //Paths of every batch, in the order split_batches packs operations
+    fn paths(batches: &[SyncBatch]) -> Vec<Vec<&str>> {
+        batches.iter()
+            .map(|batch| [Operations::Insert, Operations::Update, Operations::Delete]
+                .iter()
+                .flat_map(|op| batch.get(op).into_iter().flatten())
+                .map(|dto| dto.file_path.as_str())
+                .collect())
+            .collect()
+    }
+
+    #[test]
+    fn packs_up_to_the_file_and_byte_limits() {
+        let operations = HashMap::from([
+            (Operations::Insert, vec![dto("/a", 40), dto("/b", 40), dto("/c", 40)]),
+            (Operations::Delete, vec![dto("/d", 1000), dto("/e", 1000), dto("/f", 1000)])
+        ]);
+        let batches = split_batches(operations, &limits(3, 100, 1000));
+        //Two inserts fill the bytes, deletes weigh nothing and only count as files
+        assert_eq!(paths(&batches), [vec!["/a", "/b"], vec!["/c", "/d", "/e"], vec!["/f"]]);
+    }
+
+    #[test]
+    fn a_large_file_gets_a_batch_of_its_own() {
+        let operations = HashMap::from([
+            (Operations::Insert, vec![dto("/small", 10), dto("/dedicated", 500), dto("/over", 200), dto("/after", 10)]),
+        ]);
+        let batches = split_batches(operations, &limits(100, 100, 500));
+        assert_eq!(paths(&batches), [vec!["/dedicated"], vec!["/small"], vec!["/over"], vec!["/after"]]);
+    }
+
+    #[test]
+    fn operations_on_one_path_wait_for_each_other() {
+        let mut batches = split_batches(HashMap::from([(Operations::Delete, vec![dto("/a", 0)])]), &limits(1, 100, 1000));
+        batches.extend(split_batches(HashMap::from([(Operations::Insert, vec![dto("/b", 10), dto("/a", 10)])]), &limits(1, 100, 1000)));
+        assert_eq!(paths(&batches), [vec!["/a"], vec!["/b"], vec!["/a"]]);
+        assert_eq!(path_dependencies(&batches), [vec![], vec![], vec![0]]);
+    }
+
+    #[test]
+    fn a_batch_depends_on_each_earlier_batch_once() {
+        let batches = vec![
+            HashMap::from([(Operations::Insert, vec![dto("/a", 1), dto("/b", 1)])]),
+            HashMap::from([(Operations::Update, vec![dto("/b", 1)])]),
+            HashMap::from([(Operations::Update, vec![dto("/a", 1), dto("/b", 1)]), (Operations::Delete, vec![dto("/c", 0)])])
+        ];
+        assert_eq!(path_dependencies(&batches), [vec![], vec![0], vec![0, 1]]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::HashMap, path::PathBuf};
+
+//Where the content of each payload entry is read from, keyed by the path the
+//server sees. Usually that's the user's file, a compressed or encrypted temp
+//copy replaces it and is removed again when the batch is dropped.
+#[derive(Debug, Default)]
+pub struct Bodies(HashMap<String, Body>);
+
+#[derive(Debug)]
+pub struct Body {
+    //The user's file, progress is reported against it
+    pub local: PathBuf,
+    pub file: PathBuf,
+    //blake2s of file when it isn't the content the entry's file_hash
+    //describes, as for an encrypted copy
+    pub hash: Option<String>,
+    temp: bool
+}
+
+impl Bodies {
+    pub fn local(&mut self, wire_path: String, local: PathBuf) {
+        self.replace(wire_path, Body { file: local.clone(), local, hash: None, temp: false });
+    }
+
+    pub fn temp(&mut self, wire_path: String, local: PathBuf, file: PathBuf, hash: Option<String>) {
+        self.replace(wire_path, Body { local, file, hash, temp: true });
+    }
+
+    //Goes back to reading the user's file
+    pub fn restore(&mut self, wire_path: &str) {
+        if let Some(local) = self.0.get(wire_path).map(|b| b.local.clone()) {
+            self.local(wire_path.to_string(), local);
+        }
+    }
+
+    pub fn get(&self, wire_path: &str) -> Option<&Body> {
+        self.0.get(wire_path)
+    }
+
+    fn replace(&mut self, wire_path: String, body: Body) {
+        if let Some(old) = self.0.insert(wire_path, body) {
+            remove_temp(&old);
+        }
+    }
+}
+
+impl Drop for Bodies {
+    fn drop(&mut self) {
+        self.0.values().for_each(remove_temp);
+    }
+}
+
+fn remove_temp(body: &Body) {
+    if body.temp {
+        let _ = std::fs::remove_file(&body.file);
+    }
+}
+
+pub fn temp_file(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pocket-drive-{:016x}.{}", fastrand::u64(..), extension))
+}
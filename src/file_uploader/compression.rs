@@ -0,0 +1,106 @@
+use std::{fs::File, io::{self, Read}, path::{Path, PathBuf}, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::{config::settings::CompressionConfig, file_uploader::bodies::temp_file};
+
+//Value of the content_encoding field for zstd bodies
+pub const ZSTD: &str = "zstd";
+
+//Formats that are compressed already, zstd would only spend CPU on them
+const SKIP_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "ogg", "opus", "flac", "m4a",
+    "mp4", "mkv", "mov", "avi", "webm",
+    "docx", "xlsx", "pptx", "odt", "jar", "apk", "woff2"
+];
+
+const SKIP_MAGIC: &[&[u8]] = &[
+    b"\x1f\x8b",                  //gzip
+    b"PK\x03\x04",                //zip and the formats built on it
+    b"\x28\xb5\x2f\xfd",          //zstd
+    b"\xfd7zXZ\x00",              //xz
+    b"BZh",                       //bzip2
+    b"7z\xbc\xaf\x27\x1c",        //7z
+    b"Rar!",
+    b"\x89PNG",
+    b"\xff\xd8\xff",              //jpeg
+    b"GIF8",
+    b"OggS",
+    b"fLaC",
+    b"ID3"                        //mp3
+];
+
+//Decides which bodies are sent zstd compressed and produces them. The server
+//is told through content_encoding, file_hash and file_size always describe
+//the raw content. A 415 answer turns compression off for the rest of the run.
+#[derive(Debug)]
+pub struct Compressor {
+    level: i32,
+    min_size: u64,
+    enabled: AtomicBool
+}
+
+impl Compressor {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            level: config.level,
+            min_size: config.min_size,
+            enabled: AtomicBool::new(config.enabled)
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    //Compresses into a temp file, None when the file is skipped or zstd
+    //doesn't make it smaller
+    pub async fn compress(&self, path: &Path, size: u64) -> io::Result<Option<PathBuf>> {
+        if !self.enabled() || size < self.min_size || !is_compressible(path)? {
+            return Ok(None);
+        }
+
+        let source = path.to_path_buf();
+        let level = self.level;
+        tokio::task::spawn_blocking(move || {
+            let target = temp_file("zst");
+            let written = File::open(&source)
+                .and_then(|input| zstd::stream::copy_encode(input, File::create(&target)?, level))
+                .and_then(|_| std::fs::metadata(&target));
+
+            match written {
+                Ok(metadata) if metadata.len() < size => Ok(Some(target)),
+                Ok(_) => {
+                    std::fs::remove_file(&target)?;
+                    Ok(None)
+                }
+                Err(e) => {
+                    let _ = std::fs::remove_file(&target);
+                    Err(e)
+                }
+            }
+        }).await?
+    }
+}
+
+//Extension first, then the first bytes for files that are misnamed or have none
+pub fn is_compressible(path: &Path) -> io::Result<bool> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if SKIP_EXTENSIONS.contains(&extension.as_str()) {
+        return Ok(false);
+    }
+
+    let mut head = [0u8; 12];
+    let read = File::open(path)?.read(&mut head)?;
+    let head = &head[..read];
+
+    let media = head.len() >= 12 && (&head[4..8] == b"ftyp" || (&head[..4] == b"RIFF" && &head[8..12] == b"WEBP"));
+    Ok(!media && !SKIP_MAGIC.iter().any(|magic| head.starts_with(magic)))
+}
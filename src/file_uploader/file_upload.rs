@@ -1,13 +1,33 @@
-use std::{collections::HashMap, sync::mpsc::Sender, time::SystemTime};
-use reqwest::multipart::{Form, Part};
-use reqwest::{Client, StatusCode};
-use serde::Serialize;
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, mpsc::Sender}, time::{Duration, SystemTime}};
+use blake2::{Blake2s256, Digest};
+use futures_util::{StreamExt, stream};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
-use tokio_util::codec::{BytesCodec, FramedRead};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
 
+use crate::backend::http::HttpBackend;
+use crate::backend::remote::{RemoteBackend, SyncError, Synced, relative_key};
+use crate::config::settings::{BandwidthConfig, BasicAuth, BatchConfig, Config, UploadConfig};
 use crate::db_listener::db::FileEntry;
+use crate::event_listener::self_writes::SelfWrites;
+use crate::file_hasher::hasher::{hash_file, to_hex};
+use crate::file_uploader::batch::{SyncBatch, path_dependencies, split_batches};
+use crate::crypto::container::{ALGORITHM, Decryptor, encrypt_file, opened_size};
+use crate::crypto::vault::{Vault, VaultError};
+use crate::file_uploader::bodies::{Bodies, temp_file};
+use crate::file_uploader::handshake::{HandshakeError, Limits};
+use crate::file_uploader::response::{ItemResult, ItemStatus, SyncResponse};
+use crate::file_uploader::retry::{RetryError, RetryPolicy};
+use crate::progress::events::{BatchKind, Progress, ProgressEvent};
+use crate::throttle::bandwidth::{Bandwidth, Direction};
+use crate::throttle::bucket::TokenBucket;
 
-#[derive(Hash, Eq, PartialEq, Serialize, Debug)]
+//How often schedule windows are checked against the clock
+const SCHEDULE_TICK: Duration = Duration::from_secs(30);
+
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum Operations{
     Insert,
@@ -15,23 +35,193 @@ pub enum Operations{
     Delete
 }
 
+impl Operations {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operations::Insert => "insert",
+            Operations::Update => "update",
+            Operations::Delete => "delete"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "insert" => Some(Operations::Insert),
+            "update" => Some(Operations::Update),
+            "delete" => Some(Operations::Delete),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncOutcome {
+    //The last batch is what was left out because its content couldn't be
+    //read, the server never saw it
+    Done(SyncBatch, SyncResponse, SyncBatch),
+    //The server's copy of these paths moved on since base_hash, nothing in
+    //the batch was applied
+    Conflict(SyncBatch, Vec<RemoteConflict>),
+    //Retries ran out or the server refused the batch. The operations come back
+    //untouched so the caller can queue them again instead of losing them
+    Failed(SyncBatch, String)
+}
+
 pub enum FileUploaderCmd {
-    Sync(HashMap<Operations, Vec<FileEntryDTO>>, Sender<(StatusCode, String)>),
-    Get()
+    Sync(SyncBatch, Sender<SyncOutcome>),
+    //Replaces the token and basic auth, and leaves the Unauthenticated state
+    Authenticate(Option<String>, Option<BasicAuth>),
+    //Pulls the server change feed from the given cursor and applies it locally.
+    //The map is the synced hash per indexed path, local edits aren't overwritten.
+    Get(Option<String>, HashMap<String, String>, Sender<Result<PullResult, String>>),
+    //Downloads exactly these files, answers with the ones that landed. A path
+    //next to an entry is where the local file is moved once the download is
+    //on disk, it stays untouched when the download fails.
+    Fetch(Vec<(FileEntryDTO, Option<PathBuf>)>, Sender<Vec<FileEntry>>),
+    //Swaps in a new schedule, used when the config is reloaded
+    SetSchedule(BandwidthConfig)
+}
+
+//One entry of the 409 body: {"conflicts": [RemoteConflict]}.
+//A missing remote_hash means the server deleted the file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteConflict {
+    pub file_path: String,
+    pub remote_hash: Option<String>,
+    pub remote_size: Option<i64>,
+    pub remote_modified_time: Option<i64>,
+    pub device_id: Option<String>
+}
+
+impl RemoteConflict {
+    //The server side version, for downloading it
+    pub fn to_dto(&self) -> FileEntryDTO {
+        let path = PathBuf::from(&self.file_path);
+        FileEntryDTO {
+            file_name: path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            file_path: self.file_path.clone(),
+            file_hash: self.remote_hash.clone(),
+            file_size: self.remote_size.unwrap_or(0),
+            modified_time: self.remote_modified_time.unwrap_or(0),
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        }
+    }
+}
+
+//One entry of GET /changes. Changes this device uploaded itself carry its
+//device id and are skipped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteChange {
+    pub operation: Operations,
+    #[serde(flatten)]
+    pub entry: FileEntryDTO,
+    #[serde(default)]
+    pub device_id: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangeFeed {
+    pub cursor: String,
+    pub changes: Vec<RemoteChange>
 }
 
-pub struct FileUploader{
+#[derive(Debug)]
+pub struct PullResult {
+    //None when the feed was only partly applied, it is pulled again from the old cursor
+    pub cursor: Option<String>,
+    //What ended up on disk, with the metadata read back after the rename
+    pub applied: Vec<(Operations, FileEntry)>,
+    //Paths edited here since they were last synced, left alone for conflict
+    //resolution. The local side comes first.
+    pub conflicts: Vec<(FileEntryDTO, RemoteConflict)>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploaderStatus {
+    Ready,
+    //The server answered 401 or 403. Nothing is sent until new credentials
+    //arrive through FileUploaderCmd::Authenticate, sent on SIGHUP
+    Unauthenticated,
+    //The backend didn't answer at all. Syncs fail right away so the caller can
+    //queue them, and the backend is probed with backoff until it answers
+    Offline,
+    //The handshake found no protocol both sides speak. Like Offline, except
+    //the probe only succeeds once the handshake does
+    Incompatible
+}
+
+impl UploaderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploaderStatus::Ready => "ready",
+            UploaderStatus::Unauthenticated => "unauthenticated",
+            UploaderStatus::Offline => "offline",
+            UploaderStatus::Incompatible => "incompatible"
+        }
+    }
+}
+
+//The sync pipeline: batching, encryption, verification and downloads, the
+//same for every place files can be kept. The backend only moves bytes.
+pub struct FileUploader<B: RemoteBackend = HttpBackend> {
     tx_uploader: tokio::sync::mpsc::Sender<FileUploaderCmd>,
     rx_uploader: tokio::sync::mpsc::Receiver<FileUploaderCmd>,
+    progress: Progress,
+    backend: B,
+    device_id: String,
+    //Only the backoff between probes, backends retry their own requests
+    retry: RetryPolicy,
+    batch: BatchConfig,
+    //What the backend said it takes, batches are cut to fit
+    limits: Limits,
+    upload: UploadConfig,
+    self_writes: SelfWrites,
+    status: watch::Sender<UploaderStatus>,
+    bandwidth: Bandwidth,
+    vault: Option<Arc<Vault>>,
+    //The watched directory, nothing from the server is written outside it
+    root: PathBuf
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileEntryDTO {
-    file_name: String,
-    file_path: String,
-    file_hash: Option<String>,
-    file_size: i64,
-    modified_time: i64,
+    pub file_name: String,
+    pub file_path: String,
+    pub file_hash: Option<String>,
+    pub file_size: i64,
+    pub modified_time: i64,
+    //Set when the content went up through a resumable session, the sync
+    //request then carries no part for this entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_id: Option<String>,
+    //Hash this device last synced for the path. The server refuses the
+    //operation with 409 when its current hash is different
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_hash: Option<String>,
+    //"zstd" when the part is compressed, file_size and file_hash still
+    //describe the raw content and compressed_size the bytes on the wire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed_size: Option<i64>,
+    //Set when the body is end-to-end encrypted. The server stores it as is,
+    //file_size is then the encrypted size and names and hashes are sealed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<String>,
+    //Name of the multipart part carrying the content, see request.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<String>,
+    //Given to the operation when it is first queued and kept through every
+    //retry, so a server that already applied it answers with the earlier
+    //result instead of applying it twice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
 }
 
 impl From<&FileEntry> for FileEntryDTO {
@@ -44,88 +234,668 @@ impl From<&FileEntry> for FileEntryDTO {
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis() as i64,
-            file_name: value.filename.to_string()
+            file_name: value.filename.to_string(),
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
         }
     }
 }
 
-impl FileUploader {
+impl<B: RemoteBackend> FileUploader<B> {
 
-    pub fn new() -> Self {
+    //bandwidth is the handle the backend throttles with, the limit commands
+    //change it for both
+    //Fails when encryption is on but its keys can't be derived
+    pub fn new(backend: B, progress: Progress, config: &Config, root: &Path, self_writes: SelfWrites, bandwidth: Bandwidth) -> Result<Self, VaultError> {
         let (tx, rx) = tokio::sync::mpsc::channel(1024);
-        Self {
+        let (status, _) = watch::channel(UploaderStatus::Ready);
+        Ok(Self {
             tx_uploader: tx,
-            rx_uploader: rx
-        }
+            rx_uploader: rx,
+            progress,
+            backend,
+            device_id: config.server.device_id.clone(),
+            retry: RetryPolicy::new(&config.retry, None),
+            batch: config.batch.clone(),
+            limits: Limits::default(),
+            upload: config.upload.clone(),
+            self_writes,
+            status,
+            bandwidth,
+            vault: Vault::from_config(&config.encryption)?.map(Arc::new),
+            root: root.to_path_buf()
+        })
     }
 
     pub fn get_sender(&self) -> tokio::sync::mpsc::Sender<FileUploaderCmd> {
         self.tx_uploader.clone()
     }
 
-    pub async fn run(mut self) {
-        while let Some(val) = self.rx_uploader.recv().await {
-            self.execute(val).await;
+    pub fn status(&self) -> watch::Receiver<UploaderStatus> {
+        self.status.subscribe()
+    }
+
+    //A backend that never answered takes the uploader offline, one that
+    //refused the credentials stops it until new ones arrive. Works from any
+    //state, a probe or handshake can fail while the uploader isn't Ready.
+    fn note_failure(&self, error: &RetryError) {
+        let status = match error {
+            RetryError::Unreachable(_) | RetryError::Proxy(_) => UploaderStatus::Offline,
+            RetryError::Status(status, _) if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN => UploaderStatus::Unauthenticated,
+            _ => return
+        };
+        //Logged once, not again for every probe that still fails
+        if *self.status.borrow() == status {
+            return;
         }
+        match error {
+            RetryError::Unreachable(reason) => {
+                tracing::warn!("{} is unreachable ({}), working offline", self.backend.describe(), reason);
+            }
+            RetryError::Proxy(reason) => {
+                tracing::warn!("proxy failed ({}), {} wasn't reached, working offline", reason, self.backend.describe());
+            }
+            RetryError::Status(status, _) => {
+                tracing::warn!(
+                    "{} rejected the credentials for device {} ({}), update them in the config and send SIGHUP",
+                    self.backend.describe(), self.device_id, status
+                );
+            }
+            _ => {}
+        }
+        self.status.send_replace(status);
     }
 
-    async fn execute(&self, cmd: FileUploaderCmd) {
-        match cmd {
-            FileUploaderCmd::Sync(operations, sender) => {
-                let client = Client::new();
-                let payload = serde_json::to_string(&operations).unwrap();
-                let mut form = Form::new().text("payload", payload);
-
-                for entries in operations.values(){
-                    for dto in entries{
-                        let path = &dto.file_path;
-                        if !std::path::Path::new(path).exists() {
-                            continue;
+    //Agrees on the protocol with the backend. Ready is only set by the caller
+    //once this succeeded, a failure sets the status itself
+    async fn handshake(&mut self) -> bool {
+        match self.backend.handshake(&self.device_id).await {
+            Ok(limits) => {
+                self.limits = limits;
+                true
+            }
+            Err(HandshakeError::Incompatible(reason)) => {
+                tracing::warn!("{} can't be synced with: {}", self.backend.describe(), reason);
+                self.status.send_replace(UploaderStatus::Incompatible);
+                false
+            }
+            Err(HandshakeError::Failed(e)) => {
+                tracing::warn!("handshake with {} failed: {}", self.backend.describe(), e);
+                self.note_failure(&e);
+                false
+            }
+        }
+    }
+
+    //The server may have been upgraded while it was away, so coming back
+    //means agreeing on the protocol again
+    async fn probe(&mut self) -> bool {
+        if !self.backend.probe().await || !self.handshake().await {
+            return false;
+        }
+        self.status.send_replace(UploaderStatus::Ready);
+        tracing::info!("{} is reachable again", self.backend.describe());
+        true
+    }
+
+    //The configured batch limits, cut down to what the backend takes. Some
+    //room is left for the payload and the multipart framing.
+    fn batch_config(&self) -> BatchConfig {
+        let mut batch = self.batch.clone();
+        if let Some(max) = self.limits.max_batch_files {
+            batch.max_files = batch.max_files.min(max.max(1));
+        }
+        if let Some(max) = self.limits.max_body_bytes {
+            batch.max_bytes = batch.max_bytes.min(max - max / 16);
+            batch.dedicated_min_size = batch.dedicated_min_size.min(batch.max_bytes);
+        }
+        batch
+    }
+
+    async fn sync_batch(&self, operations: SyncBatch) -> SyncOutcome {
+        match *self.status.borrow() {
+            UploaderStatus::Ready => {}
+            UploaderStatus::Unauthenticated => return SyncOutcome::Failed(operations, "re-authenticate".to_string()),
+            UploaderStatus::Offline => return SyncOutcome::Failed(operations, "server offline".to_string()),
+            UploaderStatus::Incompatible => return SyncOutcome::Failed(operations, "server speaks an incompatible protocol".to_string())
+        }
+
+        let files = operations.values().map(|v| v.len() as u64).sum();
+        let total_bytes = operations.iter()
+            .filter(|(op, _)| **op != Operations::Delete)
+            .flat_map(|(_, entries)| entries.iter())
+            .map(|dto| dto.file_size as u64)
+            .sum();
+        self.progress.emit(ProgressEvent::UploadStarted { files, total_bytes });
+
+        //The outcome hands back the operations as they came in, the server
+        //only ever sees the wire copy
+        let (wire, mut bodies) = self.seal_batch(&operations).await;
+        let result = self.backend.sync(&self.device_id, &wire, &mut bodies).await;
+
+        let outcome = match result {
+            Ok(Synced { mut response, skipped }) => {
+                //What couldn't be read was never sent and goes back to the caller
+                //to be queued, a delete from the watcher cancels it out there
+                let mut operations = operations;
+                let mut unsent: SyncBatch = HashMap::new();
+                for (op, dto) in &skipped {
+                    if let Some(entries) = operations.get_mut(op) {
+                        let (left_out, kept) = std::mem::take(entries)
+                            .into_iter()
+                            .partition(|local| self.wire_path(&local.file_path) == dto.file_path);
+                        *entries = kept;
+                        unsent.entry(*op).or_default().extend(left_out);
+                    }
+                }
+                self.verify_results(&mut response, &wire, &bodies).await;
+                response.results = response.results
+                    .into_iter()
+                    .filter_map(|item| self.open_result(item))
+                    .collect();
+                SyncOutcome::Done(operations, response, unsent)
+            }
+            Err(SyncError::Conflict(conflicts)) => {
+                let conflicts = conflicts
+                    .into_iter()
+                    .filter_map(|c| self.open_conflict(c))
+                    .collect();
+                SyncOutcome::Conflict(operations, conflicts)
+            }
+            Err(SyncError::Failed(e)) => {
+                self.note_failure(&e);
+                SyncOutcome::Failed(operations, e.to_string())
+            }
+        };
+
+        self.progress.emit(ProgressEvent::BatchDone {
+            kind: BatchKind::Upload,
+            files,
+            success: matches!(outcome, SyncOutcome::Done(..))
+        });
+        outcome
+    }
+
+    //Builds the copy of the operations that is sent and finds the body of each
+    //entry. With encryption on, names and hashes are sealed and every body is
+    //encrypted into a temp file first.
+    async fn seal_batch(&self, operations: &SyncBatch) -> (SyncBatch, Bodies) {
+        let mut wire: SyncBatch = HashMap::new();
+        let mut bodies = Bodies::default();
+
+        for (op, entries) in operations {
+            for dto in entries {
+                let mut sealed = dto.clone();
+                let local = PathBuf::from(&dto.file_path);
+                let Some(vault) = &self.vault else {
+                    if *op != Operations::Delete {
+                        bodies.local(sealed.file_path.clone(), local);
+                    }
+                    wire.entry(*op).or_default().push(sealed);
+                    continue;
+                };
+
+                sealed.file_path = vault.seal_path(&dto.file_path);
+                sealed.file_name = vault.seal_name(&dto.file_name);
+                sealed.file_hash = dto.file_hash.as_deref().map(|h| vault.seal_hash(h));
+                sealed.base_hash = dto.base_hash.as_deref().map(|h| vault.seal_hash(h));
+
+                if *op != Operations::Delete && dto.file_hash.is_some() {
+                    let target = temp_file("pde");
+                    let (vault, source, file) = (vault.clone(), local.clone(), target.clone());
+                    let encrypted = tokio::task::spawn_blocking(move || encrypt_file(&vault, &source, &file)).await.unwrap();
+                    match encrypted {
+                        Ok((size, body_hash)) => {
+                            sealed.file_size = size as i64;
+                            sealed.encryption = Some(ALGORITHM.to_string());
+                            bodies.temp(sealed.file_path.clone(), local, target, Some(body_hash));
                         }
+                        //Goes out without a body like any file that vanished
+                        Err(e) => {
+                            let _ = std::fs::remove_file(&target);
+                            tracing::warn!("could not encrypt {}: {}", dto.file_path, e);
+                        }
+                    }
+                }
+                wire.entry(*op).or_default().push(sealed);
+            }
+        }
+
+        (wire, bodies)
+    }
+
+    //Sealing is deterministic, so this is also the path the server stored
+    fn wire_path(&self, local: &str) -> String {
+        match &self.vault {
+            Some(vault) => vault.seal_path(local),
+            None => local.to_string()
+        }
+    }
+
+    //Turns a sealed entry from the server back into local names and hashes.
+    //None for entries this passphrase can't open.
+    fn open_entry(&self, mut dto: FileEntryDTO) -> Option<FileEntryDTO> {
+        let Some(vault) = &self.vault else {
+            return Some(dto);
+        };
+        let sealed_path = dto.file_path.clone();
+        let opened = (|| {
+            dto.file_path = vault.open_path(&dto.file_path)?;
+            dto.file_name = vault.open_name(&dto.file_name)?;
+            dto.file_hash = match dto.file_hash.as_deref() {
+                Some(hash) => Some(vault.open_hash(hash)?),
+                None => None
+            };
+            if dto.encryption.take().is_some() {
+                dto.file_size = opened_size(dto.file_size as u64, vault.chunk_size()) as i64;
+            }
+            Some(dto)
+        })();
+        if opened.is_none() {
+            tracing::warn!("{} can't be opened with this passphrase, skipped", sealed_path);
+        }
+        opened
+    }
+
+    fn open_conflict(&self, conflict: RemoteConflict) -> Option<RemoteConflict> {
+        if self.vault.is_none() {
+            return Some(conflict);
+        }
+        let dto = self.open_entry(FileEntryDTO {
+            encryption: conflict.remote_size.map(|_| ALGORITHM.to_string()),
+            ..conflict.to_dto()
+        })?;
+        Some(RemoteConflict {
+            file_path: dto.file_path,
+            remote_hash: dto.file_hash,
+            remote_size: conflict.remote_size.map(|_| dto.file_size),
+            ..conflict
+        })
+    }
+
+    //Checks every stored item against what was meant to be stored: the hash
+    //the server computed has to be the one sent, and the file must look the
+    //same as when it was hashed. Anything else gets sent again.
+    async fn verify_results(&self, response: &mut SyncResponse, wire: &SyncBatch, bodies: &Bodies) {
+        let sent: HashMap<&str, &FileEntryDTO> = wire
+            .iter()
+            .filter(|(op, _)| **op != Operations::Delete)
+            .flat_map(|(_, entries)| entries.iter())
+            .map(|dto| (dto.file_path.as_str(), dto))
+            .collect();
+
+        for item in response.results.iter_mut().filter(|item| item.status == ItemStatus::Ok) {
+            let Some(dto) = sent.get(item.file_path.as_str()) else {
+                continue;
+            };
+            let body = bodies.get(&dto.file_path);
+
+            let expected = body.and_then(|b| b.hash.as_deref()).or(dto.file_hash.as_deref());
+            if let (Some(stored), Some(expected)) = (item.content_hash.as_deref(), expected)
+                && stored != expected
+            {
+                item.status = ItemStatus::Mismatch;
+                item.message = Some(format!("server stored {} but {} was sent", stored, expected));
+                continue;
+            }
+
+            //Local size and mtime, not the encrypted ones on the wire
+            let Some(local) = body.map(|b| b.local.as_path()) else {
+                continue;
+            };
+            let unchanged = match tokio::fs::metadata(local).await {
+                Ok(metadata) => {
+                    let modified = metadata.modified().ok()
+                        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+                        .map(|m| m.as_millis() as i64);
+                    let size = dto.encryption.as_ref().map_or(dto.file_size, |_| {
+                        opened_size(dto.file_size as u64, self.vault.as_ref().map_or(0, |v| v.chunk_size())) as i64
+                    });
+                    metadata.len() as i64 == size && modified == Some(dto.modified_time)
+                }
+                Err(_) => false
+            };
+            if !unchanged {
+                item.status = ItemStatus::Mismatch;
+                item.message = Some("file changed while it was uploaded".to_string());
+            }
+        }
+    }
+
+    fn open_result(&self, item: ItemResult) -> Option<ItemResult> {
+        let conflict = self.open_conflict(item.to_conflict())?;
+        Some(ItemResult {
+            file_path: conflict.file_path,
+            server_hash: conflict.remote_hash,
+            server_size: conflict.remote_size,
+            ..item
+        })
+    }
+
+    async fn pull(&self, cursor: Option<String>, synced: &HashMap<String, String>) -> Result<PullResult, RetryError> {
+        if *self.status.borrow() == UploaderStatus::Offline {
+            return Err(RetryError::Unreachable(format!("{} offline", self.backend.describe())));
+        }
+        let feed = self.backend.changes(cursor).await.inspect_err(|e| self.note_failure(e))?;
+
+        let mut applied = Vec::new();
+        let mut conflicts = Vec::new();
+        for mut change in feed.changes {
+            if change.device_id.as_deref() == Some(self.device_id.as_str()) {
+                continue;
+            }
+            change.entry = match self.open_entry(change.entry) {
+                Some(entry) => entry,
+                None => continue
+            };
+            let Some(target) = self.local_path(&change.entry.file_path) else {
+                continue;
+            };
+
+            if let Some(local) = self.local_edit(&target, &change.entry, synced).await {
+                let remote = &change.entry;
+                let deleted = change.operation == Operations::Delete;
+                conflicts.push((local, RemoteConflict {
+                    file_path: target.to_string_lossy().to_string(),
+                    remote_hash: if deleted { None } else { remote.file_hash.clone() },
+                    remote_size: if deleted { None } else { Some(remote.file_size) },
+                    remote_modified_time: Some(remote.modified_time),
+                    device_id: change.device_id.clone()
+                }));
+                continue;
+            }
+
+            let result = match change.operation {
+                Operations::Delete => self.apply_remote_delete(&change.entry).await,
+                Operations::Insert | Operations::Update => self.download(&change.entry, None).await
+            };
+
+            match result {
+                Ok(Some(entry)) => applied.push((change.operation, entry)),
+                Ok(None) => {}
+                //Transient trouble, keep what was applied and pull the rest next time
+                Err(e) => {
+                    tracing::warn!("pull stopped at {}: {}", change.entry.file_path, e);
+                    return Ok(PullResult { cursor: None, applied, conflicts });
+                }
+            }
+        }
+
+        Ok(PullResult { cursor: Some(feed.cursor), applied, conflicts })
+    }
+
+    //Where a path from the server lands on disk. Paths are kept under the
+    //watched directory, one that would climb out of it is refused.
+    fn local_path(&self, remote: &str) -> Option<PathBuf> {
+        let relative = Path::new(remote)
+            .strip_prefix(&self.root)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| remote.to_string());
+        match relative_key(&relative) {
+            Ok(key) => Some(self.root.join(key)),
+            Err(_) => {
+                tracing::warn!("{} is outside {}, skipped", remote, self.root.display());
+                None
+            }
+        }
+    }
+
+    //The local side of a path changed since it was last synced, returned
+    //for conflict resolution instead of being overwritten or deleted. A file
+    //that already has the remote content is no conflict.
+    async fn local_edit(&self, target: &Path, remote: &FileEntryDTO, synced: &HashMap<String, String>) -> Option<FileEntryDTO> {
+        let metadata = tokio::fs::metadata(target).await.ok()?;
+        let path = target.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || hash_file(&path, &TokenBucket::new(None))).await.ok()?.ok()?;
+
+        let key = target.to_string_lossy().to_string();
+        if synced.get(&key) == Some(&hash) || remote.file_hash.as_ref() == Some(&hash) {
+            return None;
+        }
+        let entry = FileEntry {
+            filename: target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            path: target.to_path_buf(),
+            hash: Some(hash),
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            fingerprint: None
+        };
+        //Resent as it is, it runs into the same conflict on the server
+        Some(FileEntryDTO { base_hash: synced.get(&key).cloned(), ..FileEntryDTO::from(&entry) })
+    }
+
+    async fn apply_remote_delete(&self, dto: &FileEntryDTO) -> Result<Option<FileEntry>, RetryError> {
+        let Some(path) = self.local_path(&dto.file_path) else {
+            return Ok(None);
+        };
+        self.self_writes.record(&path);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into())
+        }
+
+        Ok(Some(FileEntry {
+            filename: dto.file_name.clone(),
+            path,
+            hash: dto.file_hash.clone(),
+            size: dto.file_size as u64,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_millis(dto.modified_time as u64),
+            fingerprint: None
+        }))
+    }
+
+    //Streams into a temp file next to the target, checks the hash and renames
+    //it over the target, so a reader never sees a half written file.
+    //Returns None when the server no longer has the file or the content
+    //doesn't match, a newer change for it will follow in the feed.
+    //With keep_as the file at the target is moved there just before the
+    //rename, so it is only ever displaced by a complete download.
+    async fn download(&self, dto: &FileEntryDTO, keep_as: Option<&Path>) -> Result<Option<FileEntry>, RetryError> {
+        let Some(target) = self.local_path(&dto.file_path) else {
+            return Ok(None);
+        };
+        let tmp = temp_path(&target);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut stream = match self.backend.get(&self.wire_path(&dto.file_path)).await {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.note_failure(&e);
+                return Err(e);
+            }
+        };
+
+        self.self_writes.record(&tmp);
+        self.self_writes.record(&target);
+
+        let mut file = File::create(&tmp).await?;
+        let mut hash = Blake2s256::new();
+        let mut decryptor = self.vault.as_deref().map(Decryptor::new);
+        loop {
+            let next = match stream.next().await {
+                Some(Ok(chunk)) => {
+                    self.bandwidth.acquire(Direction::Download, chunk.len() as u64).await;
+                    Some(chunk)
+                }
+                Some(Err(e)) => {
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return Err(RetryError::Exhausted(e.to_string()));
+                }
+                None => None
+            };
+
+            //Decrypted content is checked against the hash like any other
+            let plain = match (decryptor.as_mut(), &next) {
+                (Some(decryptor), Some(chunk)) => decryptor.push(chunk),
+                (None, Some(chunk)) => Ok(chunk.to_vec()),
+                (_, None) => decryptor.take().map(Decryptor::finish).unwrap_or(Ok(Vec::new()))
+            };
+            let plain = match plain {
+                Ok(plain) => plain,
+                Err(e) => {
+                    tracing::warn!("{} can't be decrypted, skipped: {}", dto.file_path, e);
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return Ok(None);
+                }
+            };
+            hash.update(&plain);
+            file.write_all(&plain).await?;
+            if next.is_none() {
+                break;
+            }
+        }
+        file.flush().await?;
 
-                        let file = File::open(path).await.unwrap();
+        let digest = to_hex(&hash.finalize());
+        if dto.file_hash.as_ref().is_some_and(|expected| *expected != digest) {
+            tracing::warn!("{} changed on the server while downloading, skipped", dto.file_path);
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Ok(None);
+        }
 
-                        let stream = FramedRead::new(file, BytesCodec::new());
-                        let body = reqwest::Body::wrap_stream(stream);
+        //Keep the mtime from the device that wrote the file
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(dto.modified_time as u64);
+        file.into_std().await.set_modified(modified)?;
+        //The copy is a new file to the next scan and goes up as an insert
+        if let Some(copy) = keep_as
+            && let Err(e) = tokio::fs::rename(&target, copy).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e.into());
+        }
+        tokio::fs::rename(&tmp, &target).await?;
 
-                        let filename = std::path::Path::new(path)
-                            .file_name()
-                            .unwrap()
-                            .to_string_lossy()
-                            .to_string();
+        let metadata = tokio::fs::metadata(&target).await?;
+        Ok(Some(FileEntry {
+            filename: dto.file_name.clone(),
+            path: target,
+            hash: Some(digest),
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(modified),
+            fingerprint: None
+        }))
+    }
 
-                        let part = Part::stream(body)
-                            .file_name(filename)
-                            .mime_str("application/octet-stream")
-                            .unwrap();
+    pub async fn run(mut self) {
+        let bandwidth = self.bandwidth.clone();
+        let schedule = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SCHEDULE_TICK);
+            loop {
+                interval.tick().await;
+                bandwidth.refresh();
+            }
+        });
 
-                        // same name for multiple files
-                        form = form.part("files", part);
+        self.handshake().await;
+
+        //Probing runs between commands, the deadline survives commands arriving
+        let mut probe_attempt = 0;
+        let mut next_probe: Option<tokio::time::Instant> = None;
+        loop {
+            let status = *self.status.borrow();
+            if status == UploaderStatus::Offline || status == UploaderStatus::Incompatible {
+                next_probe.get_or_insert_with(|| tokio::time::Instant::now() + self.retry.backoff(probe_attempt));
+            } else {
+                probe_attempt = 0;
+                next_probe = None;
+            }
+
+            tokio::select! {
+                cmd = self.rx_uploader.recv() => match cmd {
+                    Some(cmd) => self.execute(cmd).await,
+                    None => break
+                },
+                _ = tokio::time::sleep_until(next_probe.unwrap_or_else(tokio::time::Instant::now)), if next_probe.is_some() => {
+                    if !self.probe().await {
+                        probe_attempt += 1;
                     }
+                    next_probe = None;
                 }
+            }
+        }
+        schedule.abort();
+    }
 
-                dbg!(&operations);
-                let response = client
-                    .post("http://localhost:8000/sync")
-                    .multipart(form)
-                    .send()
-                    .await
-                    .unwrap();
+    async fn execute(&mut self, cmd: FileUploaderCmd) {
+        match cmd {
+            //One outcome is sent per batch as it finishes, the channel closes
+            //after the last one
+            FileUploaderCmd::Sync(operations, sender) => {
+                let batches = split_batches(operations, &self.batch_config());
+                let deps = path_dependencies(&batches);
+                let (done_tx, done_rx): (Vec<_>, Vec<_>) = batches.iter().map(|_| watch::channel(false)).unzip();
 
-                let status = response.status();
-                let body = response.text().await.unwrap();
+                let this = &*self;
+                let mut outcomes = stream::iter(batches.into_iter().zip(deps).zip(done_tx))
+                    .map(|((batch, deps), done)| {
+                        let waits: Vec<_> = deps.into_iter().map(|dep| done_rx[dep].clone()).collect();
+                        async move {
+                            for mut wait in waits {
+                                let _ = wait.wait_for(|finished| *finished).await;
+                            }
+                            let outcome = this.sync_batch(batch).await;
+                            done.send_replace(true);
+                            outcome
+                        }
+                    })
+                    .buffer_unordered(self.upload.workers.max(1));
 
-                println!("Body:\n{}", body);
-                println!("Status: {}", status);
-                sender.send((status, body)).unwrap();                
+                while let Some(outcome) = outcomes.next().await {
+                    let _ = sender.send(outcome);
+                }
             }
-            FileUploaderCmd::Get() => unimplemented!()
+            //The credentials are swapped in whatever the state, only an
+            //uploader stopped by them starts again
+            FileUploaderCmd::Authenticate(token, basic_auth) => {
+                self.backend.authenticate(token, basic_auth);
+                if *self.status.borrow() == UploaderStatus::Unauthenticated && self.handshake().await {
+                    self.status.send_replace(UploaderStatus::Ready);
+                }
+            }
+            FileUploaderCmd::Fetch(files, sender) => {
+                let mut fetched = Vec::new();
+                for (dto, keep_as) in files {
+                    if *self.status.borrow() == UploaderStatus::Offline {
+                        break;
+                    }
+                    match self.download(&dto, keep_as.as_deref()).await {
+                        Ok(Some(entry)) => fetched.push(entry),
+                        Ok(None) => {}
+                        Err(e) => tracing::warn!("fetching {} failed: {}", dto.file_path, e)
+                    }
+                }
+                let _ = sender.send(fetched);
+            }
+            FileUploaderCmd::Get(cursor, synced, sender) => {
+                let result = self.pull(cursor, &synced).await.map_err(|e| e.to_string());
+                let _ = sender.send(result);
+            }
+            FileUploaderCmd::SetSchedule(config) => self.bandwidth.set_schedule(&config)
         }
     }
 }
 
 impl Default for FileUploader {
     fn default() -> Self {
-        Self::new()
+        let (progress, config) = (Progress::new(), Config::default());
+        let bandwidth = Bandwidth::new(&config.bandwidth);
+        let backend = HttpBackend::new(&config, progress.clone(), bandwidth.clone());
+        Self::new(backend, progress, &config, Path::new("."), SelfWrites::new(), bandwidth)
+            .expect("encryption is off by default")
     }
 }
+
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    target.with_file_name(format!(".{}.pocket-drive-part", name))
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+use crate::file_uploader::retry::RetryError;
+
+//POST /handshake, sent when the uploader starts and again whenever the
+//server comes back:
+//  request  {"protocol_version": 1, "features": [Feature], "device_id": "..."}
+//  response {"protocol_version": 1, "min_protocol_version": 1, "features": [Feature], "limits": Limits}
+//Each side only uses what both listed. A server that can't talk to the
+//client's version at all answers 426 with the reason as body, servers from
+//before the handshake answer 404.
+pub const PROTOCOL_VERSION: u32 = 1;
+//Oldest protocol this side still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    //zstd bodies, see compression.rs
+    Compression,
+    //Upload sessions for large files, POST /uploads
+    Resumable,
+    //POST /move
+    Rename,
+    //GET /changes
+    ChangeFeed,
+    //GET /versions and older versions through GET /files
+    Versions,
+    //Whatever a newer peer offers that this build doesn't know
+    #[serde(other)]
+    Unknown
+}
+
+//What this client can use
+pub const CLIENT_FEATURES: &[Feature] = &[Feature::Compression, Feature::Resumable, Feature::Rename, Feature::ChangeFeed];
+
+//What servers offered before there was a handshake
+pub const LEGACY_FEATURES: &[Feature] = &[Feature::Compression, Feature::Resumable, Feature::Rename, Feature::ChangeFeed];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub features: Vec<Feature>,
+    pub device_id: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub min_protocol_version: u32,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    #[serde(default)]
+    pub limits: Limits
+}
+
+//Bounds the server enforces, None where it has none
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Limits {
+    //Largest request body, a sync request or one upload chunk
+    pub max_body_bytes: Option<u64>,
+    //Entries in one sync request
+    pub max_batch_files: Option<usize>
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    //The two sides have no protocol version in common, nothing can be sent
+    Incompatible(String),
+    Failed(RetryError)
+}
+
+impl From<RetryError> for HandshakeError {
+    fn from(e: RetryError) -> Self {
+        HandshakeError::Failed(e)
+    }
+}
@@ -1 +1,11 @@
 pub mod file_upload;
+pub mod retry;
+pub mod batch;
+pub mod sessions;
+pub mod compression;
+pub mod bodies;
+pub mod request;
+pub mod response;
+pub mod tls;
+pub mod proxy;
+pub mod handshake;
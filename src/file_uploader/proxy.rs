@@ -0,0 +1,94 @@
+use std::env;
+
+use reqwest::{ClientBuilder, NoProxy, Proxy, Url};
+
+use crate::config::settings::ProxyConfig;
+
+//The proxy the server is reached through, if any. Built here rather than left
+//to reqwest's own environment lookup so failures can be blamed on the right hop.
+#[derive(Debug, Clone)]
+pub struct ProxyRoute {
+    url: Url,
+    no_proxy: Option<String>
+}
+
+impl ProxyRoute {
+    pub fn resolve(config: &ProxyConfig, base_url: &str) -> Option<Self> {
+        let scheme = Url::parse(base_url).map(|u| u.scheme().to_string()).unwrap_or_default();
+        let (raw, no_proxy) = match &config.url {
+            Some(url) => (url.clone(), config.no_proxy.clone()),
+            None if config.from_env => (
+                env_proxy(&scheme)?,
+                config.no_proxy.clone().or_else(|| first_env(&["NO_PROXY", "no_proxy"]))
+            ),
+            None => return None
+        };
+
+        let mut url = Url::parse(&raw)
+            .unwrap_or_else(|e| panic!("proxy: invalid url {:?}: {}", raw, e));
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            panic!("proxy: unsupported scheme {:?}, use http, https, socks5 or socks5h", url.scheme());
+        }
+        if let Some(username) = &config.username {
+            url.set_username(username).unwrap();
+        }
+        if let Some(password) = &config.password {
+            url.set_password(Some(password)).unwrap();
+        }
+        Some(Self { url, no_proxy })
+    }
+
+    //reqwest otherwise still reads the environment on its own
+    pub fn apply(route: Option<&Self>, builder: ClientBuilder) -> ClientBuilder {
+        match route {
+            Some(route) => {
+                //Credentials in the url become basic auth for http proxies and
+                //username/password auth for socks5
+                let proxy = Proxy::all(route.url.clone())
+                    .unwrap()
+                    .no_proxy(route.no_proxy.as_deref().and_then(NoProxy::from_string));
+                builder.proxy(proxy)
+            }
+            None => builder.no_proxy()
+        }
+    }
+
+    //Whether requests to base_url go through the proxy at all
+    pub fn covers(&self, base_url: &str) -> bool {
+        let Some(host) = Url::parse(base_url).ok().and_then(|u| u.host_str().map(str::to_lowercase)) else {
+            return true;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        !self.no_proxy.iter()
+            .flat_map(|list| list.split(','))
+            .map(|entry| entry.trim().to_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .any(|entry| {
+                let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+                entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+            })
+    }
+
+    //For logs, without the password
+    pub fn display(&self) -> String {
+        let mut url = self.url.clone();
+        if url.password().is_some() {
+            url.set_password(Some("***")).unwrap();
+        }
+        url.to_string().trim_end_matches('/').to_string()
+    }
+}
+
+//Lowercase first, the same order curl uses
+fn env_proxy(scheme: &str) -> Option<String> {
+    match scheme {
+        "https" => first_env(&["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"]),
+        _ => first_env(&["http_proxy", "HTTP_PROXY", "all_proxy", "ALL_PROXY"])
+    }
+}
+
+fn first_env(names: &[&str]) -> Option<String> {
+    names.iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+}
@@ -0,0 +1,288 @@
+use std::{collections::HashMap, fs::File, io::{Seek, SeekFrom}, path::PathBuf};
+
+use blake2::{Blake2s256, Digest};
+use futures_util::StreamExt;
+use reqwest::multipart::{Form, Part};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::{file_hasher::hasher::to_hex, file_uploader::{batch::SyncBatch, bodies::Bodies, compression::ZSTD, file_upload::{FileEntryDTO, Operations}}, progress::events::{Progress, ProgressEvent}, throttle::bandwidth::{Bandwidth, Direction}};
+
+//POST /sync, multipart/form-data:
+//
+//  payload    text part, JSON {"insert": [entry], "update": [entry], "delete": [entry]}
+//             with every entry a FileEntryDTO
+//  <part id>  one file part per entry whose content is in this request. The
+//             part is named by the entry's "part" field and its filename is
+//             the entry's file_name, the path is only in the payload.
+//
+//Every insert and update entry has exactly one of "part" or "upload_id", the
+//latter when the content already went up through a resumable session.
+//Deletes have neither. Part ids are only unique within one request. The
+//server answers 400 to a referenced part that is missing or a part that no
+//entry references, so an entry is never matched to a file by position.
+//
+//Each entry carries its idempotency_key and the request an Idempotency-Key
+//header made from them, so the same operations resent give the same key. A
+//server that has seen the key answers with what it answered then.
+pub struct SyncRequest {
+    payload: SyncBatch,
+    parts: Vec<PendingPart>
+}
+
+struct PendingPart {
+    id: String,
+    file_name: String,
+    mime: &'static str,
+    //Opened while building, so a file removed before an attempt is still sent
+    file: File,
+    //Progress is reported against the user's file
+    local: PathBuf
+}
+
+impl SyncRequest {
+    //Entries whose content can't be opened are left out of the request and
+    //returned, there is no part to link them to
+    pub fn build(operations: &SyncBatch, bodies: &Bodies) -> (Self, Vec<(Operations, FileEntryDTO)>) {
+        let mut payload: SyncBatch = HashMap::new();
+        let mut parts = Vec::new();
+        let mut skipped = Vec::new();
+
+        for op in [Operations::Insert, Operations::Update, Operations::Delete] {
+            for dto in operations.get(&op).into_iter().flatten() {
+                let mut dto = dto.clone();
+                dto.part = None;
+
+                if op != Operations::Delete && dto.upload_id.is_none() {
+                    let opened = bodies.get(&dto.file_path)
+                        .ok_or_else(|| "no content prepared".to_string())
+                        .and_then(|body| File::open(&body.file).map(|f| (f, body.local.clone())).map_err(|e| e.to_string()));
+                    let (file, local) = match opened {
+                        Ok(opened) => opened,
+                        Err(e) => {
+                            tracing::warn!("leaving {} out of the request: {}", dto.file_path, e);
+                            skipped.push((op, dto));
+                            continue;
+                        }
+                    };
+
+                    let id = format!("part-{}", parts.len());
+                    dto.part = Some(id.clone());
+                    parts.push(PendingPart {
+                        id,
+                        file_name: dto.file_name.clone(),
+                        mime: match dto.content_encoding.as_deref() {
+                            Some(ZSTD) => "application/zstd",
+                            _ => "application/octet-stream"
+                        },
+                        file,
+                        local
+                    });
+                }
+
+                payload.entry(op).or_default().push(dto);
+            }
+        }
+
+        (Self { payload, parts }, skipped)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    //None when an entry has no key of its own, the request then can't be
+    //told apart from a different one with the same paths
+    pub fn idempotency_key(&self) -> Option<String> {
+        let mut keys = Vec::new();
+        for op in [Operations::Insert, Operations::Update, Operations::Delete] {
+            for dto in self.payload.get(&op).into_iter().flatten() {
+                keys.push(format!("{}:{}", op.as_str(), dto.idempotency_key.as_deref()?));
+            }
+        }
+        keys.sort();
+        let mut hash = Blake2s256::new();
+        for key in keys {
+            hash.update(key.as_bytes());
+            hash.update(b"\n");
+        }
+        Some(to_hex(&hash.finalize()))
+    }
+
+    //A streamed part can only be sent once, so every attempt gets a new form
+    //reading the already open files from the start
+    pub fn form(&self, progress: &Progress, bandwidth: &Bandwidth) -> Form {
+        let payload = serde_json::to_string(&self.payload).unwrap();
+        let mut form = Form::new().text("payload", payload);
+
+        for part in &self.parts {
+            let file = part.file.try_clone().and_then(|mut file| {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(file)
+            });
+            //The part stays so the link holds, the server will find the content doesn't match
+            let body = match file {
+                Ok(file) => {
+                    let progress = progress.clone();
+                    let progress_path = part.local.clone();
+                    let stream = FramedRead::new(tokio::fs::File::from_std(file), BytesCodec::new())
+                        .map(|chunk| chunk.map(|bytes| bytes.freeze()));
+                    let stream = bandwidth.throttle(Direction::Upload, stream)
+                        .inspect(move |chunk| {
+                            if let Ok(bytes) = chunk {
+                                progress.emit(ProgressEvent::BytesUploaded {
+                                    path: progress_path.clone(),
+                                    bytes: bytes.len() as u64
+                                });
+                            }
+                        });
+                    reqwest::Body::wrap_stream(stream)
+                }
+                Err(e) => {
+                    tracing::warn!("could not reread {}: {}", part.local.display(), e);
+                    reqwest::Body::from(Vec::new())
+                }
+            };
+
+            let file_part = Part::stream(body)
+                .file_name(part.file_name.clone())
+                .mime_str(part.mime)
+                .unwrap();
+            form = form.part(part.id.clone(), file_part);
+        }
+
+        form
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn dto(path: &str, key: Option<&str>) -> FileEntryDTO {
+        FileEntryDTO {
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            file_path: path.to_string(),
+            file_hash: Some("hash".to_string()),
+            file_size: 4,
+            modified_time: 1,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: key.map(str::to_string)
+        }
+    }
+
+    //Bodies for the given paths, backed by files in dir
+    fn bodies(dir: &std::path::Path, paths: &[&str]) -> Bodies {
+        let mut bodies = Bodies::default();
+        for (i, path) in paths.iter().enumerate() {
+            let file = dir.join(format!("body-{}", i));
+            std::fs::write(&file, b"data").unwrap();
+            bodies.local(path.to_string(), file);
+        }
+        bodies
+    }
+
+    fn entries(request: &SyncRequest) -> Vec<(Operations, &FileEntryDTO)> {
+        request.payload.iter().flat_map(|(op, dtos)| dtos.iter().map(|dto| (*op, dto))).collect()
+    }
+
+    #[test]
+    fn every_part_is_referenced_by_exactly_one_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut resumed = dto("/home/me/docs/big.iso", None);
+        resumed.upload_id = Some("upload-1".to_string());
+        let operations: SyncBatch = HashMap::from([
+            (Operations::Insert, vec![dto("/home/me/docs/a.txt", None), dto("/home/me/a.txt", None), resumed]),
+            (Operations::Update, vec![dto("/home/me/docs/b.txt", None)]),
+            (Operations::Delete, vec![dto("/home/me/docs/c.txt", None)])
+        ]);
+        let bodies = bodies(dir.path(), &["/home/me/docs/a.txt", "/home/me/a.txt", "/home/me/docs/b.txt"]);
+
+        let (request, skipped) = SyncRequest::build(&operations, &bodies);
+        assert!(skipped.is_empty());
+
+        let ids: HashSet<&str> = request.parts.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids.len(), request.parts.len(), "part ids are unique");
+
+        let mut referenced = HashSet::new();
+        for (op, dto) in entries(&request) {
+            match op {
+                Operations::Delete => assert!(dto.part.is_none() && dto.upload_id.is_none()),
+                _ => assert!(dto.part.is_some() != dto.upload_id.is_some(), "{} needs exactly one of part and upload_id", dto.file_path)
+            }
+            if let Some(part) = &dto.part {
+                assert!(ids.contains(part.as_str()), "{} references a missing part", dto.file_path);
+                assert!(referenced.insert(part.clone()), "{} is referenced twice", part);
+            }
+        }
+        assert_eq!(referenced.len(), ids.len(), "every part is referenced");
+    }
+
+    #[test]
+    fn parts_are_named_by_id_and_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let operations: SyncBatch = HashMap::from([
+            (Operations::Insert, vec![dto("/home/me/docs/a.txt", None), dto("/home/me/a.txt", None)])
+        ]);
+        let bodies = bodies(dir.path(), &["/home/me/docs/a.txt", "/home/me/a.txt"]);
+
+        let (request, _) = SyncRequest::build(&operations, &bodies);
+        let names: Vec<(&str, &str)> = request.parts.iter().map(|p| (p.id.as_str(), p.file_name.as_str())).collect();
+        assert_eq!(names, vec![("part-0", "a.txt"), ("part-1", "a.txt")]);
+        //Same file name in two directories still links by part id
+        for (_, dto) in entries(&request) {
+            let part = request.parts.iter().find(|p| Some(&p.id) == dto.part.as_ref()).unwrap();
+            assert_eq!(part.local, bodies.get(&dto.file_path).unwrap().local);
+        }
+    }
+
+    #[test]
+    fn unreadable_entries_are_skipped_not_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let operations: SyncBatch = HashMap::from([
+            (Operations::Insert, vec![dto("/a.txt", None), dto("/gone.txt", None), dto("/unprepared.txt", None)])
+        ]);
+        let mut bodies = bodies(dir.path(), &["/a.txt"]);
+        bodies.local("/gone.txt".to_string(), dir.path().join("missing"));
+
+        let (request, skipped) = SyncRequest::build(&operations, &bodies);
+        let mut skipped: Vec<&str> = skipped.iter().map(|(_, dto)| dto.file_path.as_str()).collect();
+        skipped.sort();
+        assert_eq!(skipped, vec!["/gone.txt", "/unprepared.txt"]);
+
+        let sent: Vec<&str> = entries(&request).iter().map(|(_, dto)| dto.file_path.as_str()).collect();
+        assert_eq!(sent, vec!["/a.txt"]);
+        assert_eq!(request.parts.len(), 1);
+        assert_eq!(entries(&request)[0].1.part.as_deref(), Some("part-0"));
+    }
+
+    #[test]
+    fn all_skipped_leaves_an_empty_request() {
+        let operations: SyncBatch = HashMap::from([(Operations::Update, vec![dto("/a.txt", None)])]);
+        let (request, skipped) = SyncRequest::build(&operations, &Bodies::default());
+        assert!(request.is_empty());
+        assert_eq!(skipped.len(), 1);
+    }
+
+    #[test]
+    fn idempotency_key_ignores_order_and_needs_every_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let bodies = bodies(dir.path(), &["/a.txt", "/b.txt"]);
+        let one: SyncBatch = HashMap::from([(Operations::Insert, vec![dto("/a.txt", Some("k1")), dto("/b.txt", Some("k2"))])]);
+        let other: SyncBatch = HashMap::from([(Operations::Insert, vec![dto("/b.txt", Some("k2")), dto("/a.txt", Some("k1"))])]);
+        let changed: SyncBatch = HashMap::from([(Operations::Insert, vec![dto("/a.txt", Some("k1")), dto("/b.txt", Some("k3"))])]);
+        let unkeyed: SyncBatch = HashMap::from([(Operations::Insert, vec![dto("/a.txt", Some("k1")), dto("/b.txt", None)])]);
+
+        let key = |batch: &SyncBatch| SyncRequest::build(batch, &bodies).0.idempotency_key();
+        assert!(key(&one).is_some());
+        assert_eq!(key(&one), key(&other));
+        assert_ne!(key(&one), key(&changed));
+        assert_eq!(key(&unkeyed), None);
+    }
+}
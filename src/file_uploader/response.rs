@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+use crate::file_uploader::{batch::SyncBatch, file_upload::{Operations, RemoteConflict}};
+
+//Newest schema this client understands. A newer server may add fields, the
+//ones below keep their meaning.
+pub const SYNC_RESPONSE_VERSION: u32 = 1;
+
+//Body of a successful POST /sync:
+//  {"version": 1, "results": [ItemResult]}
+//with one result per payload entry. A 200 only means the request was
+//processed, each item says what happened to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncResponse {
+    pub version: u32,
+    pub results: Vec<ItemResult>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemStatus {
+    Ok,
+    //The server has a different version than base_hash, see the remote fields
+    Conflict,
+    //Refused for good, e.g. a name the server can't store. Not retried
+    Rejected,
+    //Retried once there is room again
+    QuotaExceeded,
+    //Set by this client, never sent by the server: the content the server
+    //stored isn't what was hashed, or the file changed while it was sent
+    #[serde(skip_deserializing)]
+    Mismatch,
+    //A status from a newer server this client can't act on, retried until
+    //the server says something it understands
+    #[serde(other, skip_serializing)]
+    Unknown
+}
+
+impl ItemStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemStatus::Ok => "synced",
+            ItemStatus::Conflict => "conflict",
+            ItemStatus::Rejected => "rejected",
+            ItemStatus::QuotaExceeded => "quota_exceeded",
+            ItemStatus::Mismatch => "mismatch",
+            ItemStatus::Unknown => "unknown"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemResult {
+    pub file_path: String,
+    pub operation: Operations,
+    pub status: ItemStatus,
+    //What the server holds for the path now, for a conflict that is the other
+    //device's version. No hash after a delete
+    #[serde(default)]
+    pub server_hash: Option<String>,
+    #[serde(default)]
+    pub server_size: Option<i64>,
+    #[serde(default)]
+    pub version_id: Option<String>,
+    //blake2s of the bytes the server stored, after undoing content_encoding.
+    //For an encrypted body that is the ciphertext, not file_hash
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    #[serde(default)]
+    pub modified_time: Option<i64>,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>
+}
+
+impl ItemResult {
+    pub fn to_conflict(&self) -> RemoteConflict {
+        RemoteConflict {
+            file_path: self.file_path.clone(),
+            remote_hash: self.server_hash.clone(),
+            remote_size: self.server_size,
+            remote_modified_time: self.modified_time,
+            device_id: self.device_id.clone()
+        }
+    }
+}
+
+impl SyncResponse {
+    //Servers from before the schema answer with something that isn't a JSON
+    //object, every item of the batch is taken as stored then. A JSON body
+    //that doesn't parse, e.g. one cut off on the way, confirms nothing and
+    //the batch is sent again.
+    pub fn parse(body: &str, batch: &SyncBatch) -> Result<Self, String> {
+        if !body.trim_start().starts_with('{') {
+            return Ok(Self::all_ok(batch));
+        }
+        let response = serde_json::from_str::<SyncResponse>(body)
+            .map_err(|e| format!("unreadable sync response: {}", e))?;
+        if response.version > SYNC_RESPONSE_VERSION {
+            tracing::warn!("server answered with sync response version {}, this client knows {}", response.version, SYNC_RESPONSE_VERSION);
+        }
+        Ok(response)
+    }
+
+    pub fn all_ok(batch: &SyncBatch) -> Self {
+        let results = batch
+            .iter()
+            .flat_map(|(op, entries)| entries.iter().map(move |dto| ItemResult {
+                file_path: dto.file_path.clone(),
+                operation: *op,
+                status: ItemStatus::Ok,
+                server_hash: dto.file_hash.clone().filter(|_| *op != Operations::Delete),
+                server_size: Some(dto.file_size),
+                version_id: None,
+                content_hash: None,
+                modified_time: Some(dto.modified_time),
+                device_id: None,
+                message: None
+            }))
+            .collect();
+        Self { version: 0, results }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::file_uploader::file_upload::FileEntryDTO;
+
+    fn batch() -> SyncBatch {
+        HashMap::from([(Operations::Insert, vec![FileEntryDTO {
+            file_name: "a.txt".to_string(),
+            file_path: "/a.txt".to_string(),
+            file_hash: Some("hash".to_string()),
+            file_size: 1,
+            modified_time: 1,
+            upload_id: None,
+            base_hash: None,
+            content_encoding: None,
+            compressed_size: None,
+            encryption: None,
+            part: None,
+            idempotency_key: None
+        }])])
+    }
+
+    #[test]
+    fn a_body_from_before_the_schema_stores_everything() {
+        let response = SyncResponse::parse("OK", &batch()).unwrap();
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(response.results[0].status, ItemStatus::Ok);
+        assert_eq!(response.results[0].server_hash.as_deref(), Some("hash"));
+    }
+
+    #[test]
+    fn a_cut_off_body_confirms_nothing() {
+        let body = r#"{"version": 1, "results": [{"file_path": "/a.txt", "operation": "insert", "sta"#;
+        assert!(SyncResponse::parse(body, &batch()).is_err());
+    }
+
+    #[test]
+    fn an_unknown_status_is_not_taken_as_stored() {
+        let body = r#"{"version": 2, "results": [{"file_path": "/a.txt", "operation": "insert", "status": "deferred"}]}"#;
+        let response = SyncResponse::parse(body, &batch()).unwrap();
+        assert_eq!(response.results[0].status, ItemStatus::Unknown);
+    }
+}
@@ -0,0 +1,294 @@
+use std::time::{Duration, SystemTime};
+
+use reqwest::{Response, StatusCode, header::RETRY_AFTER};
+use reqwest_middleware::RequestBuilder;
+
+use crate::config::settings::RetryConfig;
+
+#[derive(Debug)]
+pub enum RetryError {
+    //The server answered with a status that retrying won't change
+    Status(StatusCode, String),
+    //Every attempt failed, holds the last reason
+    Exhausted(String),
+    //The last attempt got no answer at all, the server is down or the network is
+    Unreachable(String),
+    //The proxy in front of the server failed or refused, the server was never asked
+    Proxy(String)
+}
+
+//Local IO failures around a request are not worth retrying either
+impl From<std::io::Error> for RetryError {
+    fn from(e: std::io::Error) -> Self {
+        RetryError::Exhausted(e.to_string())
+    }
+}
+
+impl std::fmt::Display for RetryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetryError::Status(status, body) => write!(f, "server answered {}: {}", status, body),
+            RetryError::Exhausted(reason) => write!(f, "gave up after retries: {}", reason),
+            RetryError::Unreachable(reason) => write!(f, "server unreachable: {}", reason),
+            RetryError::Proxy(reason) => write!(f, "proxy failed: {}", reason)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    //The proxy requests go through, to tell its failures from the server's
+    proxy: Option<String>
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig, proxy: Option<String>) -> Self {
+        Self {
+            max_attempts: config.max_attempts.max(1),
+            base_delay: Duration::from_millis(config.base_delay_ms),
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            proxy
+        }
+    }
+
+    //With a proxy every connection is made to it, so failing to connect is
+    //the proxy failing: refused, not resolved, or a CONNECT or SOCKS request
+    //it turned down. The TLS handshake with the server at the far end of a
+    //tunnel is part of connecting too, but those errors are the server's.
+    fn proxy_failure(&self, error: &reqwest_middleware::Error) -> bool {
+        let reqwest_middleware::Error::Reqwest(error) = error else {
+            return false;
+        };
+        self.proxy.is_some() && error.is_connect() && !sources(error).any(is_tls)
+    }
+
+    //Full jitter: a random delay between zero and the exponential ceiling,
+    //so clients that failed together don't come back together
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        ceiling.mul_f64(fastrand::f64())
+    }
+
+    fn is_retryable(status: StatusCode) -> bool {
+        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+    }
+
+    //Streaming bodies can't be cloned, so the request is rebuilt by the
+    //caller for every attempt instead of being replayed by middleware
+    pub async fn send<F, Fut>(&self, mut build: F) -> Result<Response, RetryError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = RequestBuilder>
+    {
+        let mut last_error = String::new();
+        let mut unreachable = false;
+        let mut proxy_failed = false;
+
+        for attempt in 0..self.max_attempts {
+            let wait = match build().await.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                //Only a plain http proxy answers for itself, tunnels fail in connect
+                Ok(response) if response.status() == StatusCode::PROXY_AUTHENTICATION_REQUIRED => {
+                    let proxy = self.proxy.as_deref().unwrap_or("proxy");
+                    return Err(RetryError::Proxy(format!("{}: authentication required, check proxy.username and the password", proxy)));
+                }
+                Ok(response) if Self::is_retryable(response.status()) => {
+                    last_error = format!("server answered {}", response.status());
+                    unreachable = false;
+                    proxy_failed = false;
+                    retry_after(&response)
+                        .map(|d| d.min(self.max_delay))
+                        .unwrap_or_else(|| self.backoff(attempt))
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(RetryError::Status(status, body));
+                }
+                //Connection refused, reset, timeouts and the like
+                Err(e) => {
+                    last_error = error_chain(&e);
+                    proxy_failed = self.proxy_failure(&e);
+                    if proxy_failed && let Some(proxy) = &self.proxy {
+                        last_error = format!("{}: {}", proxy, last_error);
+                    }
+                    unreachable = true;
+                    self.backoff(attempt)
+                }
+            };
+
+            if attempt + 1 < self.max_attempts {
+                tracing::warn!("attempt {} failed ({}), retrying in {:?}", attempt + 1, last_error, wait);
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        match (proxy_failed, unreachable) {
+            (true, _) => Err(RetryError::Proxy(last_error)),
+            (false, true) => Err(RetryError::Unreachable(last_error)),
+            (false, false) => Err(RetryError::Exhausted(last_error))
+        }
+    }
+}
+
+fn sources<'a>(error: &'a (dyn std::error::Error + 'static)) -> impl Iterator<Item = &'a (dyn std::error::Error + 'static)> {
+    std::iter::successors(Some(error), |e| e.source())
+}
+
+//tokio-rustls hands handshake failures up as io errors around the rustls one
+fn is_tls(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.is::<rustls::Error>()
+        || error.downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|inner| inner.is::<rustls::Error>())
+}
+
+//reqwest's own message is just "error sending request", the cause is further down
+fn error_chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message = format!("{}: {}", message, cause_message);
+        }
+        source = cause.source();
+    }
+    message
+}
+
+//Retry-After is either a number of seconds or an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+    use crate::{backend::http::client, config::settings::Config};
+
+    //A proxy at url, or none at all, and a single attempt
+    async fn send(proxy: Option<String>, target: &str) -> RetryError {
+        let mut config = Config::default();
+        config.proxy.url = proxy;
+        config.proxy.from_env = false;
+        config.retry.max_attempts = 1;
+        let (client, proxy) = client(&config, target);
+        let policy = RetryPolicy::new(&config.retry, proxy);
+        policy.send(|| {
+            let request = client.get(target);
+            async move { request }
+        }).await.unwrap_err()
+    }
+
+    //Nothing listens there once the listener is dropped
+    async fn closed_port() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("127.0.0.1:{}", listener.local_addr().unwrap().port())
+    }
+
+    //Answers every connection with the given bytes once the request head is
+    //in. Whatever comes next through an open tunnel gets then as its answer.
+    async fn fake_proxy(answer: &'static [u8], then: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n])
+                        }
+                    }
+                    let _ = socket.write_all(answer).await;
+                    if !then.is_empty() && socket.read(&mut buf).await.is_ok_and(|n| n > 0) {
+                        let _ = socket.write_all(then).await;
+                    }
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    //Accepts the greeting and refuses every CONNECT, as a SOCKS5 proxy that
+    //can't reach the target does
+    async fn refusing_socks() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 512];
+                    let _ = socket.read(&mut buf).await;
+                    let _ = socket.write_all(&[5, 0]).await;
+                    let _ = socket.read(&mut buf).await;
+                    let _ = socket.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+                });
+            }
+        });
+        format!("socks5h://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn unreachable_proxy_is_the_proxy() {
+        let error = send(Some(format!("http://{}", closed_port().await)), "http://files.example/handshake").await;
+        assert!(matches!(error, RetryError::Proxy(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn refused_tunnel_is_the_proxy() {
+        let proxy = fake_proxy(b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n", b"").await;
+        let error = send(Some(proxy), "https://files.example/handshake").await;
+        assert!(matches!(error, RetryError::Proxy(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn refused_socks_connect_is_the_proxy() {
+        let error = send(Some(refusing_socks().await), "http://files.example/handshake").await;
+        assert!(matches!(error, RetryError::Proxy(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn tls_failure_inside_the_tunnel_is_the_server() {
+        let proxy = fake_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n", b"this is not a TLS server\r\n").await;
+        let error = send(Some(proxy), "https://files.example/handshake").await;
+        assert!(matches!(error, RetryError::Unreachable(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn proxy_authentication_is_the_proxy() {
+        let proxy = fake_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\ncontent-length: 0\r\n\r\n", b"").await;
+        let error = send(Some(proxy), "http://files.example/handshake").await;
+        assert!(matches!(error, RetryError::Proxy(ref e) if e.contains("authentication required")), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn refused_connection_without_a_proxy_is_the_server() {
+        let error = send(None, &format!("http://{}/handshake", closed_port().await)).await;
+        assert!(matches!(error, RetryError::Unreachable(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn backoff_stays_under_the_ceiling() {
+        let policy = RetryPolicy::new(&RetryConfig { max_attempts: 5, base_delay_ms: 100, max_delay_ms: 1000 }, None);
+        for attempt in 0..20 {
+            assert!(policy.backoff(attempt) <= Duration::from_millis(1000));
+        }
+    }
+}
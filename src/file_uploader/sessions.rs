@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+use sqlite::{Connection, State};
+
+use crate::db_listener::db::{BUSY_TIMEOUT_MS, INDEX_PATH};
+
+//A resumable upload the server knows about. Kept in the index database so an
+//interrupted upload continues where it stopped, even after a restart.
+#[derive(Debug, Clone)]
+pub struct UploadSession {
+    pub file_path: String,
+    pub file_hash: String,
+    pub file_size: u64,
+    pub upload_id: String,
+    pub offset: u64
+}
+
+pub struct SessionStore {
+    conn: Mutex<Connection>
+}
+
+impl SessionStore {
+    pub fn open() -> Self {
+        let mut conn = sqlite::open(INDEX_PATH).unwrap();
+        conn.set_busy_timeout(BUSY_TIMEOUT_MS).unwrap();
+        conn.execute("
+            CREATE TABLE IF NOT EXISTS upload_sessions (filepath TEXT PRIMARY KEY, filehash TEXT, size INTEGER, upload_id TEXT, offset INTEGER);
+        ").unwrap();
+        Self { conn: Mutex::new(conn) }
+    }
+
+    //Only returns a session for the same content, a changed file starts over
+    pub fn get(&self, file_path: &str, file_hash: &str, file_size: u64) -> sqlite::Result<Option<UploadSession>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT upload_id, offset FROM upload_sessions WHERE filepath = ? AND filehash = ? AND size = ?"
+        )?;
+        stmt.bind((1, file_path))?;
+        stmt.bind((2, file_hash))?;
+        stmt.bind((3, file_size as i64))?;
+
+        if let Ok(State::Row) = stmt.next() {
+            let upload_id: String = stmt.read(0)?;
+            let offset: i64 = stmt.read(1)?;
+            return Ok(Some(UploadSession {
+                file_path: file_path.to_string(),
+                file_hash: file_hash.to_string(),
+                file_size,
+                upload_id,
+                offset: offset as u64
+            }));
+        }
+        Ok(None)
+    }
+
+    pub fn save(&self, session: &UploadSession) -> sqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "INSERT OR REPLACE INTO upload_sessions (filepath, filehash, size, upload_id, offset) VALUES (?, ?, ?, ?, ?)"
+        )?;
+        stmt.bind((1, session.file_path.as_str()))?;
+        stmt.bind((2, session.file_hash.as_str()))?;
+        stmt.bind((3, session.file_size as i64))?;
+        stmt.bind((4, session.upload_id.as_str()))?;
+        stmt.bind((5, session.offset as i64))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, file_path: &str) -> sqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("DELETE FROM upload_sessions WHERE filepath = ?")?;
+        stmt.bind((1, file_path))?;
+        stmt.next()?;
+        Ok(())
+    }
+}
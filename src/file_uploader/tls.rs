@@ -0,0 +1,350 @@
+use std::{fs, path::Path, sync::Arc};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}
+    },
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject}
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::settings::TlsConfig;
+
+const PIN_PREFIX: &str = "sha256/";
+
+//reqwest is built without a crypto provider of its own, so every client gets
+//this, the bundled roots alone when nothing is configured. Anything wrong in
+//the configured files is fatal, a client that quietly falls back to weaker
+//checks is worse than one that doesn't start.
+pub fn client_config(config: &TlsConfig) -> ClientConfig {
+    let provider = Arc::new(ring::default_provider());
+
+    let inner: Arc<dyn ServerCertVerifier> = if config.insecure {
+        tracing::warn!("certificate verification is OFF (tls.insecure), anyone on the network can read and change what is synced");
+        tracing::warn!("never use tls.insecure outside of development");
+        Arc::new(AcceptAnyCertificate { provider: provider.clone() })
+    } else {
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots(config)), provider.clone())
+            .build()
+            .unwrap_or_else(|e| panic!("tls: can't build certificate verifier: {}", e))
+    };
+    let verifier: Arc<dyn ServerCertVerifier> = if config.pins.is_empty() {
+        inner
+    } else {
+        Arc::new(PinnedVerifier { inner, pins: config.pins.iter().map(|p| parse_pin(p)).collect() })
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+
+    let mut tls = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs(cert), read_key(key))
+            .unwrap_or_else(|e| panic!("tls: client certificate {} doesn't fit its key: {}", cert.display(), e)),
+        (None, None) => builder.with_no_client_auth(),
+        _ => panic!("tls: client_cert and client_key go together")
+    };
+    tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    tls
+}
+
+//The pin for a PEM certificate, in the form tls.pins expects
+pub fn pin_of(path: &Path) -> String {
+    let certs = read_certs(path);
+    format!("{}{}", PIN_PREFIX, STANDARD.encode(spki_sha256(&certs[0]).unwrap()))
+}
+
+fn roots(config: &TlsConfig) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    if !config.ca_only {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    if let Some(ca_file) = &config.ca_file {
+        let (added, ignored) = roots.add_parsable_certificates(read_certs(ca_file));
+        if added == 0 {
+            panic!("tls: no usable certificate in {}", ca_file.display());
+        }
+        if ignored > 0 {
+            tracing::warn!("skipped {} unusable certificates in {}", ignored, ca_file.display());
+        }
+    } else if config.ca_only {
+        panic!("tls: ca_only needs a ca_file");
+    }
+    roots
+}
+
+fn read_certs(path: &Path) -> Vec<CertificateDer<'static>> {
+    let pem = fs::read(path).unwrap_or_else(|e| panic!("tls: can't read {}: {}", path.display(), e));
+    let certs: Vec<_> = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| panic!("tls: invalid PEM in {}: {}", path.display(), e));
+    if certs.is_empty() {
+        panic!("tls: no certificate in {}", path.display());
+    }
+    certs
+}
+
+fn read_key(path: &Path) -> PrivateKeyDer<'static> {
+    PrivateKeyDer::from_pem_file(path)
+        .unwrap_or_else(|e| panic!("tls: no usable private key in {}: {}", path.display(), e))
+}
+
+fn parse_pin(pin: &str) -> [u8; 32] {
+    pin.strip_prefix(PIN_PREFIX)
+        .and_then(|b64| STANDARD.decode(b64).ok())
+        .and_then(|hash| hash.try_into().ok())
+        .unwrap_or_else(|| panic!("tls: pin {:?} isn't sha256/<base64 of 32 bytes>", pin))
+}
+
+fn spki_sha256(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(Sha256::digest(cert.subject_public_key_info().as_ref()).into())
+}
+
+//Runs the usual checks first, then requires the leaf key to be a pinned one.
+//Pinning the key rather than the certificate survives renewals that keep it.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<[u8; 32]>
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        match spki_sha256(end_entity) {
+            Some(hash) if self.pins.contains(&hash) => Ok(verified),
+            hash => {
+                let seen = hash.map(|h| format!("{}{}", PIN_PREFIX, STANDARD.encode(h))).unwrap_or_default();
+                tracing::warn!("{:?} presented key {} which isn't pinned", server_name, seen);
+                Err(rustls::Error::General(format!("server key {} doesn't match any pin", seen)))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+//tls.insecure: any chain for any name. The handshake signatures are still
+//checked, so the peer at least holds the key of what it presented.
+#[derive(Debug)]
+struct AcceptAnyCertificate {
+    provider: Arc<CryptoProvider>
+}
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::{ClientConnection, Connection, ServerConfig, ServerConnection, server::WebPkiClientVerifier};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair
+    }
+
+    impl Ca {
+        fn new(name: &str) -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            Self { cert: params.self_signed(&key).unwrap(), key }
+        }
+
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (Certificate, KeyPair) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &self.cert, &self.key).unwrap(), key)
+        }
+    }
+
+    fn write(dir: &TempDir, name: &str, pem: String) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, pem).unwrap();
+        path
+    }
+
+    fn server(cert: &Certificate, key: &KeyPair, client_ca: Option<&Ca>) -> ServerConfig {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(ca.cert.der().clone()).unwrap();
+                builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build().unwrap())
+            }
+            None => builder.with_no_client_auth()
+        };
+        let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+        builder.with_single_cert(vec![cert.der().clone()], key).unwrap()
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        let mut pending = &buf[..];
+        while !pending.is_empty() {
+            to.read_tls(&mut pending).unwrap();
+            to.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    //Runs a whole handshake in memory, the server side comes back for a look
+    //at what the client presented
+    fn handshake(client: ClientConfig, server: ServerConfig) -> Result<ServerConnection, rustls::Error> {
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = Connection::Client(ClientConnection::new(Arc::new(client), name).unwrap());
+        let mut server = Connection::Server(ServerConnection::new(Arc::new(server)).unwrap());
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client)?;
+        }
+        match server {
+            Connection::Server(server) => Ok(server),
+            Connection::Client(_) => unreachable!()
+        }
+    }
+
+    fn trusting(dir: &TempDir, ca: &Ca) -> TlsConfig {
+        TlsConfig {
+            ca_file: Some(write(dir, "ca.pem", ca.cert.pem())),
+            ca_only: true,
+            ..TlsConfig::default()
+        }
+    }
+
+    #[test]
+    fn private_ca_is_accepted() {
+        let (dir, ca) = (TempDir::new().unwrap(), Ca::new("home CA"));
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        handshake(client_config(&trusting(&dir, &ca)), server(&cert, &key, None)).unwrap();
+    }
+
+    #[test]
+    fn unknown_ca_is_rejected() {
+        let (dir, ca, other) = (TempDir::new().unwrap(), Ca::new("home CA"), Ca::new("someone else"));
+        let (cert, key) = other.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let error = handshake(client_config(&trusting(&dir, &ca)), server(&cert, &key, None)).unwrap_err();
+        assert!(matches!(error, rustls::Error::InvalidCertificate(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn bundled_roots_dont_cover_a_private_ca() {
+        let ca = Ca::new("home CA");
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        assert!(handshake(client_config(&TlsConfig::default()), server(&cert, &key, None)).is_err());
+    }
+
+    #[test]
+    fn matching_pin_is_accepted() {
+        let (dir, ca) = (TempDir::new().unwrap(), Ca::new("home CA"));
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let config = TlsConfig {
+            pins: vec![pin_of(&write(&dir, "server.pem", cert.pem()))],
+            ..trusting(&dir, &ca)
+        };
+        handshake(client_config(&config), server(&cert, &key, None)).unwrap();
+    }
+
+    #[test]
+    fn mismatched_pin_is_rejected() {
+        let (dir, ca) = (TempDir::new().unwrap(), Ca::new("home CA"));
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let (renewed, _) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let config = TlsConfig {
+            pins: vec![pin_of(&write(&dir, "renewed.pem", renewed.pem()))],
+            ..trusting(&dir, &ca)
+        };
+        let error = handshake(client_config(&config), server(&cert, &key, None)).unwrap_err();
+        assert!(matches!(error, rustls::Error::General(ref e) if e.contains("doesn't match any pin")), "{:?}", error);
+    }
+
+    #[test]
+    fn client_certificate_is_presented() {
+        let (dir, ca) = (TempDir::new().unwrap(), Ca::new("home CA"));
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let (client_cert, client_key) = ca.issue("laptop", ExtendedKeyUsagePurpose::ClientAuth);
+        let config = TlsConfig {
+            client_cert: Some(write(&dir, "client.pem", client_cert.pem())),
+            client_key: Some(write(&dir, "client.key", client_key.serialize_pem())),
+            ..trusting(&dir, &ca)
+        };
+
+        let server = handshake(client_config(&config), server(&cert, &key, Some(&ca))).unwrap();
+        assert_eq!(server.peer_certificates().unwrap()[0], *client_cert.der());
+    }
+
+    #[test]
+    fn missing_client_certificate_is_refused_by_the_server() {
+        let (dir, ca) = (TempDir::new().unwrap(), Ca::new("home CA"));
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        assert!(handshake(client_config(&trusting(&dir, &ca)), server(&cert, &key, Some(&ca))).is_err());
+    }
+
+    #[test]
+    fn insecure_accepts_any_certificate() {
+        let stranger = Ca::new("someone else");
+        let (cert, key) = stranger.issue("not-localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let config = TlsConfig { insecure: true, ..TlsConfig::default() };
+        handshake(client_config(&config), server(&cert, &key, None)).unwrap();
+    }
+}
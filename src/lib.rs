@@ -3,3 +3,11 @@ pub mod file_uploader;
 pub mod event_listener;
 pub mod file_hasher;
 pub mod db_listener;
+pub mod progress;
+pub mod config;
+pub mod throttle;
+pub mod duplicates;
+pub mod crypto;
+pub mod backend;
+pub mod server;
+pub mod logging;
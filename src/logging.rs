@@ -0,0 +1,11 @@
+use tracing_subscriber::EnvFilter;
+
+//Log lines go to stderr, the json progress stream has stdout. RUST_LOG picks
+//what is shown, e.g. RUST_LOG=pocket_drive=debug, info and up by default.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
@@ -1,23 +1,104 @@
-use std::env ;
+use std::{env, path::Path, time::Duration};
 
-use pocket_drive::{db_listener::db::Db, event_listener::listener::EventListener, file_hasher::hasher::Hasher, file_uploader::file_upload::FileUploader, file_watcher::watcher::NotifyHandler};
+use pocket_drive::{backend::{http::HttpBackend, local_dir::LocalDirBackend, remote::RemoteBackend, s3::S3Backend}, config::settings::{BackendKind, Config}, db_listener::{db::{Db, DbCmd}, status}, duplicates, event_listener::{listener::EventListener, self_writes::SelfWrites}, file_hasher::hasher::Hasher, file_uploader::{file_upload::{FileUploader, FileUploaderCmd, UploaderStatus}, tls}, file_watcher::watcher::NotifyHandler, logging, progress::{events::Progress, renderer::{render, renderer_for}}, throttle::bandwidth::Bandwidth};
+use tokio::sync::{mpsc, watch};
 
 #[tokio::main]
 async fn main() {
 
     let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("duplicates") => return duplicates::command::run(&args[2..]),
+        Some("status") => return status::run(),
+        //Prints the tls.pins entry for a PEM certificate
+        Some("pin") => return println!("{}", tls::pin_of(std::path::Path::new(args.get(2).expect("usage: pocket-drive pin <cert.pem>")))),
+        _ => {}
+    }
+    logging::init();
     let path = &args[1];
+    let config = Config::load();
+
+    //Every stage reports into the same channel, the renderer picked in config draws it
+    let progress = Progress::new();
+    tokio::spawn(render(progress.subscribe(), renderer_for(config.progress)));
 
     let mut watcher = NotifyHandler::new(); 
-    let hasher = Hasher::new();
-    let uploader = FileUploader::new();
+    let hasher = Hasher::new(progress.clone(), config.hasher.clone());
+    //Downloads register the paths they write so the listener doesn't echo them back
+    let self_writes = SelfWrites::new();
+    //The uploader is generic over where files are kept, only its channels leave this block
+    let bandwidth = Bandwidth::new(&config.bandwidth);
+    let (uploader_tx, uploader_status) = match config.backend.kind {
+        BackendKind::Http => {
+            let backend = HttpBackend::new(&config, progress.clone(), bandwidth.clone());
+            start_uploader(backend, progress.clone(), &config, Path::new(path), self_writes.clone(), bandwidth)
+        }
+        BackendKind::Local => {
+            let root = config.backend.local.root.clone().expect("backend.local.root is required for the local backend");
+            let backend = LocalDirBackend::new(root, progress.clone());
+            start_uploader(backend, progress.clone(), &config, Path::new(path), self_writes.clone(), bandwidth)
+        }
+        BackendKind::S3 => {
+            let backend = S3Backend::new(&config, progress.clone(), bandwidth.clone());
+            start_uploader(backend, progress.clone(), &config, Path::new(path), self_writes.clone(), bandwidth)
+        }
+    };
 
-    let db = Db::new(hasher.get_sender(), uploader.get_sender());
-    let listener = EventListener::new(db.get_sender());
+    let db = Db::new(hasher.get_sender(), uploader_tx.clone(), uploader_status.clone(), &config);
+    let listener = EventListener::new(db.get_sender(), self_writes);
     let sender = listener.sender();
 
+    if config.sync.pull_interval_secs > 0 {
+        let db_tx = db.get_sender();
+        let period = Duration::from_secs(config.sync.pull_interval_secs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if db_tx.send(DbCmd::PullRemote).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    //Whatever queued up while offline or unauthenticated goes out once the
+    //uploader is ready again
+    {
+        let db_tx = db.get_sender();
+        let mut status = uploader_status;
+        tokio::spawn(async move {
+            while status.changed().await.is_ok() {
+                let ready = *status.borrow_and_update() == UploaderStatus::Ready;
+                if ready && db_tx.send(DbCmd::FlushPending).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    //SIGHUP rereads the bandwidth section and the credentials of the config
+    //without a restart
+    #[cfg(unix)]
+    {
+        tokio::spawn(async move {
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+            while hangup.recv().await.is_some() {
+                let config = Config::load();
+                let commands = [
+                    FileUploaderCmd::SetSchedule(config.bandwidth),
+                    FileUploaderCmd::Authenticate(config.server.token, config.server.basic_auth)
+                ];
+                for cmd in commands {
+                    if uploader_tx.send(cmd).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
     watcher.watch(path).unwrap();
-    tokio::spawn(uploader.run());
     tokio::spawn(listener.run());
 
     //Start a task of file uploader which will be async since after network call there will be
@@ -55,3 +136,13 @@ async fn main() {
     tokio::signal::ctrl_c().await.unwrap();
 }
 
+fn start_uploader<B: RemoteBackend>(backend: B, progress: Progress, config: &Config, root: &Path, self_writes: SelfWrites, bandwidth: Bandwidth) -> (mpsc::Sender<FileUploaderCmd>, watch::Receiver<UploaderStatus>) {
+    //Better to stop here than to sync anything in plaintext
+    let uploader = FileUploader::new(backend, progress, config, root, self_writes, bandwidth).unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    });
+    let channels = (uploader.get_sender(), uploader.status());
+    tokio::spawn(uploader.run());
+    channels
+}
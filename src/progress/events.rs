@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchKind {
+    Hash,
+    Upload
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    ScanStarted { total_files: u64 },
+    FileHashed { path: PathBuf, done: u64, total: u64 },
+    UploadStarted { files: u64, total_bytes: u64 },
+    BytesUploaded { path: PathBuf, bytes: u64 },
+    BatchDone { kind: BatchKind, files: u64, success: bool }
+}
+
+//Cheap to clone handle which every stage of the pipeline gets a copy of.
+//Events are broadcast, so any number of renderers or library consumers can
+//subscribe. Emitting with no subscribers is not an error.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    tx: Sender<ProgressEvent>
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(4096);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> Receiver<ProgressEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        let _ = self.tx.send(event);
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
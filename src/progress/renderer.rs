@@ -0,0 +1,125 @@
+use std::io::Write;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Deserialize;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+
+use crate::progress::events::{BatchKind, ProgressEvent};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RendererKind {
+    #[default]
+    Terminal,
+    Silent,
+    Json
+}
+
+pub trait ProgressRenderer: Send {
+    fn render(&mut self, event: &ProgressEvent);
+}
+
+//Draws indicatif bars, one for hashing and one for the bytes of the current upload batch
+#[derive(Default)]
+pub struct TerminalRenderer {
+    hash_bar: Option<ProgressBar>,
+    upload_bar: Option<ProgressBar>
+}
+
+impl TerminalRenderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProgressRenderer for TerminalRenderer {
+    fn render(&mut self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::ScanStarted { total_files } => {
+                let pb = ProgressBar::new(*total_files);
+                pb.set_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
+                    )
+                    .unwrap(),
+                );
+                self.hash_bar = Some(pb);
+            }
+            ProgressEvent::FileHashed { done, .. } => {
+                if let Some(pb) = &self.hash_bar {
+                    pb.set_position(*done);
+                }
+            }
+            ProgressEvent::UploadStarted { total_bytes, .. } => {
+                let pb = ProgressBar::new(*total_bytes);
+                pb.set_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {bar:40.green/blue} {bytes}/{total_bytes} {msg}",
+                    )
+                    .unwrap(),
+                );
+                self.upload_bar = Some(pb);
+            }
+            ProgressEvent::BytesUploaded { bytes, .. } => {
+                if let Some(pb) = &self.upload_bar {
+                    pb.inc(*bytes);
+                }
+            }
+            ProgressEvent::BatchDone { kind, success, .. } => {
+                let bar = match kind {
+                    BatchKind::Hash => self.hash_bar.take(),
+                    BatchKind::Upload => self.upload_bar.take()
+                };
+                if let Some(pb) = bar {
+                    pb.finish_with_message(if *success { "done" } else { "failed" });
+                }
+            }
+        }
+    }
+}
+
+//Drops every event, for running as a background daemon
+pub struct SilentRenderer;
+
+impl ProgressRenderer for SilentRenderer {
+    fn render(&mut self, _event: &ProgressEvent) {}
+}
+
+//Writes one JSON object per line, for consumption by other tools
+pub struct JsonRenderer<W: Write + Send> {
+    out: W
+}
+
+impl<W: Write + Send> JsonRenderer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write + Send> ProgressRenderer for JsonRenderer<W> {
+    fn render(&mut self, event: &ProgressEvent) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(self.out, "{}", line);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+pub fn renderer_for(kind: RendererKind) -> Box<dyn ProgressRenderer> {
+    match kind {
+        RendererKind::Terminal => Box::new(TerminalRenderer::new()),
+        RendererKind::Silent => Box::new(SilentRenderer),
+        RendererKind::Json => Box::new(JsonRenderer::new(std::io::stdout()))
+    }
+}
+
+//Drives a renderer until every Progress handle is dropped
+pub async fn render(mut rx: Receiver<ProgressEvent>, mut renderer: Box<dyn ProgressRenderer>) {
+    loop {
+        match rx.recv().await {
+            Ok(event) => renderer.render(&event),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{fs, io::{self, Read, Write}, path::{Path, PathBuf}};
+
+use blake2::{Blake2s256, Digest};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+
+use crate::file_hasher::hasher::to_hex;
+use crate::file_uploader::compression::ZSTD;
+
+//Content lives under blobs/, named by the blake2s of the stored bytes, so
+//versions and paths with the same content share one file. Blobs are never
+//removed, every version keeps its content.
+pub struct Blobs {
+    root: PathBuf
+}
+
+//A body decoded and hashed but not yet a blob. Dropped without commit it is
+//removed again.
+pub struct Staged {
+    file: PathBuf,
+    pub hash: String,
+    pub size: u64
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.file);
+    }
+}
+
+impl Blobs {
+    pub fn open(data_dir: &Path) -> Self {
+        for dir in ["blobs", "tmp", "uploads"] {
+            fs::create_dir_all(data_dir.join(dir))
+                .unwrap_or_else(|e| panic!("server: can't create {}: {}", data_dir.join(dir).display(), e));
+        }
+        Self { root: data_dir.to_path_buf() }
+    }
+
+    pub fn path(&self, hash: &str) -> PathBuf {
+        self.root.join("blobs").join(&hash[..2.min(hash.len())]).join(hash)
+    }
+
+    pub fn temp_path(&self) -> PathBuf {
+        self.root.join("tmp").join(format!("{:016x}", fastrand::u64(..)))
+    }
+
+    //Where the bytes of a resumable upload collect until a sync uses them
+    pub fn upload_path(&self, upload_id: &str) -> PathBuf {
+        self.root.join("uploads").join(upload_id)
+    }
+
+    //Writes a request body to a temp file as it arrives
+    pub async fn receive<S, E>(&self, mut body: S) -> io::Result<PathBuf>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display
+    {
+        let path = self.temp_path();
+        let received = async {
+            let mut file = tokio::fs::File::create(&path).await?;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|e| io::Error::other(e.to_string()))?;
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await
+        }.await;
+
+        match received {
+            Ok(()) => Ok(path),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&path).await;
+                Err(e)
+            }
+        }
+    }
+
+    //Undoes the content encoding and hashes what is left. The source is
+    //consumed either way.
+    pub async fn stage(&self, source: PathBuf, encoding: Option<String>) -> io::Result<Staged> {
+        let target = self.temp_path();
+        tokio::task::spawn_blocking(move || {
+            let staged = fs::File::create(&target).and_then(|mut out| {
+                let decoded = decode(&source, &mut out, encoding.as_deref())?;
+                out.sync_all()?;
+                Ok(decoded)
+            });
+            let _ = fs::remove_file(&source);
+            let (hash, size) = staged.inspect_err(|_| { let _ = fs::remove_file(&target); })?;
+            Ok(Staged { file: target, hash, size })
+        }).await.unwrap()
+    }
+
+    //The hash stage would give, the source stays where it is
+    pub async fn hash(&self, source: PathBuf, encoding: Option<String>) -> io::Result<String> {
+        tokio::task::spawn_blocking(move || {
+            decode(&source, &mut io::sink(), encoding.as_deref()).map(|(hash, _)| hash)
+        }).await.unwrap()
+    }
+
+    //Moves the staged body to its blob, content that is already there is
+    //simply dropped
+    pub fn commit(&self, staged: &Staged) -> io::Result<()> {
+        let blob = self.path(&staged.hash);
+        if blob.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(blob.parent().unwrap())?;
+        fs::rename(&staged.file, &blob)
+    }
+}
+
+fn decode(source: &Path, out: &mut impl Write, encoding: Option<&str>) -> io::Result<(String, u64)> {
+    let file = fs::File::open(source)?;
+    let mut input: Box<dyn Read> = match encoding {
+        None => Box::new(file),
+        Some(ZSTD) => Box::new(zstd::stream::read::Decoder::new(file)?),
+        Some(other) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported content_encoding {:?}", other)))
+    };
+    let mut hash = Blake2s256::new();
+    let mut size = 0u64;
+    let mut buf = vec![0u8; 256 * 1024];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&buf[..n]);
+        out.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok((to_hex(&hash.finalize()), size))
+}
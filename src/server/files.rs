@@ -0,0 +1,136 @@
+use axum::{Json, body::Body, extract::{Query, State}, http::{HeaderMap, HeaderValue, StatusCode, header::{CONTENT_LENGTH, CONTENT_TYPE}}, response::{IntoResponse, Response}};
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+
+use crate::backend::remote::{RemoteStat, relative_key};
+use crate::file_uploader::file_upload::ChangeFeed;
+use crate::server::{ApiError, Shared, device_id, store::{StoredFile, Version}};
+
+//The single file operations HttpBackend documents, plus the history:
+//  GET /versions?path=...            -> [Version], newest first
+//  GET /files?path=...&version=...   the content of an older version
+#[derive(Deserialize)]
+pub struct FileQuery {
+    path: String,
+    version: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct PrefixQuery {
+    #[serde(default)]
+    prefix: String
+}
+
+#[derive(Deserialize)]
+pub struct CursorQuery {
+    cursor: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct Move {
+    from: String,
+    to: String
+}
+
+//GET and HEAD /files
+pub async fn get(State(server): State<Shared>, Query(query): Query<FileQuery>) -> Result<Response, ApiError> {
+    let file = match &query.version {
+        Some(version) => server.store.version(&query.path, version)?,
+        None => server.store.current(&query.path)?
+    };
+    let file = file.ok_or_else(ApiError::not_found)?;
+    let content = tokio::fs::File::open(server.blobs.path(&file.blob)).await?;
+
+    let mut response = Body::from_stream(ReaderStream::new(content)).into_response();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(file.size));
+    headers.insert("X-Modified-Time", HeaderValue::from(file.modified_time));
+    headers.insert("X-Version-Id", HeaderValue::from_str(&file.version_id).unwrap());
+    if let Some(hash) = file.hash.as_deref().and_then(|h| HeaderValue::from_str(h).ok()) {
+        headers.insert("X-File-Hash", hash);
+    }
+    Ok(response)
+}
+
+//PUT /files, the raw content with X-File-Hash and X-Modified-Time
+pub async fn put(State(server): State<Shared>, Query(query): Query<FileQuery>, headers: HeaderMap, body: Body) -> Result<Json<RemoteStat>, ApiError> {
+    relative_key(&query.path).map_err(|_| ApiError::bad_request(format!("path {:?} can't be stored", query.path)))?;
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+
+    let received = server.blobs.receive(body.into_data_stream()).await
+        .map_err(|e| ApiError::bad_request(format!("body broke off: {}", e)))?;
+    let staged = server.blobs.stage(received, None).await?;
+
+    let file = StoredFile {
+        file_name: query.path.rsplit('/').next().unwrap_or_default().to_string(),
+        path: query.path,
+        hash: header("X-File-Hash"),
+        size: 0,
+        modified_time: header("X-Modified-Time").and_then(|v| v.parse().ok()).unwrap_or(0),
+        version_id: String::new(),
+        blob: String::new(),
+        encryption: None,
+        device_id: device_id(&headers)
+    };
+    let _writer = server.writer.lock().await;
+    match server.store(file, &staged, None)? {
+        Some(stored) => Ok(Json(stored.to_stat())),
+        None => Err(ApiError(StatusCode::INSUFFICIENT_STORAGE, "the server is out of space".to_string()))
+    }
+}
+
+//DELETE /files
+pub async fn delete(State(server): State<Shared>, Query(query): Query<FileQuery>, headers: HeaderMap) -> Result<StatusCode, ApiError> {
+    let device = device_id(&headers);
+    let _writer = server.writer.lock().await;
+    let existing = server.store.current(&query.path)?.ok_or_else(ApiError::not_found)?;
+    server.store.transaction(|tx| tx.delete(&query.path, existing.modified_time, device.as_deref(), None))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+//GET /list
+pub async fn list(State(server): State<Shared>, Query(query): Query<PrefixQuery>) -> Result<Json<Vec<RemoteStat>>, ApiError> {
+    let files = server.store.list(&query.prefix)?;
+    Ok(Json(files.iter().map(StoredFile::to_stat).collect()))
+}
+
+//POST /move. Other devices see a delete and an insert, like any rename
+pub async fn rename(State(server): State<Shared>, headers: HeaderMap, Json(request): Json<Move>) -> Result<StatusCode, ApiError> {
+    relative_key(&request.to).map_err(|_| ApiError::bad_request(format!("path {:?} can't be stored", request.to)))?;
+    let device = device_id(&headers);
+    let _writer = server.writer.lock().await;
+    let existing = server.store.current(&request.from)?.ok_or_else(ApiError::not_found)?;
+
+    let moved = StoredFile {
+        file_name: request.to.rsplit('/').next().unwrap_or_default().to_string(),
+        path: request.to.clone(),
+        device_id: device.clone(),
+        ..existing.clone()
+    };
+    server.store.transaction(|tx| {
+        tx.delete(&request.from, existing.modified_time, device.as_deref(), None)?;
+        tx.put(&moved, None)
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+//GET /versions
+pub async fn versions(State(server): State<Shared>, Query(query): Query<FileQuery>) -> Result<Json<Vec<Version>>, ApiError> {
+    Ok(Json(server.store.versions(&query.path)?))
+}
+
+//GET /changes. The cursor is the sequence number of the last change
+//returned, no cursor starts from the beginning
+pub async fn changes(State(server): State<Shared>, Query(query): Query<CursorQuery>) -> Result<Json<ChangeFeed>, ApiError> {
+    let after = match query.cursor.as_deref() {
+        Some(cursor) => cursor.parse::<i64>().map_err(|_| ApiError::bad_request(format!("invalid cursor {:?}", cursor)))?,
+        None => 0
+    };
+    let changes = server.store.changes(after, server.settings.max_changes)?;
+    let cursor = changes.last().map_or(after, |(seq, _)| *seq);
+    Ok(Json(ChangeFeed {
+        cursor: cursor.to_string(),
+        changes: changes.into_iter().map(|(_, change)| change).collect()
+    }))
+}
@@ -0,0 +1,28 @@
+use axum::{Json, extract::State, http::StatusCode};
+
+use crate::file_uploader::handshake::{Feature, Hello, Limits, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, Welcome};
+use crate::server::{ApiError, Shared};
+
+//Everything this server implements
+const SERVER_FEATURES: &[Feature] = &[Feature::Compression, Feature::Resumable, Feature::Rename, Feature::ChangeFeed, Feature::Versions];
+
+//POST /handshake, see file_uploader/handshake.rs. Nothing is kept per
+//client, each request is checked on its own anyway.
+pub async fn handshake(State(server): State<Shared>, Json(hello): Json<Hello>) -> Result<Json<Welcome>, ApiError> {
+    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(ApiError(StatusCode::UPGRADE_REQUIRED, format!(
+            "protocol {} is too old, this server needs at least {}", hello.protocol_version, MIN_PROTOCOL_VERSION
+        )));
+    }
+    tracing::debug!("{} speaks protocol {} with {:?}", hello.device_id, hello.protocol_version, hello.features);
+
+    Ok(Json(Welcome {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        features: SERVER_FEATURES.to_vec(),
+        limits: Limits {
+            max_body_bytes: Some(server.settings.max_body_bytes),
+            max_batch_files: Some(server.settings.max_batch_files)
+        }
+    }))
+}
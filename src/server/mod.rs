@@ -0,0 +1,146 @@
+pub mod blobs;
+pub mod files;
+pub mod handshake;
+pub mod store;
+pub mod sync;
+pub mod uploads;
+
+use std::{io, sync::Arc};
+
+use axum::{Router, extract::{DefaultBodyLimit, Request, State}, http::{HeaderMap, StatusCode, header::AUTHORIZATION}, middleware::{self, Next}, response::{IntoResponse, Response}, routing::{get, post}};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use tokio::net::TcpListener;
+
+use crate::config::server::ServerSettings;
+use crate::server::{blobs::{Blobs, Staged}, store::{Store, StoredFile}};
+
+//The reference pocket-drive server, everything HttpBackend talks to:
+//  POST /handshake                          see handshake.rs
+//  POST /sync, /uploads and /uploads/{id}   see sync.rs and uploads.rs
+//  /files, /list, /move, /versions, /changes see files.rs
+//Content goes into a blob directory, the index into sqlite, both under data_dir.
+pub struct Server {
+    pub settings: ServerSettings,
+    pub store: Store,
+    pub blobs: Blobs,
+    //Held from the conflict check of a batch until it is applied, so two
+    //devices can't both pass the check for the same path
+    pub writer: tokio::sync::Mutex<()>
+}
+
+pub type Shared = Arc<Server>;
+
+//A failed request, answered with the status and the message as body
+#[derive(Debug)]
+pub struct ApiError(pub StatusCode, pub String);
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self(StatusCode::BAD_REQUEST, message.into())
+    }
+
+    pub fn not_found() -> Self {
+        Self(StatusCode::NOT_FOUND, "no such file".to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        tracing::error!("{}", e);
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<sqlite::Error> for ApiError {
+    fn from(e: sqlite::Error) -> Self {
+        tracing::error!("index: {}", e);
+        Self(StatusCode::INTERNAL_SERVER_ERROR, "index error".to_string())
+    }
+}
+
+impl Server {
+    pub fn open(settings: ServerSettings) -> Self {
+        let blobs = Blobs::open(&settings.data_dir);
+        let store = Store::open(&settings.data_dir.join("index.sqlite"));
+        Self { settings, store, blobs, writer: tokio::sync::Mutex::new(()) }
+    }
+
+    //Makes the staged body the path's new content. file describes it, its
+    //blob, size and version are filled in here. None when that would go over
+    //the quota. Callers hold writer, so the quota can't change in between.
+    //key is the idempotency key of the sync entry it comes from.
+    pub fn store(&self, file: StoredFile, staged: &Staged, key: Option<&str>) -> Result<Option<StoredFile>, ApiError> {
+        if let Some(quota) = self.settings.quota_bytes {
+            let replaced = self.store.current(&file.path)?.map_or(0, |f| f.size as u64);
+            if self.store.used_bytes()? - replaced + staged.size > quota {
+                return Ok(None);
+            }
+        }
+        self.blobs.commit(staged)?;
+        let mut file = StoredFile { blob: staged.hash.clone(), size: staged.size as i64, ..file };
+        file.version_id = self.store.transaction(|tx| tx.put(&file, key))?;
+        Ok(Some(file))
+    }
+}
+
+pub fn router(server: Shared) -> Router {
+    let limit = server.settings.max_body_bytes as usize;
+    Router::new()
+        .route("/handshake", post(handshake::handshake))
+        .route("/sync", post(sync::sync))
+        .route("/uploads", post(uploads::create))
+        .route("/uploads/{id}", get(uploads::offset).patch(uploads::append))
+        .route("/files", get(files::get).put(files::put).delete(files::delete))
+        .route("/list", get(files::list))
+        .route("/move", post(files::rename))
+        .route("/versions", get(files::versions))
+        .route("/changes", get(files::changes))
+        .route_layer(middleware::from_fn_with_state(server.clone(), authorize))
+        //Unauthenticated, clients probe it to see whether the server is up
+        .route("/", get(|| async { "pocket-drive-server" }))
+        .layer(DefaultBodyLimit::max(limit))
+        .with_state(server)
+}
+
+//Serves until the listener fails. Tests bind to port 0 and pass the listener
+//in, the binary binds settings.listen.
+pub async fn serve(settings: ServerSettings, listener: TcpListener) -> io::Result<()> {
+    let server = Arc::new(Server::open(settings));
+    tracing::info!("listening on {}, data in {}", listener.local_addr()?, server.settings.data_dir.display());
+    axum::serve(listener, router(server)).await
+}
+
+//The same credentials the client sends: a bearer token or basic auth
+async fn authorize(State(server): State<Shared>, request: Request, next: Next) -> Response {
+    let settings = &server.settings;
+    if settings.token.is_none() && settings.basic_auth.is_none() {
+        return next.run(request).await;
+    }
+    let given = request.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()).unwrap_or_default();
+
+    let bearer = settings.token.as_ref()
+        .is_some_and(|token| given.strip_prefix("Bearer ") == Some(token.as_str()));
+    let basic = settings.basic_auth.as_ref().is_some_and(|auth| {
+        let expected = format!("{}:{}", auth.username, auth.password.as_deref().unwrap_or_default());
+        given.strip_prefix("Basic ")
+            .and_then(|encoded| STANDARD.decode(encoded).ok())
+            .is_some_and(|decoded| decoded == expected.as_bytes())
+    });
+
+    if bearer || basic {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, "missing or wrong credentials").into_response()
+    }
+}
+
+//The X-Device-Id every client request carries, recorded with each change
+pub fn device_id(headers: &HeaderMap) -> Option<String> {
+    headers.get("X-Device-Id").and_then(|v| v.to_str().ok()).map(str::to_string)
+}
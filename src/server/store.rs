@@ -0,0 +1,538 @@
+use std::{path::Path, sync::{Mutex, MutexGuard}, time::{Duration, SystemTime}};
+
+use serde::Serialize;
+use sqlite::{Connection, State, Statement};
+
+use crate::backend::remote::{RemoteStat, is_internal};
+use crate::file_uploader::file_upload::{FileEntryDTO, Operations, RemoteChange};
+
+const FILE_COLUMNS: &str = "path, file_name, hash, size, modified_time, version_id, blob, encryption, device_id";
+
+//The server's index. files holds what every path looks like now, versions
+//every state a path went through (a NULL blob is a delete) and changes the
+//feed GET /changes reads, in the order things were applied. applied maps the
+//idempotency key of every recently applied sync entry to the version it
+//made, and batches the answers to recent sync requests by their
+//Idempotency-Key.
+pub struct Store {
+    conn: Mutex<Connection>
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub path: String,
+    pub file_name: String,
+    //file_hash of the entry that stored it, sealed when the client encrypts
+    pub hash: Option<String>,
+    //Bytes stored, after undoing content_encoding
+    pub size: i64,
+    pub modified_time: i64,
+    pub version_id: String,
+    //blake2s of the stored bytes, names the blob
+    pub blob: String,
+    pub encryption: Option<String>,
+    pub device_id: Option<String>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Version {
+    pub version_id: String,
+    pub file_path: String,
+    pub file_hash: Option<String>,
+    pub file_size: i64,
+    pub modified_time: i64,
+    pub device_id: Option<String>,
+    pub deleted: bool,
+    //Milliseconds since the epoch, when the server applied it
+    pub created: i64
+}
+
+//What an already applied sync entry did, a delete of a path that wasn't
+//there included
+#[derive(Debug, Clone)]
+pub enum Applied {
+    Put(StoredFile),
+    Delete
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadRow {
+    pub upload_id: String,
+    pub path: String,
+    pub hash: Option<String>,
+    pub size: u64,
+    pub offset: u64
+}
+
+impl StoredFile {
+    pub fn to_stat(&self) -> RemoteStat {
+        RemoteStat {
+            path: self.path.clone(),
+            hash: self.hash.clone(),
+            size: self.size,
+            modified_time: self.modified_time,
+            version_id: Some(self.version_id.clone()),
+            content_hash: Some(self.blob.clone())
+        }
+    }
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Self {
+        let conn = sqlite::open(path).unwrap_or_else(|e| panic!("server: can't open {}: {}", path.display(), e));
+        conn.execute("
+            CREATE TABLE IF NOT EXISTS files (path TEXT PRIMARY KEY, file_name TEXT, hash TEXT, size INTEGER, modified_time INTEGER, version_id TEXT, blob TEXT, encryption TEXT, device_id TEXT);
+            CREATE TABLE IF NOT EXISTS versions (id INTEGER PRIMARY KEY AUTOINCREMENT, path TEXT, file_name TEXT, hash TEXT, size INTEGER, modified_time INTEGER, blob TEXT, encryption TEXT, device_id TEXT, created INTEGER);
+            CREATE INDEX IF NOT EXISTS versions_path ON versions (path);
+            CREATE TABLE IF NOT EXISTS changes (seq INTEGER PRIMARY KEY AUTOINCREMENT, operation TEXT, path TEXT, file_name TEXT, hash TEXT, size INTEGER, modified_time INTEGER, encryption TEXT, device_id TEXT);
+            CREATE TABLE IF NOT EXISTS uploads (upload_id TEXT PRIMARY KEY, path TEXT, hash TEXT, size INTEGER, offset INTEGER);
+            CREATE TABLE IF NOT EXISTS applied (key TEXT PRIMARY KEY, version INTEGER, created INTEGER);
+            CREATE TABLE IF NOT EXISTS batches (key TEXT PRIMARY KEY, response TEXT, created INTEGER);
+        ").unwrap();
+        date_applied(&conn);
+        Self { conn: Mutex::new(conn) }
+    }
+
+    //Everything inside runs as one sqlite transaction, rolled back on error
+    pub fn transaction<T>(&self, f: impl FnOnce(&Tx) -> sqlite::Result<T>) -> sqlite::Result<T> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("BEGIN TRANSACTION")?;
+        let tx = Tx { conn };
+        match f(&tx) {
+            Ok(value) => {
+                tx.conn.execute("COMMIT")?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = tx.conn.execute("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    pub fn current(&self, path: &str) -> sqlite::Result<Option<StoredFile>> {
+        current(&self.conn.lock().unwrap(), path)
+    }
+
+    //Bookkeeping files only when the prefix names them, like the backends
+    pub fn list(&self, prefix: &str) -> sqlite::Result<Vec<StoredFile>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(format!("SELECT {} FROM files WHERE substr(path, 1, length(?)) = ? ORDER BY path", FILE_COLUMNS))?;
+        stmt.bind((1, prefix))?;
+        stmt.bind((2, prefix))?;
+        let internal = is_internal(prefix);
+        let mut files = Vec::new();
+        while let Ok(State::Row) = stmt.next() {
+            let file = read_file(&stmt)?;
+            if is_internal(&file.path) == internal {
+                files.push(file);
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn used_bytes(&self) -> sqlite::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT COALESCE(SUM(size), 0) FROM files")?;
+        stmt.next()?;
+        Ok(stmt.read::<i64, _>(0)? as u64)
+    }
+
+    //Newest first
+    pub fn versions(&self, path: &str) -> sqlite::Result<Vec<Version>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, path, hash, size, modified_time, device_id, blob IS NULL, created FROM versions WHERE path = ? ORDER BY id DESC"
+        )?;
+        stmt.bind((1, path))?;
+        let mut versions = Vec::new();
+        while let Ok(State::Row) = stmt.next() {
+            versions.push(Version {
+                version_id: stmt.read::<i64, _>(0)?.to_string(),
+                file_path: stmt.read(1)?,
+                file_hash: stmt.read(2)?,
+                file_size: stmt.read(3)?,
+                modified_time: stmt.read(4)?,
+                device_id: stmt.read(5)?,
+                deleted: stmt.read::<i64, _>(6)? != 0,
+                created: stmt.read(7)?
+            });
+        }
+        Ok(versions)
+    }
+
+    //A stored version of the path, None for an unknown id or a delete
+    pub fn version(&self, path: &str, version_id: &str) -> sqlite::Result<Option<StoredFile>> {
+        let Ok(id) = version_id.parse::<i64>() else {
+            return Ok(None);
+        };
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT path, file_name, hash, size, modified_time, id, blob, encryption, device_id FROM versions WHERE path = ? AND id = ? AND blob IS NOT NULL"
+        )?;
+        stmt.bind((1, path))?;
+        stmt.bind((2, id))?;
+        match stmt.next()? {
+            State::Row => Ok(Some(StoredFile {
+                path: stmt.read(0)?,
+                file_name: stmt.read(1)?,
+                hash: stmt.read(2)?,
+                size: stmt.read(3)?,
+                modified_time: stmt.read(4)?,
+                version_id: stmt.read::<i64, _>(5)?.to_string(),
+                blob: stmt.read(6)?,
+                encryption: stmt.read(7)?,
+                device_id: stmt.read(8)?
+            })),
+            State::Done => Ok(None)
+        }
+    }
+
+    //Changes after the cursor with their sequence numbers, oldest first
+    pub fn changes(&self, after: i64, limit: usize) -> sqlite::Result<Vec<(i64, RemoteChange)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT seq, operation, path, file_name, hash, size, modified_time, encryption, device_id FROM changes WHERE seq > ? ORDER BY seq LIMIT ?"
+        )?;
+        stmt.bind((1, after))?;
+        stmt.bind((2, limit as i64))?;
+        let mut changes = Vec::new();
+        while let Ok(State::Row) = stmt.next() {
+            let operation: String = stmt.read(1)?;
+            let Some(operation) = Operations::parse(&operation) else {
+                continue;
+            };
+            changes.push((stmt.read(0)?, RemoteChange {
+                operation,
+                entry: FileEntryDTO {
+                    file_name: stmt.read(3)?,
+                    file_path: stmt.read(2)?,
+                    file_hash: stmt.read(4)?,
+                    file_size: stmt.read(5)?,
+                    modified_time: stmt.read(6)?,
+                    upload_id: None,
+                    base_hash: None,
+                    content_encoding: None,
+                    compressed_size: None,
+                    encryption: stmt.read(7)?,
+                    part: None,
+                    idempotency_key: None
+                },
+                device_id: stmt.read(8)?
+            }));
+        }
+        Ok(changes)
+    }
+
+    pub fn applied(&self, key: &str) -> sqlite::Result<Option<Applied>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT v.path, v.file_name, v.hash, v.size, v.modified_time, v.id, v.blob, v.encryption, v.device_id
+             FROM applied a LEFT JOIN versions v ON v.id = a.version WHERE a.key = ?"
+        )?;
+        stmt.bind((1, key))?;
+        if stmt.next()? == State::Done {
+            return Ok(None);
+        }
+        let Some(blob) = stmt.read::<Option<String>, _>(6)? else {
+            return Ok(Some(Applied::Delete));
+        };
+        Ok(Some(Applied::Put(StoredFile {
+            path: stmt.read(0)?,
+            file_name: stmt.read(1)?,
+            hash: stmt.read(2)?,
+            size: stmt.read(3)?,
+            modified_time: stmt.read(4)?,
+            version_id: stmt.read::<i64, _>(5)?.to_string(),
+            blob,
+            encryption: stmt.read(7)?,
+            device_id: stmt.read(8)?
+        })))
+    }
+
+    //The answer given to the sync request with this key, if it is recent
+    pub fn batch_response(&self, key: &str) -> sqlite::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT response FROM batches WHERE key = ?")?;
+        stmt.bind((1, key))?;
+        match stmt.next()? {
+            State::Row => Ok(Some(stmt.read(0)?)),
+            State::Done => Ok(None)
+        }
+    }
+
+    //Keeps the answer for retries, answers older than keep are dropped.
+    //Replays after that still find every entry in applied.
+    pub fn remember_batch(&self, key: &str, response: &str, keep: Duration) -> sqlite::Result<()> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("DELETE FROM batches WHERE created < ?")?;
+        stmt.bind((1, now - keep.as_millis() as i64))?;
+        stmt.next()?;
+
+        let mut stmt = conn.prepare("INSERT OR REPLACE INTO batches (key, response, created) VALUES (?, ?, ?)")?;
+        stmt.bind((1, key))?;
+        stmt.bind((2, response))?;
+        stmt.bind((3, now))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    //Drops the keys applied longer ago than keep, a replay after that is
+    //applied like a new entry
+    pub fn forget_applied(&self, keep: Duration) -> sqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("DELETE FROM applied WHERE created < ?")?;
+        stmt.bind((1, now_millis() - keep.as_millis() as i64))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    pub fn create_upload(&self, upload: &UploadRow) -> sqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("INSERT INTO uploads (upload_id, path, hash, size, offset) VALUES (?, ?, ?, ?, ?)")?;
+        stmt.bind((1, upload.upload_id.as_str()))?;
+        stmt.bind((2, upload.path.as_str()))?;
+        stmt.bind((3, upload.hash.as_deref()))?;
+        stmt.bind((4, upload.size as i64))?;
+        stmt.bind((5, upload.offset as i64))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    pub fn upload(&self, upload_id: &str) -> sqlite::Result<Option<UploadRow>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT upload_id, path, hash, size, offset FROM uploads WHERE upload_id = ?")?;
+        stmt.bind((1, upload_id))?;
+        match stmt.next()? {
+            State::Row => Ok(Some(UploadRow {
+                upload_id: stmt.read(0)?,
+                path: stmt.read(1)?,
+                hash: stmt.read(2)?,
+                size: stmt.read::<i64, _>(3)? as u64,
+                offset: stmt.read::<i64, _>(4)? as u64
+            })),
+            State::Done => Ok(None)
+        }
+    }
+
+    pub fn set_upload_offset(&self, upload_id: &str, offset: u64) -> sqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("UPDATE uploads SET offset = ? WHERE upload_id = ?")?;
+        stmt.bind((1, offset as i64))?;
+        stmt.bind((2, upload_id))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    pub fn remove_upload(&self, upload_id: &str) -> sqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("DELETE FROM uploads WHERE upload_id = ?")?;
+        stmt.bind((1, upload_id))?;
+        stmt.next()?;
+        Ok(())
+    }
+}
+
+//Writes to the index, see Store::transaction. Each one adds a version and a
+//change, so the feed and the history always agree with files.
+pub struct Tx<'a> {
+    conn: MutexGuard<'a, Connection>
+}
+
+impl Tx<'_> {
+    pub fn current(&self, path: &str) -> sqlite::Result<Option<StoredFile>> {
+        current(&self.conn, path)
+    }
+
+    //Stores file as the path's new content and returns its version id, the
+    //one passed in is ignored. A key marks the sync entry applied.
+    pub fn put(&self, file: &StoredFile, key: Option<&str>) -> sqlite::Result<String> {
+        let operation = match self.current(&file.path)? {
+            Some(_) => Operations::Update,
+            None => Operations::Insert
+        };
+        let version_id = self.add_version(file, Some(&file.blob))?;
+
+        let mut stmt = self.conn.prepare(format!("INSERT OR REPLACE INTO files ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)", FILE_COLUMNS))?;
+        stmt.bind((1, file.path.as_str()))?;
+        stmt.bind((2, file.file_name.as_str()))?;
+        stmt.bind((3, file.hash.as_deref()))?;
+        stmt.bind((4, file.size))?;
+        stmt.bind((5, file.modified_time))?;
+        stmt.bind((6, version_id.as_str()))?;
+        stmt.bind((7, file.blob.as_str()))?;
+        stmt.bind((8, file.encryption.as_deref()))?;
+        stmt.bind((9, file.device_id.as_deref()))?;
+        stmt.next()?;
+
+        self.add_change(operation, file)?;
+        self.mark_applied(key, Some(&version_id))?;
+        Ok(version_id)
+    }
+
+    //Returns what was deleted, None when the path wasn't there. The change
+    //carries the deleting device and its modified_time
+    pub fn delete(&self, path: &str, modified_time: i64, device_id: Option<&str>, key: Option<&str>) -> sqlite::Result<Option<StoredFile>> {
+        let Some(existing) = self.current(path)? else {
+            self.mark_applied(key, None)?;
+            return Ok(None);
+        };
+        let gone = StoredFile {
+            modified_time,
+            device_id: device_id.map(str::to_string),
+            ..existing.clone()
+        };
+        let version_id = self.add_version(&gone, None)?;
+
+        let mut stmt = self.conn.prepare("DELETE FROM files WHERE path = ?")?;
+        stmt.bind((1, path))?;
+        stmt.next()?;
+
+        self.add_change(Operations::Delete, &gone)?;
+        self.mark_applied(key, Some(&version_id))?;
+        Ok(Some(existing))
+    }
+
+    fn add_version(&self, file: &StoredFile, blob: Option<&str>) -> sqlite::Result<String> {
+        let created = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO versions (path, file_name, hash, size, modified_time, blob, encryption, device_id, created) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )?;
+        stmt.bind((1, file.path.as_str()))?;
+        stmt.bind((2, file.file_name.as_str()))?;
+        stmt.bind((3, file.hash.as_deref()))?;
+        stmt.bind((4, file.size))?;
+        stmt.bind((5, file.modified_time))?;
+        stmt.bind((6, blob))?;
+        stmt.bind((7, file.encryption.as_deref()))?;
+        stmt.bind((8, file.device_id.as_deref()))?;
+        stmt.bind((9, created))?;
+        stmt.next()?;
+
+        let mut stmt = self.conn.prepare("SELECT last_insert_rowid()")?;
+        stmt.next()?;
+        Ok(stmt.read::<i64, _>(0)?.to_string())
+    }
+
+    fn mark_applied(&self, key: Option<&str>, version_id: Option<&str>) -> sqlite::Result<()> {
+        let Some(key) = key else {
+            return Ok(());
+        };
+        let mut stmt = self.conn.prepare("INSERT OR REPLACE INTO applied (key, version, created) VALUES (?, ?, ?)")?;
+        stmt.bind((1, key))?;
+        stmt.bind((2, version_id.map(|v| v.parse::<i64>().unwrap())))?;
+        stmt.bind((3, now_millis()))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    fn add_change(&self, operation: Operations, file: &StoredFile) -> sqlite::Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO changes (operation, path, file_name, hash, size, modified_time, encryption, device_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        )?;
+        stmt.bind((1, operation.as_str()))?;
+        stmt.bind((2, file.path.as_str()))?;
+        stmt.bind((3, file.file_name.as_str()))?;
+        stmt.bind((4, file.hash.as_deref()))?;
+        stmt.bind((5, file.size))?;
+        stmt.bind((6, file.modified_time))?;
+        stmt.bind((7, file.encryption.as_deref()))?;
+        stmt.bind((8, file.device_id.as_deref()))?;
+        stmt.next()?;
+        Ok(())
+    }
+}
+
+fn current(conn: &Connection, path: &str) -> sqlite::Result<Option<StoredFile>> {
+    let mut stmt = conn.prepare(format!("SELECT {} FROM files WHERE path = ?", FILE_COLUMNS))?;
+    stmt.bind((1, path))?;
+    match stmt.next()? {
+        State::Row => Ok(Some(read_file(&stmt)?)),
+        State::Done => Ok(None)
+    }
+}
+
+fn read_file(stmt: &Statement) -> sqlite::Result<StoredFile> {
+    Ok(StoredFile {
+        path: stmt.read(0)?,
+        file_name: stmt.read(1)?,
+        hash: stmt.read(2)?,
+        size: stmt.read(3)?,
+        modified_time: stmt.read(4)?,
+        version_id: stmt.read(5)?,
+        blob: stmt.read(6)?,
+        encryption: stmt.read(7)?,
+        device_id: stmt.read(8)?
+    })
+}
+
+//Indexes from before keys expired have no created column, their keys are
+//counted from the upgrade
+fn date_applied(conn: &Connection) {
+    let mut dated = false;
+    conn.iterate("PRAGMA table_info(applied)", |row| {
+        dated |= row.iter().any(|(name, value)| *name == "name" && *value == Some("created"));
+        true
+    }).unwrap();
+    if !dated {
+        conn.execute("ALTER TABLE applied ADD COLUMN created INTEGER").unwrap();
+        conn.execute(format!("UPDATE applied SET created = {}", now_millis())).unwrap();
+    }
+    conn.execute("CREATE INDEX IF NOT EXISTS applied_created ON applied (created)").unwrap();
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(path: &str) -> StoredFile {
+        StoredFile {
+            path: path.to_string(),
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            hash: Some("hash".to_string()),
+            size: 1,
+            modified_time: 1,
+            version_id: String::new(),
+            blob: "blob".to_string(),
+            encryption: None,
+            device_id: None
+        }
+    }
+
+    #[test]
+    fn applied_keys_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("index.sqlite"));
+        store.transaction(|tx| tx.put(&stored("/a"), Some("key"))).unwrap();
+
+        store.forget_applied(Duration::from_secs(3600)).unwrap();
+        assert!(matches!(store.applied("key").unwrap(), Some(Applied::Put(_))));
+
+        std::thread::sleep(Duration::from_millis(5));
+        store.forget_applied(Duration::ZERO).unwrap();
+        assert!(store.applied("key").unwrap().is_none());
+        assert!(store.current("/a").unwrap().is_some());
+    }
+
+    #[test]
+    fn keys_from_before_they_expired_are_dated_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.sqlite");
+        sqlite::open(&path).unwrap().execute("
+            CREATE TABLE applied (key TEXT PRIMARY KEY, version INTEGER);
+            INSERT INTO applied (key, version) VALUES ('old', NULL);
+        ").unwrap();
+
+        let store = Store::open(&path);
+        store.forget_applied(Duration::from_secs(3600)).unwrap();
+        assert!(matches!(store.applied("old").unwrap(), Some(Applied::Delete)));
+        std::thread::sleep(Duration::from_millis(5));
+        store.forget_applied(Duration::ZERO).unwrap();
+        assert!(store.applied("old").unwrap().is_none());
+    }
+}
@@ -0,0 +1,316 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf, time::Duration};
+
+use axum::{Json, extract::{Multipart, State}, http::{HeaderMap, StatusCode, header::CONTENT_TYPE}, response::{IntoResponse, Response}};
+use serde_json::json;
+
+use crate::backend::remote::relative_key;
+use crate::file_uploader::batch::SyncBatch;
+use crate::file_uploader::compression::ZSTD;
+use crate::file_uploader::file_upload::{FileEntryDTO, Operations, RemoteConflict};
+use crate::file_uploader::response::{ItemResult, ItemStatus, SYNC_RESPONSE_VERSION, SyncResponse};
+use crate::server::{ApiError, Server, Shared, device_id, store::{Applied, StoredFile}};
+
+//Parts of the request body waiting in temp files, keyed by part name. What
+//no entry took is removed with the request.
+#[derive(Default)]
+struct Received(HashMap<String, PathBuf>);
+
+impl Drop for Received {
+    fn drop(&mut self) {
+        for file in self.0.values() {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+//How long the answer to a sync request is kept for a retry with the same
+//Idempotency-Key
+const BATCH_REPLAY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+//How long the idempotency key of an applied entry is kept. A client resends
+//what it got no answer to as soon as the server is back, a key still
+//replayed after a month is applied again.
+const APPLIED_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+//POST /sync, the request is described in request.rs and the answer in
+//response.rs. A batch with a base_hash that no longer matches is refused
+//whole with 409 before anything is applied, otherwise every entry gets its
+//own result. Entries whose idempotency key was applied before aren't applied
+//again, they get the result of back then, unless they carry other content.
+pub async fn sync(State(server): State<Shared>, headers: HeaderMap, mut multipart: Multipart) -> Result<Response, ApiError> {
+    let device = device_id(&headers);
+    let batch_key = headers.get("Idempotency-Key").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let mut payload = None;
+    let mut parts = Received::default();
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| ApiError::bad_request(e.body_text()))? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "payload" {
+            payload = Some(field.text().await.map_err(|e| ApiError::bad_request(e.body_text()))?);
+            continue;
+        }
+        let file = server.blobs.receive(field).await
+            .map_err(|e| ApiError::bad_request(format!("part {} broke off: {}", name, e)))?;
+        if let Some(earlier) = parts.0.insert(name.clone(), file) {
+            let _ = std::fs::remove_file(earlier);
+            return Err(ApiError::bad_request(format!("part {} is sent twice", name)));
+        }
+    }
+
+    let payload = payload.ok_or_else(|| ApiError::bad_request("no payload part"))?;
+    let batch: SyncBatch = serde_json::from_str(&payload)
+        .map_err(|e| ApiError::bad_request(format!("invalid payload: {}", e)))?;
+    let entries = batch.values().map(Vec::len).sum::<usize>();
+    if entries > server.settings.max_batch_files {
+        return Err(ApiError(StatusCode::PAYLOAD_TOO_LARGE, format!("{} entries, at most {} per request", entries, server.settings.max_batch_files)));
+    }
+    check_parts(&batch, &parts)?;
+
+    let _writer = server.writer.lock().await;
+    if let Some(key) = &batch_key && let Some(response) = server.store.batch_response(key)? {
+        if answer_holds(&server, &batch, &parts, &response).await {
+            tracing::info!("sync request {} was answered before, answering the same", key);
+            return Ok(([(CONTENT_TYPE, "application/json")], response).into_response());
+        }
+        tracing::info!("sync request {} comes again with other content, applying it", key);
+    }
+
+    let mut applied = HashMap::new();
+    for key in batch.values().flatten().filter_map(|dto| dto.idempotency_key.as_deref()) {
+        if let Some(earlier) = server.store.applied(key)? {
+            applied.insert(key, earlier);
+        }
+    }
+    let conflicts = find_conflicts(&server, &batch, &applied)?;
+    if !conflicts.is_empty() {
+        return Ok((StatusCode::CONFLICT, Json(json!({ "conflicts": conflicts }))).into_response());
+    }
+
+    let mut results = Vec::new();
+    for op in [Operations::Insert, Operations::Update, Operations::Delete] {
+        for dto in batch.get(&op).into_iter().flatten() {
+            let earlier = dto.idempotency_key.as_deref().and_then(|key| applied.get(key));
+            let replayed = match earlier {
+                Some(Applied::Put(stored)) => same_content(&server, dto, &parts, stored.hash.as_deref(), &stored.blob).await,
+                Some(Applied::Delete) => true,
+                None => false
+            };
+            results.push(match earlier {
+                Some(earlier) if replayed => replay(&server, op, dto, earlier).await?,
+                _ => apply(&server, op, dto, &mut parts, device.as_deref()).await?
+            });
+        }
+    }
+    let response = SyncResponse { version: SYNC_RESPONSE_VERSION, results };
+
+    //Only a request that went through whole is answered from memory, one
+    //with entries left to retry has to be looked at again
+    if let Some(key) = &batch_key && response.results.iter().all(|r| r.status == ItemStatus::Ok) {
+        server.store.remember_batch(key, &serde_json::to_string(&response).unwrap(), BATCH_REPLAY_WINDOW)?;
+    }
+    server.store.forget_applied(APPLIED_RETENTION)?;
+    Ok(Json(response).into_response())
+}
+
+//Every insert and update has exactly one of part or upload_id, and every
+//part belongs to exactly one entry
+fn check_parts(batch: &SyncBatch, parts: &Received) -> Result<(), ApiError> {
+    let mut referenced = HashSet::new();
+    for (op, entries) in batch {
+        for dto in entries {
+            match (op, &dto.part, &dto.upload_id) {
+                (Operations::Delete, None, None) => {}
+                (Operations::Delete, _, _) => {
+                    return Err(ApiError::bad_request(format!("delete of {} carries content", dto.file_path)));
+                }
+                (_, Some(part), None) => {
+                    if !parts.0.contains_key(part) {
+                        return Err(ApiError::bad_request(format!("part {} of {} is missing", part, dto.file_path)));
+                    }
+                    if !referenced.insert(part.as_str()) {
+                        return Err(ApiError::bad_request(format!("part {} belongs to more than one entry", part)));
+                    }
+                }
+                (_, None, Some(_)) => {}
+                _ => return Err(ApiError::bad_request(format!("{} needs exactly one of part or upload_id", dto.file_path)))
+            }
+            if let Some(encoding) = dto.content_encoding.as_deref().filter(|e| *e != ZSTD) {
+                return Err(ApiError(StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("content_encoding {:?} isn't supported", encoding)));
+            }
+        }
+    }
+    match parts.0.keys().find(|name| !referenced.contains(name.as_str())) {
+        Some(name) => Err(ApiError::bad_request(format!("part {} belongs to no entry", name))),
+        None => Ok(())
+    }
+}
+
+//Entries applied before are left out, their base_hash was checked then
+fn find_conflicts(server: &Server, batch: &SyncBatch, applied: &HashMap<&str, Applied>) -> Result<Vec<RemoteConflict>, ApiError> {
+    let mut conflicts = Vec::new();
+    for dto in batch.values().flatten() {
+        let Some(base) = &dto.base_hash else {
+            continue;
+        };
+        if dto.idempotency_key.as_deref().is_some_and(|key| applied.contains_key(key)) {
+            continue;
+        }
+        let current = server.store.current(&dto.file_path)?;
+        if current.as_ref().and_then(|c| c.hash.as_ref()) != Some(base) {
+            conflicts.push(RemoteConflict {
+                file_path: dto.file_path.clone(),
+                remote_hash: current.as_ref().and_then(|c| c.hash.clone()),
+                remote_size: current.as_ref().map(|c| c.size),
+                remote_modified_time: current.as_ref().map(|c| c.modified_time),
+                device_id: current.and_then(|c| c.device_id)
+            });
+        }
+    }
+    Ok(conflicts)
+}
+
+async fn apply(server: &Server, op: Operations, dto: &FileEntryDTO, parts: &mut Received, device: Option<&str>) -> Result<ItemResult, ApiError> {
+    let result = |status, message: Option<String>| ItemResult {
+        file_path: dto.file_path.clone(),
+        operation: op,
+        status,
+        server_hash: None,
+        server_size: None,
+        version_id: None,
+        content_hash: None,
+        modified_time: Some(dto.modified_time),
+        device_id: device.map(str::to_string),
+        message
+    };
+    if relative_key(&dto.file_path).is_err() {
+        return Ok(result(ItemStatus::Rejected, Some(format!("path {:?} can't be stored", dto.file_path))));
+    }
+
+    if op == Operations::Delete {
+        server.store.transaction(|tx| tx.delete(&dto.file_path, dto.modified_time, device, dto.idempotency_key.as_deref()))?;
+        return Ok(result(ItemStatus::Ok, None));
+    }
+
+    //check_parts made sure one of them is there. Session content is never encoded
+    let (source, encoding) = match (&dto.part, &dto.upload_id) {
+        (Some(part), _) => (parts.0.remove(part).unwrap(), dto.content_encoding.clone()),
+        (None, Some(upload_id)) => match upload_source(server, upload_id, dto)? {
+            Ok(source) => (source, None),
+            Err(reason) => return Ok(result(ItemStatus::Rejected, Some(reason)))
+        },
+        (None, None) => unreachable!()
+    };
+    let encoded = encoding.is_some();
+    let staged = match server.blobs.stage(source, encoding).await {
+        Ok(staged) => staged,
+        Err(e) if encoded => return Ok(result(ItemStatus::Rejected, Some(format!("body can't be decoded: {}", e)))),
+        Err(e) => return Err(e.into())
+    };
+
+    let file = StoredFile {
+        path: dto.file_path.clone(),
+        file_name: dto.file_name.clone(),
+        hash: dto.file_hash.clone(),
+        size: 0,
+        modified_time: dto.modified_time,
+        version_id: String::new(),
+        blob: String::new(),
+        encryption: dto.encryption.clone(),
+        device_id: device.map(str::to_string)
+    };
+    let Some(stored) = server.store(file, &staged, dto.idempotency_key.as_deref())? else {
+        return Ok(result(ItemStatus::QuotaExceeded, Some("the server is out of space".to_string())));
+    };
+    if let Some(upload_id) = &dto.upload_id {
+        server.store.remove_upload(upload_id)?;
+    }
+
+    Ok(ItemResult {
+        server_hash: stored.hash,
+        server_size: Some(stored.size),
+        version_id: Some(stored.version_id),
+        content_hash: Some(stored.blob),
+        ..result(ItemStatus::Ok, None)
+    })
+}
+
+//Whether an entry sent again carries what was stored for it back then. A
+//client that found the stored content wrong sends it again under the same
+//key, that is applied afresh rather than answered with the bad result.
+async fn same_content(server: &Server, dto: &FileEntryDTO, parts: &Received, hash: Option<&str>, blob: &str) -> bool {
+    if dto.file_hash.as_deref() != hash {
+        return false;
+    }
+    let (source, encoding) = match (&dto.part, &dto.upload_id) {
+        (Some(part), _) => (parts.0[part].clone(), dto.content_encoding.clone()),
+        (None, Some(upload_id)) => (server.blobs.upload_path(upload_id), None),
+        (None, None) => return true
+    };
+    //A session used up by the first apply has nothing new to compare, a body
+    //that can't be decoded is reported by apply
+    match server.blobs.hash(source.clone(), encoding).await {
+        Ok(sent) => sent == blob,
+        Err(_) => !source.exists()
+    }
+}
+
+//A remembered answer stands only for the content it was given for
+async fn answer_holds(server: &Server, batch: &SyncBatch, parts: &Received, response: &str) -> bool {
+    let Ok(response) = serde_json::from_str::<SyncResponse>(response) else {
+        return false;
+    };
+    let stored = batch.iter()
+        .filter(|(op, _)| **op != Operations::Delete)
+        .flat_map(|(_, dtos)| dtos.iter())
+        .collect::<Vec<_>>();
+    for dto in stored {
+        let Some(item) = response.results.iter().find(|item| item.file_path == dto.file_path) else {
+            return false;
+        };
+        let Some(blob) = &item.content_hash else {
+            return false;
+        };
+        if !same_content(server, dto, parts, item.server_hash.as_deref(), blob).await {
+            return false;
+        }
+    }
+    true
+}
+
+//The result an entry got when it was applied. Its part goes with the
+//request, a session it names is done with.
+async fn replay(server: &Server, op: Operations, dto: &FileEntryDTO, earlier: &Applied) -> Result<ItemResult, ApiError> {
+    if let Some(upload_id) = &dto.upload_id && server.store.upload(upload_id)?.is_some() {
+        let _ = tokio::fs::remove_file(server.blobs.upload_path(upload_id)).await;
+        server.store.remove_upload(upload_id)?;
+    }
+    let stored = match earlier {
+        Applied::Put(stored) => Some(stored),
+        Applied::Delete => None
+    };
+    Ok(ItemResult {
+        file_path: dto.file_path.clone(),
+        operation: op,
+        status: ItemStatus::Ok,
+        server_hash: stored.and_then(|s| s.hash.clone()),
+        server_size: stored.map(|s| s.size),
+        version_id: stored.map(|s| s.version_id.clone()),
+        content_hash: stored.map(|s| s.blob.clone()),
+        modified_time: Some(stored.map_or(dto.modified_time, |s| s.modified_time)),
+        device_id: stored.and_then(|s| s.device_id.clone()),
+        message: None
+    })
+}
+
+//The bytes of a finished resumable upload for this entry, or why it can't be used
+fn upload_source(server: &Server, upload_id: &str, dto: &FileEntryDTO) -> Result<Result<PathBuf, String>, ApiError> {
+    let Some(upload) = server.store.upload(upload_id)? else {
+        return Ok(Err(format!("upload {} is unknown", upload_id)));
+    };
+    if upload.path != dto.file_path {
+        return Ok(Err(format!("upload {} is for {}", upload_id, upload.path)));
+    }
+    if upload.offset != upload.size {
+        return Ok(Err(format!("upload {} has {} of {} bytes", upload_id, upload.offset, upload.size)));
+    }
+    Ok(Ok(server.blobs.upload_path(upload_id)))
+}
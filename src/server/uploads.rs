@@ -0,0 +1,88 @@
+use axum::{Json, body::Body, extract::{Path, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::server::{ApiError, Shared, store::UploadRow};
+
+//Resumable uploads, see HttpBackend::upload_resumable for the protocol. The
+//bytes collect in uploads/ until a sync entry names the upload_id.
+#[derive(Deserialize)]
+pub struct CreateUpload {
+    file_path: String,
+    file_hash: Option<String>,
+    file_size: u64
+}
+
+#[derive(Serialize)]
+pub struct UploadOffset {
+    upload_id: String,
+    offset: u64
+}
+
+impl From<&UploadRow> for UploadOffset {
+    fn from(upload: &UploadRow) -> Self {
+        Self { upload_id: upload.upload_id.clone(), offset: upload.offset }
+    }
+}
+
+//POST /uploads
+pub async fn create(State(server): State<Shared>, Json(request): Json<CreateUpload>) -> Result<Json<UploadOffset>, ApiError> {
+    let upload = UploadRow {
+        upload_id: format!("{:016x}", fastrand::u64(..)),
+        path: request.file_path,
+        hash: request.file_hash,
+        size: request.file_size,
+        offset: 0
+    };
+    tokio::fs::File::create(server.blobs.upload_path(&upload.upload_id)).await?;
+    server.store.create_upload(&upload)?;
+    Ok(Json(UploadOffset::from(&upload)))
+}
+
+//GET /uploads/{id}
+pub async fn offset(State(server): State<Shared>, Path(upload_id): Path<String>) -> Result<Json<UploadOffset>, ApiError> {
+    let upload = server.store.upload(&upload_id)?.ok_or_else(ApiError::not_found)?;
+    Ok(Json(UploadOffset::from(&upload)))
+}
+
+//PATCH /uploads/{id}, Upload-Offset must be where the upload stands. What
+//arrives is kept even when the body breaks off, the client asks for the
+//offset and carries on from there.
+pub async fn append(State(server): State<Shared>, Path(upload_id): Path<String>, headers: HeaderMap, body: Body) -> Result<Response, ApiError> {
+    let upload = server.store.upload(&upload_id)?.ok_or_else(ApiError::not_found)?;
+    let offset = headers.get("Upload-Offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| ApiError::bad_request("Upload-Offset is missing"))?;
+    if offset != upload.offset {
+        return Ok((StatusCode::CONFLICT, Json(UploadOffset::from(&upload))).into_response());
+    }
+
+    let mut file = tokio::fs::OpenOptions::new().append(true).open(server.blobs.upload_path(&upload_id)).await?;
+    let mut written = upload.offset;
+    let mut stream = body.into_data_stream();
+    let mut failure = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                failure = Some(ApiError::bad_request(format!("body broke off: {}", e)));
+                break;
+            }
+        };
+        if written + chunk.len() as u64 > upload.size {
+            failure = Some(ApiError::bad_request(format!("upload {} is only {} bytes", upload_id, upload.size)));
+            break;
+        }
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    file.sync_all().await?;
+    server.store.set_upload_offset(&upload_id, written)?;
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(Json(UploadOffset { upload_id, offset: written }).into_response())
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, stream};
+
+use crate::{config::settings::BandwidthConfig, throttle::{bucket::TokenBucket, schedule::{BandwidthLimits, BandwidthSchedule}}};
+
+//Bodies are metered in pieces this size so a large chunk doesn't go out as one burst
+const SLICE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download
+}
+
+//Network limits shared by every transfer of the uploader. Each transfer takes
+//from its direction's bucket and from the total bucket. The limits follow the
+//schedule, which a reloaded config replaces at runtime.
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    upload: Arc<TokenBucket>,
+    download: Arc<TokenBucket>,
+    total: Arc<TokenBucket>,
+    schedule: Arc<Mutex<BandwidthSchedule>>
+}
+
+impl Bandwidth {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        let schedule = BandwidthSchedule::new(config);
+        let limits = schedule.current();
+        Self {
+            upload: Arc::new(TokenBucket::new(limits.upload)),
+            download: Arc::new(TokenBucket::new(limits.download)),
+            total: Arc::new(TokenBucket::new(limits.total)),
+            schedule: Arc::new(Mutex::new(schedule))
+        }
+    }
+
+    pub fn limits(&self) -> BandwidthLimits {
+        BandwidthLimits {
+            upload: self.upload.rate(),
+            download: self.download.rate(),
+            total: self.total.rate()
+        }
+    }
+
+    //Replaces the schedule, e.g. after the config file was edited
+    pub fn set_schedule(&self, config: &BandwidthConfig) {
+        *self.schedule.lock().unwrap() = BandwidthSchedule::new(config);
+        self.refresh();
+    }
+
+    //Called periodically so schedule windows take effect as the clock moves
+    pub fn refresh(&self) {
+        let limits = self.schedule.lock().unwrap().current();
+        if limits != self.limits() {
+            tracing::debug!("upload {}, download {}, total {}",
+                describe(limits.upload), describe(limits.download), describe(limits.total));
+        }
+        self.upload.set_rate(limits.upload);
+        self.download.set_rate(limits.download);
+        self.total.set_rate(limits.total);
+    }
+
+    pub async fn acquire(&self, direction: Direction, bytes: u64) {
+        let bucket = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download
+        };
+        bucket.acquire(bytes).await;
+        self.total.acquire(bytes).await;
+    }
+
+    //Meters a body stream as it is read by the client
+    pub fn throttle<S, E>(&self, direction: Direction, body: S) -> impl Stream<Item = Result<Bytes, E>> + use<S, E>
+    where
+        S: Stream<Item = Result<Bytes, E>>
+    {
+        let bandwidth = self.clone();
+        body
+            .flat_map(|chunk| stream::iter(split(chunk)))
+            .then(move |chunk| {
+                let bandwidth = bandwidth.clone();
+                async move {
+                    if let Ok(bytes) = &chunk {
+                        bandwidth.acquire(direction, bytes.len() as u64).await;
+                    }
+                    chunk
+                }
+            })
+    }
+
+    pub fn throttle_bytes(&self, direction: Direction, bytes: Bytes) -> impl Stream<Item = Result<Bytes, std::io::Error>> + use<> {
+        self.throttle(direction, stream::once(async move { Ok(bytes) }))
+    }
+}
+
+fn split<E>(chunk: Result<Bytes, E>) -> Vec<Result<Bytes, E>> {
+    match chunk {
+        Ok(bytes) if bytes.len() > SLICE => (0..bytes.len())
+            .step_by(SLICE)
+            .map(|start| Ok(bytes.slice(start..(start + SLICE).min(bytes.len()))))
+            .collect(),
+        other => vec![other]
+    }
+}
+
+fn describe(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{} KiB/s", rate / 1024),
+        None => "unlimited".to_string()
+    }
+}
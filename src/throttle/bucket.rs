@@ -0,0 +1,136 @@
+use std::{sync::Mutex, thread, time::{Duration, Instant}};
+
+//Token bucket measured in bytes per second. A rate of None means unlimited.
+//Callers take what they need up front and the bucket goes into debt, the
+//wait returned is how long it takes to pay that debt back. The bucket holds
+//at most one second worth of tokens so an idle period doesn't allow a burst.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last: Instant::now()
+            })
+        }
+    }
+
+    pub fn from_mbps(mbps: Option<u64>) -> Self {
+        Self::new(mbps.map(|m| m * 1024 * 1024))
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate != rate {
+            state.rate = rate;
+            state.tokens = state.tokens.min(rate.unwrap_or(0) as f64);
+            state.last = Instant::now();
+        }
+    }
+
+    fn reserve(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let rate = match state.rate {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return Duration::ZERO
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.last = now;
+        state.tokens = (state.tokens + elapsed * rate).min(rate);
+        state.tokens -= bytes as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+
+    pub fn acquire_blocking(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    //Same as acquire_blocking for tasks on the runtime
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Moves the bucket's clock back instead of sleeping
+    fn idle(bucket: &TokenBucket, seconds: f64) {
+        bucket.state.lock().unwrap().last -= Duration::from_secs_f64(seconds);
+    }
+
+    fn close_to(wait: Duration, seconds: f64) -> bool {
+        (wait.as_secs_f64() - seconds).abs() < 0.05
+    }
+
+    #[test]
+    fn starts_with_one_second_of_tokens() {
+        let bucket = TokenBucket::new(Some(1000));
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert!(close_to(bucket.reserve(500), 0.5));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let bucket = TokenBucket::new(Some(1000));
+        bucket.reserve(1500);
+        idle(&bucket, 1.0);
+        assert_eq!(bucket.reserve(500), Duration::ZERO);
+        assert!(close_to(bucket.reserve(250), 0.25));
+    }
+
+    #[test]
+    fn an_idle_bucket_holds_no_more_than_one_second() {
+        let bucket = TokenBucket::new(Some(1000));
+        idle(&bucket, 60.0);
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert!(close_to(bucket.reserve(1000), 1.0));
+    }
+
+    #[test]
+    fn a_lower_rate_drops_the_tokens_above_it() {
+        let bucket = TokenBucket::new(Some(1000));
+        bucket.set_rate(Some(100));
+        assert_eq!(bucket.reserve(100), Duration::ZERO);
+        assert!(close_to(bucket.reserve(100), 1.0));
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let bucket = TokenBucket::new(None);
+        assert_eq!(bucket.reserve(u64::MAX / 2), Duration::ZERO);
+        bucket.set_rate(Some(10));
+        bucket.set_rate(None);
+        assert_eq!(bucket.reserve(1 << 30), Duration::ZERO);
+    }
+}
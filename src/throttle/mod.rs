@@ -0,0 +1,3 @@
+pub mod bucket;
+pub mod schedule;
+pub mod bandwidth;
@@ -0,0 +1,146 @@
+use chrono::{Local, NaiveTime};
+
+use crate::config::settings::{BandwidthConfig, BandwidthWindow};
+
+//Rates in bytes per second, None is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub total: Option<u64>
+}
+
+impl BandwidthLimits {
+    fn from_kbps(upload: Option<u64>, download: Option<u64>, total: Option<u64>) -> Self {
+        let bytes = |kbps: Option<u64>| kbps.map(|k| k * 1024);
+        Self {
+            upload: bytes(upload),
+            download: bytes(download),
+            total: bytes(total)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    limits: BandwidthLimits
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthSchedule {
+    default: BandwidthLimits,
+    windows: Vec<Window>
+}
+
+impl BandwidthSchedule {
+    //Windows with a time that doesn't parse are reported and left out
+    pub fn new(config: &BandwidthConfig) -> Self {
+        let windows = config.schedule
+            .iter()
+            .filter_map(|w| match parse_window(w) {
+                Some(window) => Some(window),
+                None => {
+                    tracing::warn!("ignoring window {}-{}, times must be HH:MM", w.start, w.end);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            default: BandwidthLimits::from_kbps(config.upload_kbps, config.download_kbps, config.total_kbps),
+            windows
+        }
+    }
+
+    pub fn limits_at(&self, time: NaiveTime) -> BandwidthLimits {
+        self.windows
+            .iter()
+            .find(|w| w.contains(time))
+            .map(|w| w.limits)
+            .unwrap_or(self.default)
+    }
+
+    pub fn current(&self) -> BandwidthLimits {
+        self.limits_at(Local::now().time())
+    }
+}
+
+fn parse_window(window: &BandwidthWindow) -> Option<Window> {
+    Some(Window {
+        start: NaiveTime::parse_from_str(&window.start, "%H:%M").ok()?,
+        end: NaiveTime::parse_from_str(&window.end, "%H:%M").ok()?,
+        limits: BandwidthLimits::from_kbps(window.upload_kbps, window.download_kbps, window.total_kbps)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: &str, end: &str, upload_kbps: u64) -> BandwidthWindow {
+        BandwidthWindow {
+            start: start.to_string(),
+            end: end.to_string(),
+            upload_kbps: Some(upload_kbps),
+            download_kbps: None,
+            total_kbps: None
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn upload_at(schedule: &BandwidthSchedule, hour: u32, minute: u32) -> Option<u64> {
+        schedule.limits_at(at(hour, minute)).upload
+    }
+
+    #[test]
+    fn a_window_past_midnight_covers_both_days() {
+        let schedule = BandwidthSchedule::new(&BandwidthConfig {
+            upload_kbps: Some(100),
+            schedule: vec![window("22:00", "06:00", 1000)],
+            ..BandwidthConfig::default()
+        });
+        assert_eq!(upload_at(&schedule, 23, 0), Some(1000 * 1024));
+        assert_eq!(upload_at(&schedule, 5, 59), Some(1000 * 1024));
+        assert_eq!(upload_at(&schedule, 7, 0), Some(100 * 1024));
+        //Starts inclusive, ends exclusive
+        assert_eq!(upload_at(&schedule, 22, 0), Some(1000 * 1024));
+        assert_eq!(upload_at(&schedule, 6, 0), Some(100 * 1024));
+    }
+
+    #[test]
+    fn the_first_matching_window_wins() {
+        let schedule = BandwidthSchedule::new(&BandwidthConfig {
+            schedule: vec![window("09:00", "17:00", 1000), window("12:00", "13:00", 10)],
+            ..BandwidthConfig::default()
+        });
+        assert_eq!(upload_at(&schedule, 12, 30), Some(1000 * 1024));
+        assert_eq!(upload_at(&schedule, 8, 0), None);
+        assert_eq!(schedule.limits_at(at(10, 0)).download, None);
+    }
+
+    #[test]
+    fn a_window_that_doesnt_parse_is_left_out() {
+        let schedule = BandwidthSchedule::new(&BandwidthConfig {
+            schedule: vec![window("9am", "17:00", 1000), window("18:00", "20:00", 10)],
+            ..BandwidthConfig::default()
+        });
+        assert_eq!(schedule.windows.len(), 1);
+        assert_eq!(upload_at(&schedule, 10, 0), None);
+        assert_eq!(upload_at(&schedule, 19, 0), Some(10 * 1024));
+    }
+}
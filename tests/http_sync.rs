@@ -0,0 +1,344 @@
+//The uploader against a real server on a local port, the way two devices
+//sharing one server see each other
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, mpsc};
+use std::time::{Duration, SystemTime};
+
+use pocket_drive::backend::http::{HttpBackend, client};
+use pocket_drive::config::server::ServerSettings;
+use pocket_drive::config::settings::Config;
+use pocket_drive::db_listener::db::FileEntry;
+use pocket_drive::event_listener::self_writes::SelfWrites;
+use pocket_drive::file_hasher::hasher::hash_file;
+use pocket_drive::file_uploader::batch::SyncBatch;
+use pocket_drive::file_uploader::file_upload::{FileEntryDTO, FileUploader, FileUploaderCmd, Operations, PullResult, SyncOutcome, UploaderStatus};
+use pocket_drive::file_uploader::response::{ItemResult, ItemStatus};
+use pocket_drive::progress::events::Progress;
+use pocket_drive::server;
+use pocket_drive::throttle::bandwidth::Bandwidth;
+use pocket_drive::throttle::bucket::TokenBucket;
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+//Upload sessions are kept in the index in the working directory, the tests
+//keep theirs out of the checkout
+fn workdir() {
+    static DIR: OnceLock<tempfile::TempDir> = OnceLock::new();
+    std::env::set_current_dir(DIR.get_or_init(|| tempfile::tempdir().unwrap()).path()).unwrap();
+}
+
+async fn start_server(data_dir: PathBuf, configure: impl FnOnce(&mut ServerSettings)) -> String {
+    let mut settings = ServerSettings { data_dir, ..ServerSettings::default() };
+    configure(&mut settings);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(server::serve(settings, listener));
+    url
+}
+
+struct Device {
+    root: PathBuf,
+    tx: tokio::sync::mpsc::Sender<FileUploaderCmd>,
+    status: watch::Receiver<UploaderStatus>,
+    //Hash of every path as last pulled, what the index keeps
+    synced: Mutex<HashMap<String, String>>
+}
+
+impl Device {
+    fn start(url: &str, root: PathBuf, device_id: &str, configure: impl FnOnce(&mut Config)) -> Self {
+        workdir();
+        std::fs::create_dir_all(&root).unwrap();
+        let mut config = Config::default();
+        config.server.base_url = url.to_string();
+        config.server.device_id = device_id.to_string();
+        config.proxy.from_env = false;
+        config.retry.max_attempts = 2;
+        config.retry.base_delay_ms = 10;
+        configure(&mut config);
+
+        let progress = Progress::new();
+        let bandwidth = Bandwidth::new(&config.bandwidth);
+        let backend = HttpBackend::new(&config, progress.clone(), bandwidth.clone());
+        let uploader = FileUploader::new(backend, progress, &config, &root, SelfWrites::new(), bandwidth).unwrap();
+        let (tx, status) = (uploader.get_sender(), uploader.status());
+        tokio::spawn(uploader.run());
+        Self { root, tx, status, synced: Mutex::default() }
+    }
+
+    //Writes the file and describes it the way the index would
+    fn write(&self, name: &str, content: &[u8]) -> FileEntryDTO {
+        let path = self.root.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        FileEntryDTO::from(&FileEntry {
+            filename: name.rsplit('/').next().unwrap().to_string(),
+            hash: Some(hash_file(&path, &TokenBucket::new(None)).unwrap()),
+            path,
+            size: metadata.len(),
+            modified: metadata.modified().unwrap(),
+            fingerprint: None
+        })
+    }
+
+    async fn sync(&self, batch: SyncBatch) -> Vec<SyncOutcome> {
+        let (tx, rx) = mpsc::channel();
+        self.tx.send(FileUploaderCmd::Sync(batch, tx)).await.unwrap();
+        tokio::task::spawn_blocking(move || rx.iter().collect()).await.unwrap()
+    }
+
+    //The results of a batch that has to go through as a whole
+    async fn sync_ok(&self, batch: SyncBatch) -> Vec<ItemResult> {
+        let mut outcomes = self.sync(batch).await;
+        assert_eq!(outcomes.len(), 1);
+        match outcomes.remove(0) {
+            SyncOutcome::Done(_, response, unsent) => {
+                assert!(unsent.values().all(Vec::is_empty));
+                assert!(response.results.iter().all(|r| r.status == ItemStatus::Ok), "{:?}", response.results);
+                response.results
+            }
+            other => panic!("expected the batch to be applied, got {:?}", other)
+        }
+    }
+
+    async fn pull(&self, cursor: Option<String>) -> PullResult {
+        self.try_pull(cursor).await.unwrap()
+    }
+
+    async fn try_pull(&self, cursor: Option<String>) -> Result<PullResult, String> {
+        let (tx, rx) = mpsc::channel();
+        let synced = self.synced.lock().unwrap().clone();
+        self.tx.send(FileUploaderCmd::Get(cursor, synced, tx)).await.unwrap();
+        let pulled = tokio::task::spawn_blocking(move || rx.recv_timeout(Duration::from_secs(30))).await.unwrap().unwrap()?;
+        let mut synced = self.synced.lock().unwrap();
+        for (_, entry) in &pulled.applied {
+            let path = entry.path.to_string_lossy().to_string();
+            match &entry.hash {
+                Some(hash) if entry.path.exists() => synced.insert(path, hash.clone()),
+                _ => synced.remove(&path)
+            };
+        }
+        drop(synced);
+        Ok(pulled)
+    }
+
+    //Where a file another device synced lands here
+    fn landed(&self, other: &FileEntryDTO) -> PathBuf {
+        self.root.join(other.file_path.trim_start_matches('/'))
+    }
+}
+
+fn batch(op: Operations, entries: Vec<FileEntryDTO>) -> SyncBatch {
+    HashMap::from([(op, entries)])
+}
+
+fn rebased(dto: FileEntryDTO, base: &FileEntryDTO) -> FileEntryDTO {
+    FileEntryDTO { base_hash: base.file_hash.clone(), ..dto }
+}
+
+//Straight to the server, with the client the uploader uses
+async fn get(url: &str, endpoint: &str, query: &[(&str, &str)]) -> reqwest::Response {
+    let mut config = Config::default();
+    config.proxy.from_env = false;
+    let (client, _) = client(&config, url);
+    let url = reqwest::Url::parse_with_params(&format!("{}/{}", url, endpoint), query).unwrap();
+    client.get(url).send().await.unwrap()
+}
+
+async fn fetch(url: &str, path: &str, version: Option<&str>) -> reqwest::Response {
+    let mut query = vec![("path", path)];
+    query.extend(version.map(|v| ("version", v)));
+    get(url, "files", &query).await
+}
+
+async fn versions(url: &str, path: &str) -> Vec<Value> {
+    get(url, "versions", &[("path", path)]).await.json().await.unwrap()
+}
+
+fn setup() -> tempfile::TempDir {
+    tempfile::tempdir().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn changes_from_one_device_reach_the_other() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |_| {}).await;
+    let laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |_| {});
+    let phone = Device::start(&url, dir.path().join("phone"), "phone", |_| {});
+
+    let first = laptop.write("docs/a.txt", b"first");
+    laptop.sync_ok(batch(Operations::Insert, vec![first.clone()])).await;
+    let pulled = phone.pull(None).await;
+    assert_eq!(pulled.applied.len(), 1);
+    assert_eq!(std::fs::read(phone.landed(&first)).unwrap(), b"first");
+
+    let second = rebased(laptop.write("docs/a.txt", b"second"), &first);
+    laptop.sync_ok(batch(Operations::Update, vec![second.clone()])).await;
+    let pulled = phone.pull(pulled.cursor).await;
+    assert_eq!(pulled.applied.len(), 1);
+    assert_eq!(std::fs::read(phone.landed(&first)).unwrap(), b"second");
+
+    std::fs::remove_file(laptop.root.join("docs/a.txt")).unwrap();
+    laptop.sync_ok(batch(Operations::Delete, vec![rebased(second.clone(), &second)])).await;
+    let pulled = phone.pull(pulled.cursor).await;
+    assert_eq!(pulled.applied[0].0, Operations::Delete);
+    assert!(!phone.landed(&first).exists());
+
+    //Its own changes don't come back to the device that made them
+    assert!(laptop.pull(None).await.applied.is_empty());
+    assert!(phone.pull(pulled.cursor).await.applied.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_stale_base_is_refused_as_a_conflict() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |_| {}).await;
+    let laptop = Device::start(&url, dir.path().join("home"), "laptop", |_| {});
+    //Laid out like the laptop, so both name the file the same
+    let phone = Device::start(&url, dir.path().join("home"), "phone", |_| {});
+
+    let original = laptop.write("a.txt", b"original");
+    laptop.sync_ok(batch(Operations::Insert, vec![original.clone()])).await;
+    let edited = rebased(laptop.write("a.txt", b"laptop edit"), &original);
+    laptop.sync_ok(batch(Operations::Update, vec![edited.clone()])).await;
+
+    //The phone still thinks the original is current
+    let stale = rebased(phone.write("a.txt", b"phone edit"), &original);
+    let outcomes = phone.sync(batch(Operations::Update, vec![stale])).await;
+    match &outcomes[..] {
+        [SyncOutcome::Conflict(_, conflicts)] => {
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].remote_hash, edited.file_hash);
+            assert_eq!(conflicts[0].device_id.as_deref(), Some("laptop"));
+        }
+        other => panic!("expected a conflict, got {:?}", other)
+    }
+    let stored = fetch(&url, &original.file_path, None).await.bytes().await.unwrap();
+    assert_eq!(&stored[..], b"laptop edit");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_files_go_up_in_chunks() {
+    let dir = setup();
+    //A multipart request with the whole file would be refused
+    let url = start_server(dir.path().join("server"), |settings| settings.max_body_bytes = 128 * 1024).await;
+    let laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |config| {
+        config.upload.resumable_threshold = 64 * 1024;
+        config.upload.chunk_size = 16 * 1024;
+    });
+
+    let content: Vec<u8> = (0..300 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+    let large = laptop.write("large.bin", &content);
+    let results = laptop.sync_ok(batch(Operations::Insert, vec![large.clone()])).await;
+    assert_eq!(results[0].server_size, Some(content.len() as i64));
+
+    let stored = fetch(&url, &large.file_path, None).await.bytes().await.unwrap();
+    assert!(stored[..] == content[..]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn earlier_versions_stay_readable() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |_| {}).await;
+    let laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |_| {});
+
+    let first = laptop.write("notes.md", b"v1");
+    let written = laptop.sync_ok(batch(Operations::Insert, vec![first.clone()])).await;
+    let second = rebased(laptop.write("notes.md", b"v2"), &first);
+    laptop.sync_ok(batch(Operations::Update, vec![second.clone()])).await;
+
+    let history = versions(&url, &first.file_path).await;
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0]["file_hash"].as_str(), second.file_hash.as_deref());
+    assert_eq!(history[1]["version_id"].as_str(), written[0].version_id.as_deref());
+    assert_eq!(history[1]["device_id"].as_str(), Some("laptop"));
+
+    let old = fetch(&url, &first.file_path, written[0].version_id.as_deref()).await;
+    assert_eq!(old.headers()["X-File-Hash"].to_str().unwrap(), first.file_hash.as_deref().unwrap());
+    assert_eq!(&old.bytes().await.unwrap()[..], b"v1");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_batch_sent_twice_is_applied_once() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |_| {}).await;
+    let laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |_| {});
+
+    let entry = FileEntryDTO { idempotency_key: Some("insert-a-1".to_string()), ..laptop.write("a.txt", b"once") };
+    let first = laptop.sync_ok(batch(Operations::Insert, vec![entry.clone()])).await;
+    //The answer was lost, the same operations are sent again
+    let again = laptop.sync_ok(batch(Operations::Insert, vec![entry.clone()])).await;
+
+    assert_eq!(again[0].version_id, first[0].version_id);
+    assert_eq!(again[0].content_hash, first[0].content_hash);
+    assert_eq!(versions(&url, &entry.file_path).await.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paths_from_the_server_stay_under_the_root() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |_| {}).await;
+    let laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |_| {});
+    let phone = Device::start(&url, dir.path().join("phone"), "phone", |_| {});
+
+    let entry = laptop.write("deep/er/file.txt", b"x");
+    laptop.sync_ok(batch(Operations::Insert, vec![entry.clone()])).await;
+    let pulled = phone.pull(None).await;
+    assert!(pulled.applied.iter().all(|(_, e)| e.path.starts_with(&phone.root)));
+    assert!(phone.landed(&entry).exists());
+    assert!(!Path::new(&entry.file_path).starts_with(&phone.root));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_accepted_credentials_make_the_uploader_ready() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |settings| settings.token = Some("right".to_string())).await;
+    let mut laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |config| {
+        config.server.token = Some("wrong".to_string());
+    });
+
+    let outcomes = laptop.sync(batch(Operations::Insert, vec![laptop.write("a.txt", b"a")])).await;
+    assert!(matches!(outcomes[..], [SyncOutcome::Failed(..)]));
+    assert_eq!(*laptop.status.borrow_and_update(), UploaderStatus::Unauthenticated);
+
+    //Still refused, the uploader never looks ready in between
+    laptop.tx.send(FileUploaderCmd::Authenticate(Some("still wrong".to_string()), None)).await.unwrap();
+    assert!(laptop.try_pull(None).await.is_err());
+    assert!(!laptop.status.has_changed().unwrap());
+
+    laptop.tx.send(FileUploaderCmd::Authenticate(Some("right".to_string()), None)).await.unwrap();
+    laptop.status.changed().await.unwrap();
+    assert_eq!(*laptop.status.borrow(), UploaderStatus::Ready);
+    laptop.sync_ok(batch(Operations::Insert, vec![laptop.write("a.txt", b"a")])).await;
+}
+
+//Rewrites a file without changing what the index saw of it
+fn overwrite(path: &Path, content: &[u8], modified: SystemTime) {
+    std::fs::write(path, content).unwrap();
+    std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_bad_copy_is_replaced_when_sent_again_under_the_same_key() {
+    let dir = setup();
+    let url = start_server(dir.path().join("server"), |_| {}).await;
+    let laptop = Device::start(&url, dir.path().join("laptop"), "laptop", |_| {});
+
+    let entry = FileEntryDTO { idempotency_key: Some("insert-a-1".to_string()), ..laptop.write("a.txt", b"good") };
+    let path = laptop.root.join("a.txt");
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+    //The server gets other bytes than were hashed, as if they broke on the way
+    overwrite(&path, b"bad!", modified);
+    match &laptop.sync(batch(Operations::Insert, vec![entry.clone()])).await[..] {
+        [SyncOutcome::Done(_, response, _)] => assert_eq!(response.results[0].status, ItemStatus::Mismatch),
+        other => panic!("expected the copy to be found wrong, got {:?}", other)
+    }
+
+    overwrite(&path, b"good", modified);
+    let results = laptop.sync_ok(batch(Operations::Insert, vec![entry.clone()])).await;
+    assert_eq!(results[0].content_hash, entry.file_hash);
+    assert_eq!(&fetch(&url, &entry.file_path, None).await.bytes().await.unwrap()[..], b"good");
+    assert_eq!(versions(&url, &entry.file_path).await.len(), 2);
+}